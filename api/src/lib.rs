@@ -78,3 +78,49 @@ pub unsafe extern "C" fn get_request_method(req: *mut Request) -> *const c_char
     let req: &mut Request = unsafe { get_object_from_pointer(req) };
     CString::new(req.method.as_str()).unwrap().into_raw()
 }
+
+fn option_to_c_string(value: Option<String>) -> *const c_char {
+    CString::new(value.unwrap_or_default()).unwrap().into_raw()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn get_remote_addr(req: *mut Request) -> *const c_char {
+    let req: &mut Request = unsafe { get_object_from_pointer(req) };
+    option_to_c_string(req.conn_info.remote_addr.map(|addr| addr.to_string()))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn get_local_addr(req: *mut Request) -> *const c_char {
+    let req: &mut Request = unsafe { get_object_from_pointer(req) };
+    option_to_c_string(req.conn_info.local_addr.map(|addr| addr.to_string()))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn get_scheme(req: *mut Request) -> *const c_char {
+    let req: &mut Request = unsafe { get_object_from_pointer(req) };
+    CString::new(req.conn_info.scheme()).unwrap().into_raw()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn get_tls_version(req: *mut Request) -> *const c_char {
+    let req: &mut Request = unsafe { get_object_from_pointer(req) };
+    option_to_c_string(req.conn_info.tls_version.clone())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn get_tls_cipher(req: *mut Request) -> *const c_char {
+    let req: &mut Request = unsafe { get_object_from_pointer(req) };
+    option_to_c_string(req.conn_info.tls_cipher.clone())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn get_sni(req: *mut Request) -> *const c_char {
+    let req: &mut Request = unsafe { get_object_from_pointer(req) };
+    option_to_c_string(req.conn_info.sni.clone())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn get_alpn(req: *mut Request) -> *const c_char {
+    let req: &mut Request = unsafe { get_object_from_pointer(req) };
+    option_to_c_string(req.conn_info.alpn.clone())
+}
@@ -0,0 +1,46 @@
+use jequi_module_api::{declare_module, serde_yaml, Flow, Module, ModuleOption, Request, Response};
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct Hello {
+    hello_message: Option<String>,
+    hello_status: u16,
+}
+
+impl Default for Hello {
+    fn default() -> Self {
+        Hello {
+            hello_message: None,
+            hello_status: 200,
+        }
+    }
+}
+
+impl Module for Hello {
+    const NAME: &'static str = "hello_module";
+    const OPTIONS: &'static [ModuleOption] = &[
+        ModuleOption::new("hello_message", "Body of every response."),
+        ModuleOption::new("hello_status", "Status code of every response."),
+    ];
+
+    fn load(config: &serde_yaml::Value) -> Result<Option<Self>, String> {
+        let hello: Hello = serde_yaml::from_value(config.clone()).map_err(|err| err.to_string())?;
+        if hello.hello_message.is_none() {
+            return Ok(None);
+        }
+        match hello.hello_status {
+            100..=599 => Ok(Some(hello)),
+            _ => Err("invalid hello_status".to_string()),
+        }
+    }
+
+    fn handle_request(&self, request: &Request, response: &mut Response) -> Flow {
+        response.set_status(self.hello_status);
+        response.set_header("x-hello-uri", request.uri());
+        response.write_body(self.hello_message.as_ref().unwrap().as_bytes());
+        Flow::Exit
+    }
+}
+
+declare_module!(Hello);
@@ -1,4 +1,3 @@
-use core::panic;
 use std::{
     any::Any,
     collections::HashMap,
@@ -6,10 +5,30 @@ use std::{
     sync::Arc,
 };
 
-use serde::Deserialize;
-use serde_yaml::from_reader;
+use indexmap::IndexMap;
+use plugins::get_plugin;
+use serde::{
+    de::{self, DeserializeOwned},
+    Deserialize, Deserializer,
+};
+use serde_yaml::Mapping;
+
+use crate::{
+    config_error::SourceMap,
+    config_interpolation::{self, LenientValue},
+    config_keys::{self, KnownKeys},
+    config_schema::{self, ConfigType},
+    module, registry,
+    request::host_without_port,
+    ssl::SslIdentity,
+    Config, ConfigError, ConfigList, ConfigLocation, ConfigMap, ConfigMapParser, ConfigOptions,
+    ConfigScope, ConfigSources, HostConfig, HostConfigParser, JequiConfig, Lifecycle, ListenConfig,
+    MatchConditions, MatchConfig, PathCaptures, PathMatcher, Plugin, Request, RouteExplanation,
+    RouteKey, Value,
+};
 
-use crate::{Config, ConfigMap, ConfigMapParser, HostConfig, JequiConfig, Plugin, Value};
+// Redirect statuses clients follow with the Location header
+const REDIRECT_STATUSES: [usize; 4] = [301, 302, 307, 308];
 
 impl Default for Config {
     fn default() -> Self {
@@ -21,17 +40,117 @@ impl Default for Config {
             chunk_size: 1024 * 64,
             ssl_certificate: None,
             ssl_key: None,
+            ssl_stapling_file: None,
+            proxy_protocol: false,
+            proxy_protocol_trusted: Vec::new(),
+            listen: Vec::new(),
+            listeners: Vec::new(),
+            redirect_to_https: false,
+            redirect_to_https_status: 301,
+            redirect_to_https_port: 443,
+            hsts_max_age: None,
+            strict_config: false,
+            watch_config: false,
+            admin_path: None,
+            admin_clients: vec!["127.0.0.0/8".parse().unwrap(), "::1".parse().unwrap()],
+            ssl_identity: SslIdentity::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Addresses the server should listen on, when `listen` isn't set a single listener is
+    /// made from `ip`, `port`, `tls_active` and `proxy_protocol`.
+    pub fn listen_configs(&self) -> Vec<ListenConfig> {
+        if !self.listen.is_empty() {
+            return self.listen.clone();
         }
+        vec![ListenConfig {
+            name: None,
+            ip: self.ip.clone(),
+            port: self.port,
+            tls: self.tls_active,
+            http2: None,
+            proxy_protocol: self.proxy_protocol,
+        }]
+    }
+}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        Self {
+            name: None,
+            ip: String::from("127.0.0.1"),
+            port: 7878,
+            tls: false,
+            http2: None,
+            proxy_protocol: false,
+        }
+    }
+}
+
+impl ListenConfig {
+    /// Name used by hosts in `listeners`, `ip:port` if the listener doesn't have one.
+    pub fn name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("{}:{}", self.ip, self.port),
+        }
+    }
+}
+
+impl ConfigType for ListenConfig {
+    fn type_name() -> String {
+        "listener".to_string()
+    }
+
+    fn schema() -> serde_json::Value {
+        config_schema::object_schema(&ListenConfig::options())
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "name": self.name,
+            "ip": self.ip,
+            "port": self.port,
+            "tls": self.tls,
+            "http2": self.http2,
+            "proxy_protocol": self.proxy_protocol,
+        })
     }
 }
 
 impl JequiConfig for Config {
-    fn load(config_yaml: &Value, _configs: &mut Vec<Option<Plugin>>) -> Option<Arc<Self>>
+    fn load(
+        config_yaml: &Value,
+        _configs: &mut Vec<Option<Plugin>>,
+    ) -> Result<Option<Arc<Self>>, ConfigError>
     where
         Self: Sized,
     {
-        let conf: Config = Deserialize::deserialize(config_yaml).unwrap();
-        Some(Arc::new(conf))
+        let conf: Config = deserialize(config_yaml)?;
+        // Any address could claim to be any client otherwise
+        let proxy_protocol = conf
+            .listen_configs()
+            .iter()
+            .any(|listener| listener.proxy_protocol);
+        if proxy_protocol && conf.proxy_protocol_trusted.is_empty() {
+            return Err(ConfigError::new(
+                "must list the addresses allowed to send the PROXY protocol header",
+            )
+            .with_key("proxy_protocol_trusted"));
+        }
+        if !REDIRECT_STATUSES.contains(&conf.redirect_to_https_status) {
+            return Err(ConfigError::new(format!(
+                "invalid redirect status {}, expected one of 301, 302, 307 or 308",
+                conf.redirect_to_https_status
+            ))
+            .with_key("redirect_to_https_status"));
+        }
+        if let (Some(key), Some(cert)) = (&conf.ssl_key, &conf.ssl_certificate) {
+            conf.ssl_identity.set(key.clone(), cert.clone());
+        }
+        Ok(Some(Arc::new(conf)))
     }
 
     fn as_any(&self) -> &dyn Any {
@@ -43,47 +162,264 @@ impl JequiConfig for Config {
     }
 }
 
+impl Lifecycle for Config {}
+
+// Tags of the options that replace or remove the value of the parent scope instead of being
+// merged with it
+const REPLACE_TAG: &str = "replace";
+const UNSET_TAG: &str = "unset";
+
+/// Merges `b` on top of `a`, maps are merged by key and sequences are concatenated. A value
+/// tagged `!replace` replaces the one in `a` and `!unset` removes it, the tags are kept in `a`
+/// so they also apply when it is merged on top of another scope, `resolve_tags` removes them.
 fn merge_yaml(a: &mut Value, b: Value) {
     match (a, b) {
-        (Value::Mapping(ref mut a), Value::Mapping(b)) => {
+        (a, b @ Value::Tagged(_)) => *a = b,
+        (Value::Tagged(a), b) if a.tag == REPLACE_TAG => merge_yaml(&mut a.value, b),
+        (Value::Mapping(a), Value::Mapping(b)) => {
             for (k, v) in b {
-                if let Some(b_seq) = v.as_sequence()
-                    && let Some(a_val) = a.get(&k)
-                    && let Some(a_seq) = a_val.as_sequence()
-                {
-                    a[&k] = [a_seq.as_slice(), b_seq.as_slice()].concat().into();
-                    continue;
-                }
-                if !a.contains_key(&k) {
-                    a.insert(k, v);
-                } else {
-                    merge_yaml(&mut a[&k], v);
+                match a.get_mut(&k) {
+                    Some(a) => merge_yaml(a, v),
+                    None => {
+                        a.insert(k, v);
+                    }
                 }
             }
         }
+        (Value::Sequence(a), Value::Sequence(b)) => a.extend(b),
         (a, b) => *a = b,
     }
 }
 
+/// Removes the options tagged `!unset` and the `!replace` tags from a merged config.
+pub(crate) fn resolve_tags(value: Value) -> Result<Value, String> {
+    Ok(match value {
+        Value::Tagged(tagged) if tagged.tag == REPLACE_TAG => resolve_tags(tagged.value)?,
+        Value::Tagged(tagged) => {
+            return Err(format!(
+                "unknown tag {}, expected !{} or !{}",
+                tagged.tag, REPLACE_TAG, UNSET_TAG
+            ))
+        }
+        Value::Mapping(mapping) => {
+            let mut resolved = Mapping::new();
+            for (key, value) in mapping {
+                if is_unset(&value) {
+                    continue;
+                }
+                resolved.insert(key, resolve_tags(value)?);
+            }
+            Value::Mapping(resolved)
+        }
+        Value::Sequence(sequence) => Value::Sequence(
+            sequence
+                .into_iter()
+                .map(resolve_tags)
+                .collect::<Result<_, _>>()?,
+        ),
+        value => value,
+    })
+}
+
+/// Merges the options of a scope on top of the ones of its parent, with `inherit: false` the
+/// options of the parent are discarded instead.
+pub(crate) fn merge_scope(parent: &mut Value, mut scope: Value) -> Result<(), ConfigError> {
+    let inherit = match scope
+        .as_mapping_mut()
+        .and_then(|scope| scope.remove("inherit"))
+    {
+        Some(Value::Bool(inherit)) => inherit,
+        Some(_) => {
+            return Err(ConfigError::new("invalid type, expected a boolean").with_key("inherit"))
+        }
+        None => true,
+    };
+    match inherit {
+        true => merge_yaml(parent, scope),
+        false => *parent = scope,
+    }
+    Ok(())
+}
+
+fn is_unset(value: &Value) -> bool {
+    matches!(value, Value::Tagged(tagged) if tagged.tag == UNSET_TAG)
+}
+
+/// Loads the plugins of a merged config.
+fn load_merged_plugins(
+    config_parser: &Value,
+    load_plugins: LoadPlugins,
+) -> Result<Vec<Plugin>, ConfigError> {
+    // Resolved by option so the error points to the option with the invalid tag
+    let mut resolved = Mapping::new();
+    for (key, value) in config_parser.as_mapping().into_iter().flatten() {
+        if is_unset(value) {
+            continue;
+        }
+        let value = resolve_tags(value.clone())
+            .map_err(|err| ConfigError::new(err).with_key(key.as_str().unwrap_or_default()))?;
+        resolved.insert(key.clone(), value);
+    }
+    load_plugins(&Value::Mapping(resolved))
+}
+
 fn merge_config_and_load_plugins(
-    mut config_parser: &mut Value,
+    config_parser: &mut Value,
     config_to_merge: Value,
     key_to_add: &str,
     value_to_add: String,
-    load_plugins: fn(&Value) -> Vec<Plugin>,
-) -> Vec<Plugin> {
-    // TODO: maybe merging doesn't make sense, i need to think about it
-    merge_yaml(&mut config_parser, config_to_merge);
+    load_plugins: LoadPlugins,
+) -> Result<Vec<Plugin>, ConfigError> {
+    merge_scope(config_parser, config_to_merge)?;
     if let Value::Mapping(ref mut config_parser) = config_parser {
         config_parser.insert(format!("config_{}", key_to_add).into(), value_to_add.into());
     }
-    load_plugins(config_parser)
+    load_merged_plugins(config_parser, load_plugins)
+}
+
+/// Function that loads every plugin for a scope, usually `registry::load_plugins`.
+pub type LoadPlugins = fn(&Value) -> Result<Vec<Plugin>, ConfigError>;
+
+/// `load_plugin` of a plugin, gets the plugins loaded before it.
+pub type LoadPlugin = fn(&Value, &mut Vec<Option<Plugin>>) -> Result<Option<Plugin>, ConfigError>;
+
+/// Deserializes the config of a plugin, numbers and booleans can be written as strings, like
+/// the values of `${...}` references. If it fails the key with the invalid value is added to
+/// the error.
+pub fn deserialize<T: DeserializeOwned>(config_yaml: &Value) -> Result<T, ConfigError> {
+    let err = match T::deserialize(LenientValue(config_yaml)) {
+        Ok(conf) => return Ok(conf),
+        Err(err) => err,
+    };
+
+    // Configs have defaults for every key, so each key can be tried alone to find the wrong one
+    if let Value::Mapping(mapping) = config_yaml {
+        for (key, value) in mapping {
+            let single = Mapping::from_iter([(key.clone(), value.clone())]);
+            if let Err(err) = T::deserialize(LenientValue(&Value::Mapping(single))) {
+                let err = ConfigError::new(err);
+                return Err(match key.as_str() {
+                    Some(key) => err.with_key(key),
+                    None => err,
+                });
+            }
+        }
+    }
+
+    Err(ConfigError::new(err))
+}
+
+fn mapping_keys(value: &Value) -> Vec<String> {
+    value
+        .as_mapping()
+        .into_iter()
+        .flat_map(|mapping| mapping.keys())
+        .filter_map(|key| key.as_str().map(|key| key.to_string()))
+        .collect()
+}
+
+/// Keys set in every scope of the file, by scope, with the keys leading to them.
+fn scope_keys(parser: &ConfigMapParser) -> Vec<(ConfigScope, Vec<String>, Vec<String>)> {
+    let mut scopes = Vec::new();
+    let mut add_scope = |scope: ConfigScope, value: &Value| {
+        let yaml_path = scope.yaml_path();
+        // The options of `match` entries are checked with the ones of their path block
+        let matches = value.get("match").and_then(|matches| matches.as_sequence());
+        for (i, entry) in matches.into_iter().flatten().enumerate() {
+            let condition_keys = config_keys::fields::<MatchConditions>();
+            let keys = mapping_keys(entry)
+                .into_iter()
+                .filter(|key| !condition_keys.contains(&key.as_str()))
+                .collect();
+            let entry_path = [yaml_path.clone(), vec!["match".to_string(), i.to_string()]];
+            scopes.push((scope.clone(), entry_path.concat(), keys));
+        }
+        let keys = mapping_keys(value)
+            .into_iter()
+            .filter(|key| (key != "match" || matches.is_none()) && key != "inherit")
+            .collect();
+        scopes.push((scope, yaml_path, keys));
+    };
+
+    add_scope(ConfigScope::Default, &parser.config);
+    for (host, host_parser) in parser.host.iter().flatten() {
+        add_scope(ConfigScope::Host(host.clone()), &host_parser.config);
+        for (path, value) in host_parser.path.iter().flatten() {
+            add_scope(ConfigScope::HostPath(host.clone(), path.clone()), value);
+        }
+    }
+    for (path, value) in parser.path.iter().flatten() {
+        add_scope(ConfigScope::Path(path.clone()), value);
+    }
+    scopes
 }
 
 impl ConfigMap {
-    pub fn load(path: &str, load_plugins: fn(&Value) -> Vec<Plugin>) -> ConfigMap {
+    pub fn load(path: &str, load_plugins: LoadPlugins) -> Result<ConfigMap, ConfigError> {
+        let (main_conf_parser, source_map) = ConfigMapParser::load_file(Path::new(path), &[])?;
+        let scope_keys = scope_keys(&main_conf_parser);
+
+        // Every key is checked before any plugin is loaded, so a key in the wrong scope fails
+        // before their setup runs
+        let default = resolve_tags(main_conf_parser.config.clone())
+            .map_err(|err| ConfigError::new(err).with_scope(ConfigScope::Default, &source_map))?;
+        let module_options = module::module_options(&default)
+            .map_err(|err| err.with_scope(ConfigScope::Default, &source_map))?;
+        let known_keys = KnownKeys::new(&registry::config_options()?, module_options);
+        let strict = deserialize::<Config>(&default)
+            .map_err(|err| err.with_scope(ConfigScope::Default, &source_map))?
+            .strict_config;
+        for (scope, yaml_path, keys) in scope_keys {
+            for key in keys {
+                let location = source_map
+                    .get(&[yaml_path.as_slice(), &[key.clone()]].concat())
+                    .map(Box::new);
+                if let Some(scopes) = known_keys.scopes.get(key.as_str())
+                    && !scopes.contains(&scope.kind())
+                {
+                    let scopes: Vec<String> = scopes.iter().map(ToString::to_string).collect();
+                    let mut err = ConfigError::new(format!(
+                        "can't be set in a {} scope, only in: {}",
+                        scope.kind(),
+                        scopes.join(", ")
+                    ))
+                    .with_key(&key);
+                    err.location = location;
+                    return Err(err.with_scope(scope, &source_map));
+                }
+                if known_keys.keys.contains(key.as_str())
+                    || config_keys::INJECTED_KEYS.contains(&key.as_str())
+                {
+                    continue;
+                }
+                let message = match config_keys::closest(&key, known_keys.keys.iter().copied()) {
+                    Some(closest) => format!("unknown option, did you mean `{}`?", closest),
+                    None => "unknown option".to_string(),
+                };
+                let mut err = ConfigError::new(message).with_key(&key);
+                err.location = location;
+                let err = err.with_scope(scope.clone(), &source_map);
+                if strict {
+                    return Err(err);
+                }
+                println!("[WARN] {}", err);
+            }
+        }
+
+        let parser = main_conf_parser.clone();
+        let mut main_conf = ConfigMap::load_parser(main_conf_parser, &source_map, load_plugins)?;
+        main_conf.files = source_map.files().to_vec();
+        main_conf.include_globs = source_map.include_globs().to_vec();
+        main_conf.sources = Some(ConfigSources::new(parser, source_map));
+        Ok(main_conf)
+    }
+
+    fn load_parser(
+        main_conf_parser: ConfigMapParser,
+        source_map: &SourceMap,
+        load_plugins: LoadPlugins,
+    ) -> Result<ConfigMap, ConfigError> {
         let mut main_conf = ConfigMap::default();
-        let main_conf_parser = ConfigMapParser::load_config(path);
 
         let config_parser = main_conf_parser.config.clone();
         for (host, host_config_parser) in main_conf_parser.host.into_iter().flatten() {
@@ -94,9 +430,17 @@ impl ConfigMap {
                 "host",
                 host.clone(),
                 load_plugins,
-            );
+            )
+            .map_err(|err| err.with_scope(ConfigScope::Host(host.clone()), source_map))?;
             let mut path_config: Option<HashMap<PathBuf, Vec<Plugin>>> = None;
-            for (path, path_config_parser) in host_config_parser.path.into_iter().flatten() {
+            let mut path_matcher = PathMatcher::default();
+            let mut path_match = HashMap::new();
+            for (path, mut path_config_parser) in host_config_parser.path.into_iter().flatten() {
+                let scope = ConfigScope::HostPath(host.clone(), path.clone());
+                path_matcher
+                    .push(path.clone())
+                    .map_err(|err| ConfigError::new(err).with_scope(scope.clone(), source_map))?;
+                let matches = take_matches(&mut path_config_parser);
                 let mut config_parser = config_parser.clone();
                 let plugin_list = merge_config_and_load_plugins(
                     &mut config_parser,
@@ -104,7 +448,13 @@ impl ConfigMap {
                     "path",
                     path.to_string_lossy().to_string(),
                     load_plugins,
-                );
+                )
+                .map_err(|err| err.with_scope(scope.clone(), source_map))?;
+                let match_configs =
+                    load_matches(matches, &config_parser, &scope, source_map, load_plugins)?;
+                if !match_configs.is_empty() {
+                    path_match.insert(path.clone(), match_configs);
+                }
                 path_config
                     .get_or_insert_default()
                     .insert(path, plugin_list);
@@ -113,12 +463,20 @@ impl ConfigMap {
                 host,
                 HostConfig {
                     path: path_config,
+                    path_matcher,
+                    path_match,
                     config: plugin_list,
                 },
             );
         }
 
-        for (path, path_config_parser) in main_conf_parser.path.into_iter().flatten() {
+        for (path, mut path_config_parser) in main_conf_parser.path.into_iter().flatten() {
+            let scope = ConfigScope::Path(path.clone());
+            main_conf
+                .path_matcher
+                .push(path.clone())
+                .map_err(|err| ConfigError::new(err).with_scope(scope.clone(), source_map))?;
+            let matches = take_matches(&mut path_config_parser);
             let mut config_parser = config_parser.clone();
             let plugin_list = merge_config_and_load_plugins(
                 &mut config_parser,
@@ -126,84 +484,694 @@ impl ConfigMap {
                 "path",
                 path.to_string_lossy().to_string(),
                 load_plugins,
-            );
+            )
+            .map_err(|err| err.with_scope(scope.clone(), source_map))?;
+            let match_configs =
+                load_matches(matches, &config_parser, &scope, source_map, load_plugins)?;
+            if !match_configs.is_empty() {
+                main_conf.path_match.insert(path.clone(), match_configs);
+            }
             main_conf
                 .path
                 .get_or_insert_default()
                 .insert(path, plugin_list);
         }
 
-        main_conf.config = load_plugins(&config_parser);
-        main_conf
+        main_conf.config = load_merged_plugins(&config_parser, load_plugins)
+            .map_err(|err| err.with_scope(ConfigScope::Default, source_map))?;
+        Ok(main_conf)
+    }
+
+    /// Merged options of the scope used for `host` and `path`, the same options the plugins of
+    /// that scope are loaded with. `match` entries aren't merged since they depend on the
+    /// request. Also returns the scope the options come from.
+    pub fn effective_config(
+        file: &str,
+        host: Option<&str>,
+        path: Option<&str>,
+    ) -> Result<(ConfigScope, Value), ConfigError> {
+        let explanation = Self::explain_file_route(file, host, path)?;
+        Ok((explanation.scope, explanation.config))
+    }
+
+    // Only the file is read, the plugins aren't loaded so the explanation doesn't list them
+    fn explain_file_route(
+        file: &str,
+        host: Option<&str>,
+        path: Option<&str>,
+    ) -> Result<RouteExplanation, ConfigError> {
+        let (parser, source_map) = ConfigMapParser::load_file(Path::new(file), &[])?;
+        let host = host.map(host_without_port);
+        let (host, paths) = match host.and_then(|host| parser.host.as_ref()?.get_key_value(host)) {
+            Some((host, host_parser)) => (Some(host.clone()), &host_parser.path),
+            None => (None, &parser.path),
+        };
+
+        let mut path_matcher = PathMatcher::default();
+        for key in paths.iter().flat_map(|paths| paths.keys()) {
+            path_matcher.push(key.clone()).map_err(ConfigError::new)?;
+        }
+        let path = path
+            .and_then(|path| path_matcher.find(path))
+            .map(|(key, _)| key.to_path_buf());
+
+        let sources = ConfigSources::new(parser, source_map);
+        sources.merge(host.as_deref(), path.as_deref())
+    }
+
+    /// Iterates over the plugin lists of every scope.
+    pub fn config_lists(&self) -> impl Iterator<Item = &ConfigList> {
+        fn match_configs(
+            path_match: &HashMap<PathBuf, Vec<MatchConfig>>,
+        ) -> impl Iterator<Item = &ConfigList> {
+            path_match
+                .values()
+                .flatten()
+                .map(|match_config| &match_config.config)
+        }
+        let host_configs = self.host.iter().flatten().flat_map(|(_, host_config)| {
+            std::iter::once(&host_config.config)
+                .chain(host_config.path.iter().flat_map(|path| path.values()))
+                .chain(match_configs(&host_config.path_match))
+        });
+        std::iter::once(&self.config)
+            .chain(host_configs)
+            .chain(self.path.iter().flat_map(|path| path.values()))
+            .chain(match_configs(&self.path_match))
     }
 
     pub fn get_config_for_request(&self, host: Option<&str>, path: Option<&str>) -> &Vec<Plugin> {
-        let mut config = &self.config;
+        self.get_config_for_listener(None, host, path)
+    }
+
+    /// Same as `get_config_for_request`, but hosts that are restricted to other listeners are
+    /// handled by the default scope.
+    pub fn get_config_for_listener(
+        &self,
+        listener: Option<&str>,
+        host: Option<&str>,
+        path: Option<&str>,
+    ) -> &Vec<Plugin> {
+        self.route(listener, host, path, None).0
+    }
+
+    /// Config used for `request`, the `match` entries of its path block are checked in order
+    /// and the first one that matches is used. Also returns the groups captured when the path
+    /// matched a regex `path` block.
+    pub fn match_request(&self, request: &Request) -> (&Vec<Plugin>, PathCaptures) {
+        let (config, captures, _) = self.route(
+            request.conn_info.listener.as_deref(),
+            request.host.as_deref(),
+            Some(request.uri.path()),
+            Some(request),
+        );
+        (config, captures)
+    }
+
+    /// Config chosen for `request` by `Request::handle_request`, so its response is filtered
+    /// and logged with the config that handled it even if a handler changed its uri. Requests
+    /// that weren't handled yet are matched again.
+    pub fn request_config(&self, request: &Request) -> &Vec<Plugin> {
+        request
+            .route
+            .as_ref()
+            .and_then(|route| self.route_config(route))
+            .unwrap_or_else(|| self.match_request(request).0)
+    }
+
+    /// Same as `match_request`, also returning the blocks chosen so they can be found again.
+    pub(crate) fn match_route(&self, request: &Request) -> (&Vec<Plugin>, PathCaptures, RouteKey) {
+        self.route(
+            request.conn_info.listener.as_deref(),
+            request.host.as_deref(),
+            Some(request.uri.path()),
+            Some(request),
+        )
+    }
+
+    fn route_config(&self, route: &RouteKey) -> Option<&Vec<Plugin>> {
+        let (config, path_map, path_match) = match &route.host {
+            Some(host) => {
+                let host_config = self.host.as_ref()?.get(host)?;
+                (
+                    &host_config.config,
+                    &host_config.path,
+                    &host_config.path_match,
+                )
+            }
+            None => (&self.config, &self.path, &self.path_match),
+        };
+        let Some(path) = &route.path else {
+            return Some(config);
+        };
+        match route.match_index {
+            Some(index) => Some(&path_match.get(path)?.get(index)?.config),
+            None => path_map.as_ref()?.get(path),
+        }
+    }
+
+    fn route(
+        &self,
+        listener: Option<&str>,
+        host: Option<&str>,
+        path: Option<&str>,
+        request: Option<&Request>,
+    ) -> (&Vec<Plugin>, PathCaptures, RouteKey) {
+        let route = self.select(listener, host, path);
+        let mut key = RouteKey {
+            host: route.host.map(str::to_string),
+            path: route.path.map(Path::to_path_buf),
+            match_index: None,
+        };
+        if let Some(request) = request
+            && let Some(path) = route.path
+            && let Some(match_configs) = route.path_match.get(path)
+            && let Some(index) = match_configs
+                .iter()
+                .position(|match_config| match_config.conditions.matches(request))
+        {
+            key.match_index = Some(index);
+            return (&match_configs[index].config, route.captures, key);
+        }
+        (route.config, route.captures, key)
+    }
+
+    /// Chooses the host and path blocks for a request, without checking their `match` entries.
+    pub(crate) fn select(
+        &self,
+        listener: Option<&str>,
+        host: Option<&str>,
+        path: Option<&str>,
+    ) -> Route<'_> {
+        let mut route = Route {
+            host: None,
+            path: None,
+            config: &self.config,
+            captures: PathCaptures::default(),
+            path_match: &self.path_match,
+        };
         let mut path_map = &self.path;
+        let mut path_matcher = &self.path_matcher;
         if let Some(host_map) = &self.host
             && let Some(host) = host
-            && let Some(host_config) = host_map.get(host.split(':').next().unwrap())
+            && let Some((host, host_config)) = host_map.get_key_value(host_without_port(host))
+            && host_config.serves_listener(listener)
         {
-            config = &host_config.config;
+            route.host = Some(host);
+            route.config = &host_config.config;
+            route.path_match = &host_config.path_match;
             path_map = &host_config.path;
+            path_matcher = &host_config.path_matcher;
         }
 
-        let path = match path {
-            Some(path) => path,
-            None => return config,
-        };
+        if let (Some(path), Some(path_map)) = (path, path_map)
+            && let Some((key, captures)) = path_matcher.find(path)
+        {
+            route.path = Some(key);
+            route.config = &path_map[key];
+            route.captures = captures;
+        }
+        route
+    }
+}
 
-        let path_map = match path_map {
-            Some(path_map) => {
-                if path_map.is_empty() {
-                    return config;
-                }
-                path_map
+/// Host and path blocks chosen for a request by `ConfigMap::select`, `None` when the request
+/// isn't in one.
+pub(crate) struct Route<'a> {
+    pub(crate) host: Option<&'a str>,
+    pub(crate) path: Option<&'a Path>,
+    pub(crate) config: &'a ConfigList,
+    pub(crate) captures: PathCaptures,
+    path_match: &'a HashMap<PathBuf, Vec<MatchConfig>>,
+}
+
+impl HostConfig {
+    fn serves_listener(&self, listener: Option<&str>) -> bool {
+        let plugin_list = &self.config;
+        let conf = get_plugin!(plugin_list, jequi).unwrap();
+        match listener {
+            Some(listener) => {
+                conf.listeners.is_empty() || conf.listeners.iter().any(|name| name == listener)
+            }
+            None => true,
+        }
+    }
+}
+
+/// Removes the `match` list of a path block.
+pub(crate) fn take_matches(path_config_parser: &mut Value) -> Option<Value> {
+    path_config_parser.as_mapping_mut()?.remove("match")
+}
+
+/// Loads the entries of the `match` list of a path block, `config_parser` has the options of
+/// the path block that the entries can replace.
+fn load_matches(
+    matches: Option<Value>,
+    config_parser: &Value,
+    scope: &ConfigScope,
+    source_map: &SourceMap,
+    load_plugins: LoadPlugins,
+) -> Result<Vec<MatchConfig>, ConfigError> {
+    let Some(matches) = matches else {
+        return Ok(Vec::new());
+    };
+    let Value::Sequence(entries) = matches else {
+        return Err(ConfigError::new("must be a list of conditions and options")
+            .with_key("match")
+            .with_scope(scope.clone(), source_map));
+    };
+    let condition_keys = config_keys::fields::<MatchConditions>();
+
+    let mut match_configs = Vec::new();
+    for (i, entry) in entries.into_iter().enumerate() {
+        let match_path = [scope.yaml_path(), vec!["match".to_string()]].concat();
+        // Errors point to the key inside the entry, or to `match` if it isn't found
+        let locate = |mut err: ConfigError| {
+            if err.location.is_none() {
+                let mut key_path = [match_path.clone(), vec![i.to_string()]].concat();
+                key_path.extend(err.key.clone());
+                err.location = source_map
+                    .get(&key_path)
+                    .or_else(|| source_map.get(&match_path))
+                    .map(Box::new);
             }
-            None => return config,
+            err.with_scope(scope.clone(), source_map)
         };
 
-        let mut path = PathBuf::from(path);
+        let Value::Mapping(entry) = entry else {
+            return Err(locate(ConfigError::new(
+                "match entries must be a map of conditions and options",
+            )));
+        };
+        let (conditions, options): (Mapping, Mapping) = entry.into_iter().partition(|(key, _)| {
+            key.as_str()
+                .is_some_and(|key| condition_keys.contains(&key))
+        });
+        let conditions: MatchConditions =
+            deserialize(&Value::Mapping(conditions)).map_err(locate)?;
+        if conditions.is_empty() {
+            return Err(locate(ConfigError::new(format!(
+                "match entry needs at least one of {}",
+                condition_keys.join(", ")
+            ))));
+        }
+
+        let mut config_parser = config_parser.clone();
+        merge_scope(&mut config_parser, Value::Mapping(options)).map_err(locate)?;
+        let config = load_merged_plugins(&config_parser, load_plugins).map_err(locate)?;
+        match_configs.push(MatchConfig { conditions, config });
+    }
+    Ok(match_configs)
+}
+
+// Limit for files including each other, cycles are found before reaching it
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Checks that `include` can be loaded by the last file of `stack`, the files being loaded
+/// from the main one. A file that includes itself, directly or not, is an error naming the
+/// cycle.
+fn check_include(stack: &[PathBuf], include: &Path) -> Result<(), ConfigError> {
+    let canonical = |path: &Path| path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let include_path = canonical(include);
+    if let Some(start) = stack
+        .iter()
+        .position(|file| canonical(file) == include_path)
+    {
+        let cycle: Vec<String> = stack[start..]
+            .iter()
+            .map(|file| file.as_path())
+            .chain([include])
+            .map(|file| file.display().to_string())
+            .collect();
+        return Err(ConfigError::new(format!(
+            "include cycle: {}",
+            cycle.join(" -> ")
+        )));
+    }
+    if stack.len() > MAX_INCLUDE_DEPTH {
+        return Err(ConfigError::new(format!(
+            "too many nested includes at {}",
+            include.display()
+        )));
+    }
+    Ok(())
+}
+
+fn parse_file<T: DeserializeOwned>(
+    filename: &Path,
+    prefix: &[String],
+) -> Result<(T, SourceMap), ConfigError> {
+    let source = std::fs::read_to_string(filename)
+        .map_err(|err| ConfigError::new(format!("can't read {}: {}", filename.display(), err)))?;
+    let mut parsed: Value = serde_yaml::from_str(&source).map_err(|err| {
+        let mut config_err = ConfigError::new(format!("failed to parse config: {}", err));
+        config_err.location = err.location().map(|location| {
+            Box::new(ConfigLocation {
+                file: filename.to_path_buf(),
+                line: location.line(),
+                column: location.column(),
+            })
+        });
+        config_err
+    })?;
+    let mut source_map = SourceMap::parse(filename, &source, prefix);
+    let dir = filename.parent().unwrap_or(Path::new(""));
+    config_interpolation::interpolate(&mut parsed, &mut prefix.to_vec(), &mut source_map, dir)?;
+    let parsed = serde_yaml::from_value(parsed).map_err(|err| {
+        ConfigError::new(format!(
+            "failed to parse config {}: {}",
+            filename.display(),
+            err
+        ))
+    })?;
+    Ok((parsed, source_map))
+}
+
+/// Removes the `include` key of `config`, returns the files matching its globs and the globs
+/// with wildcards. Relative globs start from the directory of `filename`.
+fn take_includes(
+    config: &mut Value,
+    filename: &Path,
+    prefix: &[String],
+    source_map: &SourceMap,
+) -> Result<(Vec<PathBuf>, Vec<PathBuf>), ConfigError> {
+    let Some(include) = config
+        .as_mapping_mut()
+        .and_then(|config| config.remove("include"))
+    else {
+        return Ok((Vec::new(), Vec::new()));
+    };
+
+    let include_error = |message: String| {
+        let mut err = ConfigError::new(message).with_key("include");
+        err.location = source_map
+            .get(&[prefix, &["include".to_string()]].concat())
+            .map(Box::new);
+        err
+    };
 
-        if let Some(config) = path_map.get(&path) {
-            return config;
+    let patterns: Vec<String> = match include {
+        Value::String(pattern) => vec![pattern],
+        include => serde_yaml::from_value(include)
+            .map_err(|_| include_error("expected a glob or a list of globs".to_string()))?,
+    };
+
+    let dir = filename.parent().unwrap_or(Path::new(""));
+    let mut files = Vec::new();
+    let mut globs = Vec::new();
+    for pattern in patterns {
+        let full_pattern = dir.join(&pattern);
+        let matches = glob::glob(&full_pattern.to_string_lossy())
+            .map_err(|err| include_error(format!("invalid glob {}: {}", pattern, err)))?;
+        let mut matched = matches
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| include_error(err.to_string()))?;
+        let is_glob = pattern.contains(['*', '?', '[']);
+        if matched.is_empty() && !is_glob {
+            return Err(include_error(format!(
+                "file {} doesn't exist",
+                full_pattern.display()
+            )));
+        }
+        matched.sort();
+        files.extend(matched);
+        if is_glob {
+            globs.push(full_pattern);
         }
+    }
+    Ok((files, globs))
+}
 
-        loop {
-            if let Some(config) = path_map.get(&path) {
-                return config;
-            }
+fn duplicate_error(
+    kind: &str,
+    name: &str,
+    path: &[String],
+    first: &SourceMap,
+    second: &SourceMap,
+) -> ConfigError {
+    let mut message = format!("{} {} is defined more than once", kind, name);
+    if let Some(location) = first.get(path) {
+        message += &format!(", first at {}:{}", location.file.display(), location.line);
+    }
+    let mut err = ConfigError::new(message);
+    err.location = second.get(path).map(Box::new);
+    err
+}
 
-            if !path.pop() {
-                return config;
-            }
+/// Adds the paths of `other` to `paths`, a path can't be set by both.
+fn merge_paths(
+    paths: &mut Option<IndexMap<PathBuf, Value>>,
+    other: Option<IndexMap<PathBuf, Value>>,
+    prefix: &[String],
+    source_map: &SourceMap,
+    other_source_map: &SourceMap,
+) -> Result<(), ConfigError> {
+    for (path, value) in other.into_iter().flatten() {
+        let paths = paths.get_or_insert_default();
+        if paths.contains_key(&path) {
+            let path_keys = [
+                prefix,
+                &["path".to_string()],
+                &[path.to_string_lossy().to_string()],
+            ]
+            .concat();
+            return Err(duplicate_error(
+                "path",
+                &path.to_string_lossy(),
+                &path_keys,
+                source_map,
+                other_source_map,
+            ));
+        }
+        paths.insert(path, value);
+    }
+    Ok(())
+}
+
+/// Removes `key` from the options of a scope and deserializes it.
+fn take_key<T: DeserializeOwned, E: de::Error>(
+    config: &mut Value,
+    key: &str,
+) -> Result<Option<T>, E> {
+    match config
+        .as_mapping_mut()
+        .and_then(|config| config.remove(key))
+    {
+        Some(value) => serde_yaml::from_value(value)
+            .map(Some)
+            .map_err(|err| E::custom(format!("{}: {}", key, err))),
+        None => Ok(None),
+    }
+}
+
+/// Options of a scope, `#[serde(flatten)]` isn't used because it loses the tags of the values.
+fn scope_options<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Value, D::Error> {
+    match Value::deserialize(deserializer)? {
+        Value::Null => Ok(Value::Mapping(Mapping::new())),
+        config @ Value::Mapping(_) => Ok(config),
+        _ => Err(de::Error::custom("invalid type, expected a map of options")),
+    }
+}
+
+impl<'de> Deserialize<'de> for HostConfigParser {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut config = scope_options(deserializer)?;
+        Ok(HostConfigParser {
+            path: take_key(&mut config, "path")?,
+            config,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for ConfigMapParser {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut config = scope_options(deserializer)?;
+        Ok(ConfigMapParser {
+            host: take_key(&mut config, "host")?,
+            path: take_key(&mut config, "path")?,
+            config,
+        })
+    }
+}
+
+impl HostConfigParser {
+    fn load_file(
+        filename: &Path,
+        prefix: &[String],
+        including: &[PathBuf],
+    ) -> Result<(HostConfigParser, SourceMap), ConfigError> {
+        let (mut parser, file_map): (HostConfigParser, _) = parse_file(filename, prefix)?;
+        let stack = [including, &[filename.to_path_buf()]].concat();
+        let mut merged = HostConfigParser {
+            path: None,
+            config: Value::Null,
+        };
+        let mut source_map = SourceMap::default();
+        parser.include_files(
+            filename,
+            prefix,
+            &file_map,
+            &stack,
+            &mut merged,
+            &mut source_map,
+        )?;
+        merged.merge(parser, prefix, &source_map, &file_map)?;
+        source_map.extend(file_map);
+        Ok((merged, source_map))
+    }
+
+    /// Loads the files included by this host into `merged`, `stack` are the files being loaded
+    /// up to `filename`.
+    fn include_files(
+        &mut self,
+        filename: &Path,
+        prefix: &[String],
+        file_map: &SourceMap,
+        stack: &[PathBuf],
+        merged: &mut HostConfigParser,
+        source_map: &mut SourceMap,
+    ) -> Result<(), ConfigError> {
+        let (includes, globs) = take_includes(&mut self.config, filename, prefix, file_map)?;
+        source_map.add_include_globs(globs);
+        for include in includes {
+            check_include(stack, &include)?;
+            let (included, included_map) = HostConfigParser::load_file(&include, prefix, stack)?;
+            merged.merge(included, prefix, source_map, &included_map)?;
+            source_map.extend(included_map);
         }
+        Ok(())
+    }
+
+    /// Merges `other` on top of this host config.
+    fn merge(
+        &mut self,
+        other: HostConfigParser,
+        prefix: &[String],
+        source_map: &SourceMap,
+        other_source_map: &SourceMap,
+    ) -> Result<(), ConfigError> {
+        merge_paths(
+            &mut self.path,
+            other.path,
+            prefix,
+            source_map,
+            other_source_map,
+        )?;
+        merge_yaml(&mut self.config, other.config);
+        Ok(())
     }
 }
 
 impl ConfigMapParser {
-    pub fn load_config(filename: &str) -> ConfigMapParser {
-        let file_reader = std::fs::File::open(filename).unwrap();
-        let config_map: ConfigMapParser = from_reader(file_reader)
-            .unwrap_or_else(|_| panic!("Failed to parse config for `{}`", filename));
+    pub fn load_config(filename: &str) -> Result<ConfigMapParser, ConfigError> {
+        Ok(ConfigMapParser::load_file(Path::new(filename), &[])?.0)
+    }
+
+    /// Loads `filename` and the files included by it, the values of a file replace the ones of
+    /// the files it includes. `including` are the files that include it, from the main one.
+    pub(crate) fn load_file(
+        filename: &Path,
+        including: &[PathBuf],
+    ) -> Result<(ConfigMapParser, SourceMap), ConfigError> {
+        let (mut parser, file_map): (ConfigMapParser, _) = parse_file(filename, &[])?;
+        let stack = [including, &[filename.to_path_buf()]].concat();
+        let mut merged = ConfigMapParser {
+            host: None,
+            path: None,
+            config: Value::Null,
+        };
+        let mut source_map = SourceMap::default();
 
-        config_map
+        let (includes, globs) = take_includes(&mut parser.config, filename, &[], &file_map)?;
+        source_map.add_include_globs(globs);
+        for include in includes {
+            check_include(&stack, &include)?;
+            let (included, included_map) = ConfigMapParser::load_file(&include, &stack)?;
+            merged.merge(included, &source_map, &included_map)?;
+            source_map.extend(included_map);
+        }
+
+        for (host, host_parser) in parser.host.iter_mut().flatten() {
+            let prefix = vec!["host".to_string(), host.clone()];
+            let mut included = HostConfigParser {
+                path: None,
+                config: Value::Null,
+            };
+            let mut included_map = SourceMap::default();
+            host_parser.include_files(
+                filename,
+                &prefix,
+                &file_map,
+                &stack,
+                &mut included,
+                &mut included_map,
+            )?;
+            let own = std::mem::replace(host_parser, included);
+            host_parser.merge(own, &prefix, &included_map, &file_map)?;
+            source_map.extend(included_map);
+        }
+
+        merged.merge(parser, &source_map, &file_map)?;
+        source_map.extend(file_map);
+        Ok((merged, source_map))
+    }
+
+    fn merge(
+        &mut self,
+        other: ConfigMapParser,
+        source_map: &SourceMap,
+        other_source_map: &SourceMap,
+    ) -> Result<(), ConfigError> {
+        for (host, host_parser) in other.host.into_iter().flatten() {
+            let hosts = self.host.get_or_insert_default();
+            if hosts.contains_key(&host) {
+                return Err(duplicate_error(
+                    "host",
+                    &host,
+                    &["host".to_string(), host.clone()],
+                    source_map,
+                    other_source_map,
+                ));
+            }
+            hosts.insert(host, host_parser);
+        }
+        merge_paths(
+            &mut self.path,
+            other.path,
+            &[],
+            source_map,
+            other_source_map,
+        )?;
+        merge_yaml(&mut self.config, other.config);
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{path::Path, sync::Arc};
+    use std::{
+        path::{Path, PathBuf},
+        sync::Arc,
+    };
+
+    use http::HeaderValue;
 
-    use crate::{load_plugin, Config, ConfigMap, ConfigMapParser, JequiConfig, Plugin};
+    use crate::{
+        load_plugin, proxy_protocol::Cidr, Config, ConfigMap, ConfigMapParser, ConfigScope,
+        ConnInfo, JequiConfig, ListenConfig, Plugin, Request, Uri, Value,
+    };
+
+    use super::{merge_scope, merge_yaml, resolve_tags};
 
     static CONF_TEST_PATH: &str = "test/test.conf";
 
     #[test]
     fn load_config_test() {
-        let main_conf = ConfigMapParser::load_config(CONF_TEST_PATH);
+        let main_conf = ConfigMapParser::load_config(CONF_TEST_PATH).unwrap();
 
-        let conf = Config::load(&main_conf.config, &mut Vec::new()).unwrap();
+        let conf = Config::load(&main_conf.config, &mut Vec::new())
+            .unwrap()
+            .unwrap();
 
         let test_config = Arc::new(Config {
             ip: "1.1.1.1".to_owned(),
@@ -217,11 +1185,12 @@ mod tests {
     #[test]
     fn get_config_for_request_test() {
         let config_map = ConfigMap::load(CONF_TEST_PATH, |val| {
-            vec![load_plugin(val, &mut Vec::new()).unwrap()]
-        });
+            Ok(vec![load_plugin(val, &mut Vec::new())?.unwrap()])
+        })
+        .unwrap();
 
         let get_config: fn(&Vec<Plugin>) -> &Config = |conf| {
-            conf.get(0)
+            conf.first()
                 .unwrap()
                 .config
                 .as_any()
@@ -323,4 +1292,501 @@ mod tests {
             "1.2.1.2"
         );
     }
+
+    #[test]
+    fn listen_configs_test() {
+        let conf = Config {
+            ip: "1.1.1.1".to_owned(),
+            port: 443,
+            tls_active: true,
+            ..Default::default()
+        };
+        let listeners = conf.listen_configs();
+        assert_eq!(
+            listeners,
+            vec![ListenConfig {
+                ip: "1.1.1.1".to_owned(),
+                port: 443,
+                tls: true,
+                ..Default::default()
+            }]
+        );
+        assert_eq!(listeners[0].name(), "1.1.1.1:443");
+
+        let conf: Config = serde_yaml::from_str(
+            "
+listen:
+  - port: 80
+  - name: https
+    port: 443
+    tls: true
+    http2: true
+",
+        )
+        .unwrap();
+        let listeners = conf.listen_configs();
+        assert_eq!(listeners.len(), 2);
+        assert_eq!(listeners[0].name(), "127.0.0.1:80");
+        assert!(!listeners[0].tls);
+        assert_eq!(listeners[1].name(), "https");
+        assert!(listeners[1].tls);
+        assert_eq!(listeners[1].http2, Some(true));
+    }
+
+    #[test]
+    fn proxy_protocol_trusted_test() {
+        let load = |yaml: &str| Config::load(&serde_yaml::from_str(yaml).unwrap(), &mut Vec::new());
+
+        let err = load("proxy_protocol: true").err().unwrap();
+        assert_eq!(err.key.as_deref(), Some("proxy_protocol_trusted"));
+        let err = load("listen:\n  - port: 80\n    proxy_protocol: true")
+            .err()
+            .unwrap();
+        assert_eq!(err.key.as_deref(), Some("proxy_protocol_trusted"));
+
+        assert!(load("proxy_protocol: true\nproxy_protocol_trusted: [10.0.0.0/8]").is_ok());
+        assert!(load("proxy_protocol: false").is_ok());
+    }
+
+    #[test]
+    fn redirect_to_https_status_test() {
+        let load = |yaml: &str| Config::load(&serde_yaml::from_str(yaml).unwrap(), &mut Vec::new());
+
+        let err = load("redirect_to_https_status: 200").err().unwrap();
+        assert_eq!(err.key.as_deref(), Some("redirect_to_https_status"));
+        assert_eq!(
+            err.message,
+            "invalid redirect status 200, expected one of 301, 302, 307 or 308"
+        );
+
+        for status in [301, 302, 307, 308] {
+            assert!(load(&format!("redirect_to_https_status: {}", status)).is_ok());
+        }
+    }
+
+    #[test]
+    fn get_config_for_listener_test() {
+        let config_map = ConfigMap::load(CONF_TEST_PATH, |val| {
+            Ok(vec![load_plugin(val, &mut Vec::new())?.unwrap()])
+        })
+        .unwrap();
+
+        let get_ip = |listener, host| {
+            config_map
+                .get_config_for_listener(listener, Some(host), Some("/"))
+                .first()
+                .unwrap()
+                .config
+                .as_any()
+                .downcast_ref::<Config>()
+                .unwrap()
+                .ip
+                .clone()
+        };
+
+        assert_eq!(get_ip(Some("https"), "www.jequi.com"), "1.1.3.1");
+        assert_eq!(get_ip(None, "www.jequi.com"), "1.1.3.1");
+        assert_eq!(get_ip(Some("http"), "www.jequi.com"), "1.1.1.1");
+        assert_eq!(get_ip(Some("http"), "jequi.com"), "1.1.2.1");
+    }
+
+    #[test]
+    fn load_invalid_config_test() {
+        let err = ConfigMap::load("test/invalid.conf", |val| {
+            Ok(vec![load_plugin(val, &mut Vec::new())?.unwrap()])
+        })
+        .unwrap_err();
+
+        assert_eq!(err.key.as_deref(), Some("chunk_size"));
+        assert_eq!(err.scope, Some(ConfigScope::Host("jequi.com".to_string())));
+        let location = err.location.unwrap();
+        assert_eq!((location.line, location.column), (4, 9));
+
+        let err = ConfigMapParser::load_config("test/missing.conf")
+            .err()
+            .unwrap();
+        assert!(err.message.contains("test/missing.conf"));
+    }
+
+    #[test]
+    fn path_modifiers_test() {
+        let config_map = ConfigMap::load("test/locations.conf", |val| {
+            Ok(vec![load_plugin(val, &mut Vec::new())?.unwrap()])
+        })
+        .unwrap();
+
+        let get_ip = |path: &str| {
+            let mut request = Request::new();
+            request.uri = Uri::from(path.to_string());
+            let (config, captures) = config_map.match_request(&request);
+            let conf = config[0].config.as_any().downcast_ref::<Config>().unwrap();
+            (
+                conf.ip.clone(),
+                captures.name("name").map(|name| name.to_string()),
+            )
+        };
+
+        assert_eq!(get_ip("/img"), ("1.2.1.2".to_string(), None));
+        assert_eq!(get_ip("/img/logo.jpg"), ("1.2.1.1".to_string(), None));
+        assert_eq!(
+            get_ip("/img/icons/logo.png"),
+            ("1.2.1.3".to_string(), Some("icons/logo".to_string()))
+        );
+        assert_eq!(
+            get_ip("/img/static/logo.png"),
+            ("1.2.1.4".to_string(), None)
+        );
+        assert_eq!(get_ip("/img/../logo.png"), ("1.1.1.1".to_string(), None));
+
+        let err = ConfigMap::load("test/locations-invalid.conf", |val| {
+            Ok(vec![load_plugin(val, &mut Vec::new())?.unwrap()])
+        })
+        .err()
+        .unwrap();
+        assert!(err.message.starts_with("invalid regex"));
+        let location = err.location.unwrap();
+        assert_eq!((location.line, location.column), (5, 13));
+    }
+
+    #[test]
+    fn match_conditions_test() {
+        let config_map = ConfigMap::load("test/match.conf", |val| {
+            Ok(vec![load_plugin(val, &mut Vec::new())?.unwrap()])
+        })
+        .unwrap();
+
+        let get_config = |method: &str, host: &str, uri: &str, headers: &[(&'static str, &str)]| {
+            let mut request = Request::new();
+            request.method = method.to_string();
+            request.host = Some(host.to_string());
+            request.uri = Uri::from(uri.to_string());
+            for (name, value) in headers {
+                request
+                    .headers
+                    .insert(*name, HeaderValue::from_str(value).unwrap());
+            }
+            request.conn_info = Arc::new(ConnInfo {
+                remote_addr: Some("10.1.2.3:56324".parse().unwrap()),
+                ..Default::default()
+            });
+            let (config, _) = config_map.match_request(&request);
+            let conf = config[0].config.as_any().downcast_ref::<Config>().unwrap();
+            (conf.ip.clone(), conf.chunk_size)
+        };
+
+        assert_eq!(
+            get_config("GET", "jequi.com", "/api/users", &[]),
+            ("1.1.2.1".to_string(), 10)
+        );
+        // Match entries keep the options of their path block
+        assert_eq!(
+            get_config("POST", "jequi.com", "/api/users", &[]),
+            ("1.1.2.2".to_string(), 10)
+        );
+        assert_eq!(
+            get_config("GET", "jequi.com", "/api?verbose", &[("x-debug", "true")]).0,
+            "1.1.2.3"
+        );
+        assert_eq!(
+            get_config("GET", "jequi.com", "/api", &[("x-debug", "true")]).0,
+            "1.1.2.1"
+        );
+        assert_eq!(get_config("GET", "jequi.org", "/app", &[]).0, "1.2.1.2");
+        // The route chosen for a request is kept even if a handler rewrites it
+        let mut request = Request::new();
+        request.method = "POST".to_string();
+        request.host = Some("jequi.com".to_string());
+        request.uri = Uri::from("/api/users".to_string());
+        let (_, _, route) = config_map.match_route(&request);
+        assert_eq!(route.host.as_deref(), Some("jequi.com"));
+        assert_eq!(route.match_index, Some(0));
+        request.route = Some(route);
+        request.uri = Uri::from("/app".to_string());
+        let config = config_map.request_config(&request);
+        let conf = config[0].config.as_any().downcast_ref::<Config>().unwrap();
+        assert_eq!(conf.ip, "1.1.2.2");
+        // Without the request only the path is used
+        let config = config_map.get_config_for_request(Some("jequi.org"), Some("/app"));
+        let conf = config[0].config.as_any().downcast_ref::<Config>().unwrap();
+        assert_eq!(conf.ip, "1.2.1.1");
+        assert_eq!(config_map.config_lists().count(), 7);
+
+        let load_invalid = |conf: &str| {
+            ConfigMap::load(conf, |val| {
+                Ok(vec![load_plugin(val, &mut Vec::new())?.unwrap()])
+            })
+            .err()
+            .unwrap()
+        };
+        let err = load_invalid("test/match-invalid.conf");
+        assert!(err.message.starts_with("match entry needs at least one of"));
+        let location = err.location.unwrap();
+        assert_eq!((location.line, location.column), (4, 9));
+
+        let err = load_invalid("test/match-invalid-value.conf");
+        assert_eq!(err.key.as_deref(), Some("chunk_size"));
+        let location = err.location.unwrap();
+        assert_eq!((location.line, location.column), (5, 15));
+    }
+
+    #[test]
+    fn merge_yaml_test() {
+        let merge = |a: &str, b: &str| {
+            let mut a: Value = serde_yaml::from_str(a).unwrap();
+            merge_yaml(&mut a, serde_yaml::from_str(b).unwrap());
+            a
+        };
+        let yaml = |yaml: &str| serde_yaml::from_str::<Value>(yaml).unwrap();
+
+        assert_eq!(
+            merge("{a: 1, b: [1], c: {d: 1}}", "{a: 2, b: [2], c: {e: 2}}"),
+            yaml("{a: 2, b: [1, 2], c: {d: 1, e: 2}}")
+        );
+        assert_eq!(
+            resolve_tags(merge(
+                "{a: 1, b: [1], c: {d: 1}}",
+                "{a: !unset, b: !replace [2]}"
+            )),
+            Ok(yaml("{b: [2], c: {d: 1}}"))
+        );
+        // The tags are kept for the scopes merged later
+        let host = merge("{b: [1]}", "{a: !unset, b: !replace [2]}");
+        let mut path = merge("{a: 1, b: [1]}", "{}");
+        merge_yaml(&mut path, host);
+        merge_yaml(&mut path, yaml("{b: [3]}"));
+        assert_eq!(resolve_tags(path), Ok(yaml("{b: [2, 3]}")));
+
+        assert!(resolve_tags(yaml("{a: !remove 1}")).is_err());
+
+        let mut parent = yaml("{a: 1, b: 2}");
+        merge_scope(&mut parent, yaml("{inherit: false, b: 3}")).unwrap();
+        assert_eq!(parent, yaml("{b: 3}"));
+        let mut parent = yaml("{a: 1}");
+        merge_scope(&mut parent, yaml("{inherit: true, b: 3}")).unwrap();
+        assert_eq!(parent, yaml("{a: 1, b: 3}"));
+        let err = merge_scope(&mut parent, yaml("{inherit: no}")).unwrap_err();
+        assert_eq!(err.key.as_deref(), Some("inherit"));
+    }
+
+    #[test]
+    fn inherit_test() {
+        let config_map = ConfigMap::load("test/inherit.conf", |val| {
+            Ok(vec![load_plugin(val, &mut Vec::new())?.unwrap()])
+        })
+        .unwrap();
+
+        let get_config = |host, path| {
+            config_map.get_config_for_request(host, path)[0]
+                .config
+                .as_any()
+                .downcast_ref::<Config>()
+                .unwrap()
+        };
+        let trusted = |conf: &Config| {
+            conf.proxy_protocol_trusted
+                .iter()
+                .map(|cidr| format!("{:?}", cidr))
+                .collect::<Vec<_>>()
+        };
+        let cidrs = |cidrs: &[&str]| {
+            cidrs
+                .iter()
+                .map(|cidr| format!("{:?}", cidr.parse::<Cidr>().unwrap()))
+                .collect::<Vec<_>>()
+        };
+
+        let jequi = get_config(Some("jequi.com"), None);
+        assert_eq!(jequi.hsts_max_age, None);
+        assert_eq!(trusted(jequi), cidrs(&["192.168.0.0/16"]));
+        let api = get_config(Some("jequi.com"), Some("/api"));
+        assert_eq!(api.hsts_max_age, None);
+        assert_eq!(trusted(api), cidrs(&["192.168.0.0/16", "172.16.0.0/12"]));
+
+        let www = get_config(Some("www.jequi.com"), None);
+        assert_eq!(www.hsts_max_age, Some(3600));
+        assert_eq!(trusted(www), cidrs(&["10.0.0.0/8", "192.168.0.0/16"]));
+
+        let other = get_config(Some("other.com"), None);
+        assert_eq!(other.ip, "127.0.0.1");
+        assert_eq!(other.hsts_max_age, None);
+        assert_eq!(other.chunk_size, 10);
+
+        assert_eq!(get_config(None, Some("/app")).hsts_max_age, Some(60));
+
+        let err = ConfigMap::load("test/inherit-invalid.conf", |val| {
+            Ok(vec![load_plugin(val, &mut Vec::new())?.unwrap()])
+        })
+        .err()
+        .unwrap();
+        assert_eq!(err.key.as_deref(), Some("hsts_max_age"));
+        assert!(err.message.starts_with("unknown tag !remove"));
+        let location = err.location.unwrap();
+        assert_eq!((location.line, location.column), (4, 9));
+    }
+
+    #[test]
+    fn effective_config_test() {
+        let effective = |host, path| {
+            let (scope, config) =
+                ConfigMap::effective_config("test/inherit.conf", host, path).unwrap();
+            (scope, serde_yaml::to_string(&config).unwrap())
+        };
+
+        let (scope, config) = effective(Some("jequi.com:8080"), Some("/api/users"));
+        assert_eq!(
+            scope,
+            ConfigScope::HostPath("jequi.com".to_string(), PathBuf::from("/api"))
+        );
+        assert_eq!(
+            config,
+            "ip: 1.1.1.1\nproxy_protocol_trusted:\n- 192.168.0.0/16\n- 172.16.0.0/12\n"
+        );
+
+        let (scope, config) = effective(Some("other.com"), Some("/app"));
+        assert_eq!(scope, ConfigScope::Host("other.com".to_string()));
+        assert_eq!(config, "chunk_size: 10\n");
+
+        let (scope, config) = effective(Some("unknown.com"), Some("/app"));
+        assert_eq!(scope, ConfigScope::Path(PathBuf::from("/app")));
+        assert_eq!(
+            config,
+            "ip: 1.1.1.1\nhsts_max_age: 60\nproxy_protocol_trusted:\n- 10.0.0.0/8\n"
+        );
+    }
+
+    #[test]
+    fn interpolation_test() {
+        std::env::set_var("JEQUI_TEST_LISTEN_PORT", "8080");
+        std::env::remove_var("JEQUI_TEST_IP");
+        std::env::remove_var("JEQUI_TEST_CHUNK_SIZE");
+        let config_map = ConfigMap::load("test/interpolation.conf", |val| {
+            Ok(vec![load_plugin(val, &mut Vec::new())?.unwrap()])
+        })
+        .unwrap();
+
+        let get_config = |host| {
+            config_map.get_config_for_request(host, None)[0]
+                .config
+                .as_any()
+                .downcast_ref::<Config>()
+                .unwrap()
+        };
+        let config = get_config(None);
+        assert_eq!(config.ip, "127.0.0.1");
+        assert_eq!(config.port, 8080);
+        let config = get_config(Some("jequi.com"));
+        assert_eq!(config.ip, "jequi-secret");
+        assert_eq!(config.chunk_size, 1024);
+
+        std::env::remove_var("JEQUI_TEST_UNSET_PORT");
+        let err = ConfigMapParser::load_config("test/interpolation-unset.conf")
+            .err()
+            .unwrap();
+        assert_eq!(err.key.as_deref(), Some("port"));
+        let location = err.location.unwrap();
+        assert_eq!((location.line, location.column), (4, 9));
+        assert_eq!(
+            err.message,
+            "environment variable JEQUI_TEST_UNSET_PORT is not set and has no default"
+        );
+    }
+
+    #[test]
+    fn unknown_key_test() {
+        let err = ConfigMap::load("test/unknown.conf", |val| {
+            Ok(vec![load_plugin(val, &mut Vec::new())?.unwrap()])
+        })
+        .err()
+        .unwrap();
+
+        assert_eq!(err.key.as_deref(), Some("chunk_sise"));
+        assert_eq!(err.message, "unknown option, did you mean `chunk_size`?");
+        assert_eq!(err.scope, Some(ConfigScope::Host("jequi.com".to_string())));
+        let location = err.location.unwrap();
+        assert_eq!((location.line, location.column), (5, 9));
+    }
+
+    #[test]
+    fn include_test() {
+        let config_map = ConfigMap::load("test/include.conf", |val| {
+            Ok(vec![load_plugin(val, &mut Vec::new())?.unwrap()])
+        })
+        .unwrap();
+
+        let get_config = |host, path| {
+            config_map
+                .get_config_for_request(host, Some(path))
+                .first()
+                .unwrap()
+                .config
+                .as_any()
+                .downcast_ref::<Config>()
+                .unwrap()
+        };
+
+        // Values of the including file replace the included ones
+        assert_eq!(get_config(None, "/").ip, "1.1.1.1");
+        assert_eq!(get_config(None, "/").chunk_size, 10);
+        assert_eq!(get_config(None, "/app").ip, "1.2.1.1");
+        assert_eq!(get_config(Some("jequi.com"), "/").ip, "1.1.2.1");
+        assert_eq!(get_config(Some("jequi.com"), "/").port, 8080);
+        assert_eq!(get_config(Some("jequi.com"), "/api").ip, "1.1.2.3");
+        assert_eq!(get_config(Some("www.jequi.com"), "/").ip, "1.1.3.1");
+        assert_eq!(get_config(Some("www.jequi.com"), "/").chunk_size, 10);
+
+        let mut files = config_map.files.clone();
+        files.sort();
+        assert_eq!(
+            files,
+            [
+                "test/include/defaults.conf",
+                "test/include/hosts/jequi.conf",
+                "test/include/sites.conf",
+                "test/include.conf",
+            ]
+            .map(PathBuf::from)
+        );
+        assert_eq!(
+            config_map.include_globs,
+            [PathBuf::from("test/include/*.conf")]
+        );
+    }
+
+    #[test]
+    fn include_errors_test() {
+        let load = |path| {
+            ConfigMap::load(path, |val| {
+                Ok(vec![load_plugin(val, &mut Vec::new())?.unwrap()])
+            })
+            .err()
+            .unwrap()
+        };
+
+        let err = load("test/include-duplicate.conf");
+        assert_eq!(
+            err.message,
+            "host www.jequi.com is defined more than once, first at test/include/sites.conf:2"
+        );
+        let location = err.location.unwrap();
+        assert_eq!(location.file, Path::new("test/include-duplicate.conf"));
+        assert_eq!((location.line, location.column), (3, 5));
+
+        let err = load("test/include-invalid.conf");
+        assert_eq!(err.key.as_deref(), Some("chunk_size"));
+        let location = err.location.unwrap();
+        assert_eq!(location.file, Path::new("test/include/bad/host.conf"));
+        assert_eq!((location.line, location.column), (3, 9));
+
+        let err = load("test/include-cycle/a.conf");
+        assert_eq!(
+            err.message,
+            "include cycle: test/include-cycle/a.conf -> test/include-cycle/b.conf -> \
+             test/include-cycle/a.conf"
+        );
+        let err = load("test/include-cycle/host.conf");
+        assert_eq!(
+            err.message,
+            "include cycle: test/include-cycle/host.conf -> test/include-cycle/host.conf"
+        );
+    }
 }
@@ -0,0 +1,356 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use yaml_rust2::{
+    parser::{Event, MarkedEventReceiver, Parser},
+    scanner::Marker,
+};
+
+use crate::{ConfigError, ConfigLocation, ConfigScope, ScopeKind};
+
+impl ConfigError {
+    pub fn new<M: ToString>(message: M) -> ConfigError {
+        ConfigError {
+            message: message.to_string(),
+            plugin: None,
+            scope: None,
+            key: None,
+            location: None,
+        }
+    }
+
+    pub fn with_key(mut self, key: &str) -> ConfigError {
+        self.key = Some(key.to_string());
+        self
+    }
+
+    pub fn with_plugin(mut self, plugin: &'static str) -> ConfigError {
+        self.plugin.get_or_insert(plugin);
+        self
+    }
+
+    /// Sets the scope the error happened in and finds where the key is in the file.
+    pub(crate) fn with_scope(mut self, scope: ConfigScope, source_map: &SourceMap) -> ConfigError {
+        if self.location.is_none() {
+            self.location = source_map.locate(&scope, self.key.as_deref()).map(Box::new);
+        }
+        self.scope.get_or_insert(scope);
+        self
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(location) = &self.location {
+            write!(
+                f,
+                "{}:{}:{}: ",
+                location.file.display(),
+                location.line,
+                location.column
+            )?;
+        }
+        if let Some(plugin) = &self.plugin {
+            write!(f, "{}: ", plugin)?;
+        }
+        if let Some(key) = &self.key {
+            write!(f, "`{}`: ", key)?;
+        }
+        write!(f, "{}", self.message)?;
+        if let Some(scope) = &self.scope {
+            write!(f, " (in {} scope)", scope)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl fmt::Display for ConfigScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigScope::Default => write!(f, "default"),
+            ConfigScope::Host(host) => write!(f, "host {}", host),
+            ConfigScope::Path(path) => write!(f, "path {}", path.display()),
+            ConfigScope::HostPath(host, path) => {
+                write!(f, "host {} path {}", host, path.display())
+            }
+        }
+    }
+}
+
+impl fmt::Display for ScopeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScopeKind::Default => write!(f, "default"),
+            ScopeKind::Host => write!(f, "host"),
+            ScopeKind::Path => write!(f, "path"),
+        }
+    }
+}
+
+impl ConfigScope {
+    pub fn kind(&self) -> ScopeKind {
+        match self {
+            ConfigScope::Default => ScopeKind::Default,
+            ConfigScope::Host(_) => ScopeKind::Host,
+            ConfigScope::Path(_) | ConfigScope::HostPath(..) => ScopeKind::Path,
+        }
+    }
+
+    /// Keys leading to the scope in the configuration file.
+    pub fn yaml_path(&self) -> Vec<String> {
+        match self {
+            ConfigScope::Default => vec![],
+            ConfigScope::Host(host) => vec!["host".to_string(), host.clone()],
+            ConfigScope::Path(path) => {
+                vec!["path".to_string(), path.to_string_lossy().to_string()]
+            }
+            ConfigScope::HostPath(host, path) => vec![
+                "host".to_string(),
+                host.clone(),
+                "path".to_string(),
+                path.to_string_lossy().to_string(),
+            ],
+        }
+    }
+
+    /// Scopes the options of this scope are inherited from, including itself, from the most
+    /// specific.
+    fn inherited(&self) -> Vec<ConfigScope> {
+        match self {
+            ConfigScope::Default => vec![ConfigScope::Default],
+            ConfigScope::Host(_) | ConfigScope::Path(_) => {
+                vec![self.clone(), ConfigScope::Default]
+            }
+            ConfigScope::HostPath(host, _) => vec![
+                self.clone(),
+                ConfigScope::Host(host.clone()),
+                ConfigScope::Default,
+            ],
+        }
+    }
+}
+
+/// File and position of every key of the configuration, indexed by the keys leading to it.
+#[derive(Default, Debug)]
+pub(crate) struct SourceMap {
+    keys: HashMap<Vec<String>, (Arc<PathBuf>, Marker)>,
+    files: Vec<PathBuf>,
+    include_globs: Vec<PathBuf>,
+    // Text of the interpolated strings, by the keys leading to them and their value
+    references: HashMap<(Vec<String>, String), String>,
+}
+
+enum Node {
+    Mapping(Option<String>),
+    Sequence(usize),
+}
+
+struct SourceMapBuilder {
+    prefix: Vec<String>,
+    nodes: Vec<Node>,
+    keys: HashMap<Vec<String>, Marker>,
+}
+
+impl SourceMapBuilder {
+    fn current_path(&self) -> Vec<String> {
+        let nodes = self.nodes.iter().map_while(|node| match node {
+            Node::Mapping(key) => key.clone(),
+            Node::Sequence(index) => Some(index.to_string()),
+        });
+        self.prefix.iter().cloned().chain(nodes).collect()
+    }
+
+    // Called after a value of the current node is read
+    fn value_done(&mut self) {
+        match self.nodes.last_mut() {
+            Some(Node::Mapping(key)) => *key = None,
+            Some(Node::Sequence(index)) => *index += 1,
+            None => (),
+        }
+    }
+}
+
+impl MarkedEventReceiver for SourceMapBuilder {
+    fn on_event(&mut self, event: Event, marker: Marker) {
+        match event {
+            Event::Scalar(value, ..) => {
+                if let Some(Node::Mapping(key @ None)) = self.nodes.last_mut() {
+                    *key = Some(value);
+                    let path = self.current_path();
+                    self.keys.entry(path).or_insert(marker);
+                } else {
+                    self.value_done();
+                }
+            }
+            Event::Alias(_) => self.value_done(),
+            Event::MappingStart(..) => self.nodes.push(Node::Mapping(None)),
+            Event::SequenceStart(..) => self.nodes.push(Node::Sequence(0)),
+            Event::MappingEnd | Event::SequenceEnd => {
+                self.nodes.pop();
+                self.value_done();
+            }
+            _ => (),
+        }
+    }
+}
+
+impl SourceMap {
+    /// Finds the keys of `source`, `prefix` are the keys leading to the place the file is
+    /// included in.
+    pub(crate) fn parse(file: &Path, source: &str, prefix: &[String]) -> SourceMap {
+        let mut builder = SourceMapBuilder {
+            prefix: prefix.to_vec(),
+            nodes: Vec::new(),
+            keys: HashMap::new(),
+        };
+        // Syntax errors are reported by serde_yaml, keep the keys found until the error
+        let _ = Parser::new_from_str(source).load(&mut builder, false);
+        let file = Arc::new(file.to_path_buf());
+        SourceMap {
+            keys: builder
+                .keys
+                .into_iter()
+                .map(|(path, marker)| (path, (file.clone(), marker)))
+                .collect(),
+            files: vec![file.to_path_buf()],
+            include_globs: Vec::new(),
+            references: HashMap::new(),
+        }
+    }
+
+    /// Adds the keys of `other`, replacing the ones already set.
+    pub(crate) fn extend(&mut self, other: SourceMap) {
+        self.keys.extend(other.keys);
+        self.files.extend(other.files);
+        self.include_globs.extend(other.include_globs);
+        self.references.extend(other.references);
+    }
+
+    /// Files the keys were read from, including the files without any key.
+    pub(crate) fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    /// Globs with wildcards of the `include` keys, more files can match them later.
+    pub(crate) fn include_globs(&self) -> &[PathBuf] {
+        &self.include_globs
+    }
+
+    pub(crate) fn add_include_globs(&mut self, globs: Vec<PathBuf>) {
+        self.include_globs.extend(globs);
+    }
+
+    /// Records that the string `value` at `path` was written as `reference` before its `${...}`
+    /// references were replaced. Indexes of lists are left out of `path`, since the lists of
+    /// included files are concatenated.
+    pub(crate) fn add_reference(&mut self, path: Vec<String>, value: String, reference: String) {
+        self.references.insert((path, value), reference);
+    }
+
+    /// How the string `value` at `path` was written, if it was interpolated.
+    pub(crate) fn reference(&self, path: Vec<String>, value: String) -> Option<&str> {
+        self.references.get(&(path, value)).map(String::as_str)
+    }
+
+    /// Location of the key at the end of `path`.
+    pub(crate) fn get(&self, path: &[String]) -> Option<ConfigLocation> {
+        self.keys.get(path).map(|(file, marker)| ConfigLocation {
+            file: file.to_path_buf(),
+            line: marker.line(),
+            column: marker.col() + 1,
+        })
+    }
+
+    /// Finds where `key` is set for `scope`, if the key is only in a less specific scope that
+    /// location is used. Without a key the location of the scope is returned.
+    pub(crate) fn locate(&self, scope: &ConfigScope, key: Option<&str>) -> Option<ConfigLocation> {
+        if let Some(key) = key {
+            for scope in scope.inherited() {
+                let mut path = scope.yaml_path();
+                path.push(key.to_string());
+                if let Some(location) = self.get(&path) {
+                    return Some(location);
+                }
+            }
+        }
+        self.get(&scope.yaml_path())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use crate::{ConfigError, ConfigLocation, ConfigScope};
+
+    use super::SourceMap;
+
+    static SOURCE: &str = "tls_active: true
+ip: 1.1.1.1
+host:
+    jequi.com:
+        ip: 1.1.2.1
+        listeners: [http, https]
+        path:
+            /api:
+                chunk_size: 10
+path:
+    /app:
+        ip: 1.2.1.1
+";
+
+    fn location(line: usize, column: usize) -> Option<ConfigLocation> {
+        Some(ConfigLocation {
+            file: PathBuf::from("conf.yaml"),
+            line,
+            column,
+        })
+    }
+
+    #[test]
+    fn source_map_test() {
+        let source_map = SourceMap::parse(Path::new("conf.yaml"), SOURCE, &[]);
+
+        assert_eq!(
+            source_map.locate(&ConfigScope::Default, Some("ip")),
+            location(2, 1)
+        );
+        assert_eq!(
+            source_map.locate(&ConfigScope::Host("jequi.com".to_string()), Some("ip")),
+            location(5, 9)
+        );
+        let api = ConfigScope::HostPath("jequi.com".to_string(), PathBuf::from("/api"));
+        assert_eq!(source_map.locate(&api, Some("chunk_size")), location(9, 17));
+        assert_eq!(source_map.locate(&api, Some("ip")), location(5, 9));
+        assert_eq!(source_map.locate(&api, Some("tls_active")), location(1, 1));
+        assert_eq!(source_map.locate(&api, Some("port")), location(8, 13));
+        assert_eq!(
+            source_map.locate(&ConfigScope::Path(PathBuf::from("/app")), Some("ip")),
+            location(12, 9)
+        );
+    }
+
+    #[test]
+    fn config_error_display_test() {
+        let source_map = SourceMap::parse(Path::new("conf.yaml"), SOURCE, &[]);
+        let err = ConfigError::new("invalid type: string \"abc\", expected usize")
+            .with_key("chunk_size")
+            .with_plugin("jequi")
+            .with_scope(
+                ConfigScope::HostPath("jequi.com".to_string(), PathBuf::from("/api")),
+                &source_map,
+            );
+
+        assert_eq!(
+            err.to_string(),
+            "conf.yaml:9:17: jequi: `chunk_size`: invalid type: string \"abc\", expected usize \
+             (in host jequi.com path /api scope)"
+        );
+    }
+}
@@ -0,0 +1,436 @@
+use std::path::Path;
+
+use serde::{
+    de::{
+        value::{MapDeserializer, SeqDeserializer},
+        IntoDeserializer, Visitor,
+    },
+    forward_to_deserialize_any, Deserializer,
+};
+use serde_yaml::Value;
+
+use crate::{config_error::SourceMap, ConfigError};
+
+enum Reference<'a> {
+    Env(&'a str, Option<&'a str>),
+    File(&'a str),
+}
+
+fn parse_reference(reference: &str) -> Result<Reference, String> {
+    if let Some(path) = reference.strip_prefix("file:") {
+        return Ok(Reference::File(path));
+    }
+
+    let (name, default) = match reference.split_once(":-") {
+        Some((name, default)) => (name, Some(default)),
+        None => (reference, None),
+    };
+    let valid_name = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid_name {
+        return Err(format!("invalid reference ${{{}}}", reference));
+    }
+    Ok(Reference::Env(name, default))
+}
+
+fn resolve(reference: Reference, dir: &Path) -> Result<String, String> {
+    match reference {
+        Reference::Env(name, default) => match (std::env::var(name), default) {
+            (Ok(value), _) => Ok(value),
+            (Err(_), Some(default)) => Ok(default.to_string()),
+            (Err(_), None) => Err(format!(
+                "environment variable {} is not set and has no default",
+                name
+            )),
+        },
+        Reference::File(path) => std::fs::read_to_string(dir.join(path))
+            .map(|content| content.trim_end_matches(['\r', '\n']).to_string())
+            .map_err(|err| format!("can't read {}: {}", path, err)),
+    }
+}
+
+/// Replaces the `${ENV}`, `${ENV:-default}` and `${file:path}` references in `value`, `$${` is
+/// kept as `${`. Relative file paths start from `dir`, the directory of the config file.
+pub fn interpolate_str(value: &str, dir: &Path) -> Result<String, String> {
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('$') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if let Some(escaped) = rest.strip_prefix("$${") {
+            result.push_str("${");
+            rest = escaped;
+            continue;
+        }
+        let Some(reference) = rest.strip_prefix("${") else {
+            result.push('$');
+            rest = &rest[1..];
+            continue;
+        };
+        let end = reference
+            .find('}')
+            .ok_or(format!("unclosed reference in {}", value))?;
+        result.push_str(&resolve(parse_reference(&reference[..end])?, dir)?);
+        rest = &reference[end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+fn interpolate_string(value: &mut Value, dir: &Path) -> Result<(), String> {
+    let Value::String(string) = value else {
+        return Ok(());
+    };
+    // The value stays a string, `LenientValue` reads numbers and booleans from it
+    if string.contains('$') {
+        *string = interpolate_str(string, dir)?;
+    }
+    Ok(())
+}
+
+/// Deserializes a config like `Value` does, but numbers and booleans can also be read from
+/// strings, so `port: ${PORT}` works once the reference is replaced.
+pub(crate) struct LenientValue<'a>(pub &'a Value);
+
+// Number or boolean written in the string, like it would be parsed without the quotes
+fn parse_scalar(value: &Value) -> Option<Value> {
+    let Value::String(string) = value else {
+        return None;
+    };
+    match serde_yaml::from_str(string) {
+        Ok(parsed @ (Value::Bool(_) | Value::Number(_))) => Some(parsed),
+        _ => None,
+    }
+}
+
+macro_rules! deserialize_scalar {
+    ($($method:ident),*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                match parse_scalar(self.0) {
+                    Some(parsed) => parsed.$method(visitor),
+                    None => self.0.$method(visitor),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de> Deserializer<'de> for LenientValue<'de> {
+    type Error = serde_yaml::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.0 {
+            Value::Sequence(sequence) => {
+                visitor.visit_seq(SeqDeserializer::new(sequence.iter().map(LenientValue)))
+            }
+            Value::Mapping(mapping) => visitor.visit_map(MapDeserializer::new(
+                mapping
+                    .iter()
+                    .map(|(key, value)| (LenientValue(key), LenientValue(value))),
+            )),
+            value => value.deserialize_any(visitor),
+        }
+    }
+
+    deserialize_scalar!(
+        deserialize_bool,
+        deserialize_i8,
+        deserialize_i16,
+        deserialize_i32,
+        deserialize_i64,
+        deserialize_u8,
+        deserialize_u16,
+        deserialize_u32,
+        deserialize_u64,
+        deserialize_f32,
+        deserialize_f64
+    );
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.0 {
+            Value::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.0 {
+            Value::Sequence(_) => self.deserialize_any(visitor),
+            value => value.deserialize_seq(visitor),
+        }
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.0 {
+            Value::Mapping(_) => self.deserialize_any(visitor),
+            value => value.deserialize_map(visitor),
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        match self.0 {
+            Value::Mapping(_) => self.deserialize_any(visitor),
+            value => value.deserialize_struct(name, fields, visitor),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        i128 u128 char str string bytes byte_buf unit unit_struct identifier ignored_any
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.0.deserialize_enum(name, variants, visitor)
+    }
+}
+
+impl<'de> IntoDeserializer<'de, serde_yaml::Error> for LenientValue<'de> {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+// Keys leading to a value without the indexes of the lists, see `SourceMap::add_reference`
+fn reference_path(path: &[String]) -> Vec<String> {
+    path.iter()
+        .filter(|key| key.parse::<usize>().is_err())
+        .cloned()
+        .collect()
+}
+
+/// Interpolates every string value of `value`, `path` are the keys leading to it and are used
+/// to locate errors. `dir` is the directory of the config file. The text of the interpolated
+/// strings is kept in `source_map`.
+pub(crate) fn interpolate(
+    value: &mut Value,
+    path: &mut Vec<String>,
+    source_map: &mut SourceMap,
+    dir: &Path,
+) -> Result<(), ConfigError> {
+    let result = match value {
+        Value::Mapping(mapping) => {
+            for (key, value) in mapping.iter_mut() {
+                path.push(key.as_str().unwrap_or_default().to_string());
+                interpolate(value, path, source_map, dir)?;
+                path.pop();
+            }
+            Ok(())
+        }
+        Value::Sequence(sequence) => {
+            for (i, value) in sequence.iter_mut().enumerate() {
+                path.push(i.to_string());
+                interpolate(value, path, source_map, dir)?;
+                path.pop();
+            }
+            Ok(())
+        }
+        Value::Tagged(tagged) => return interpolate(&mut tagged.value, path, source_map, dir),
+        Value::String(string) => {
+            let reference = string.clone();
+            interpolate_string(value, dir).map(|()| {
+                if let Value::String(string) = value
+                    && *string != reference
+                {
+                    source_map.add_reference(reference_path(path), string.clone(), reference);
+                }
+            })
+        }
+        _ => Ok(()),
+    };
+
+    result.map_err(|message| {
+        let key = path.iter().rev().find(|key| key.parse::<usize>().is_err());
+        let mut err = ConfigError::new(message);
+        err.key = key.cloned();
+        err.location = source_map.get(path).map(Box::new);
+        err
+    })
+}
+
+/// Puts back the `${...}` references of the interpolated strings of `value`, so the values
+/// read from the environment or from files aren't shown. `path` are the keys leading to it.
+pub(crate) fn restore_references(
+    value: &mut Value,
+    path: &mut Vec<String>,
+    source_map: &SourceMap,
+) {
+    match value {
+        Value::Mapping(mapping) => {
+            for (key, value) in mapping.iter_mut() {
+                path.push(key.as_str().unwrap_or_default().to_string());
+                restore_references(value, path, source_map);
+                path.pop();
+            }
+        }
+        Value::Sequence(sequence) => {
+            for value in sequence {
+                restore_references(value, path, source_map);
+            }
+        }
+        Value::Tagged(tagged) => restore_references(&mut tagged.value, path, source_map),
+        Value::String(string) => {
+            if let Some(reference) = source_map.reference(reference_path(path), string.clone()) {
+                *string = reference.to_string();
+            }
+        }
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use serde::Deserialize;
+    use serde_yaml::Value;
+
+    use super::{interpolate_str, interpolate_string, LenientValue};
+
+    #[test]
+    fn interpolate_str_test() {
+        let interpolate_str = |value: &str| interpolate_str(value, Path::new("test"));
+        std::env::set_var("JEQUI_TEST_HOST", "jequi.com");
+        std::env::remove_var("JEQUI_TEST_UNSET");
+
+        assert_eq!(
+            interpolate_str("https://${JEQUI_TEST_HOST}/api").unwrap(),
+            "https://jequi.com/api"
+        );
+        assert_eq!(
+            interpolate_str("${JEQUI_TEST_UNSET:-localhost}:${JEQUI_TEST_UNSET:-}").unwrap(),
+            "localhost:"
+        );
+        assert_eq!(
+            interpolate_str("${file:secret.txt}").unwrap(),
+            "jequi-secret"
+        );
+        assert_eq!(
+            interpolate_str(&format!(
+                "${{file:{}/test/secret.txt}}",
+                env!("CARGO_MANIFEST_DIR")
+            ))
+            .unwrap(),
+            "jequi-secret"
+        );
+        assert_eq!(
+            interpolate_str("$${JEQUI_TEST_HOST} $5").unwrap(),
+            "${JEQUI_TEST_HOST} $5"
+        );
+
+        assert_eq!(
+            interpolate_str("${JEQUI_TEST_UNSET}").unwrap_err(),
+            "environment variable JEQUI_TEST_UNSET is not set and has no default"
+        );
+        assert!(interpolate_str("${JEQUI_TEST_HOST").is_err());
+        assert!(interpolate_str("${not valid}").is_err());
+        assert!(interpolate_str("${file:missing.txt}").is_err());
+    }
+
+    #[test]
+    fn interpolate_keeps_string_test() {
+        let interpolate = |value: &str| {
+            let mut value = Value::String(value.to_string());
+            interpolate_string(&mut value, Path::new("test")).unwrap();
+            value
+        };
+        std::env::set_var("JEQUI_TEST_PORT", "8080");
+        std::env::set_var("JEQUI_TEST_TLS", "true");
+
+        assert_eq!(
+            interpolate("${JEQUI_TEST_PORT}"),
+            Value::String("8080".to_string())
+        );
+        assert_eq!(
+            interpolate("${JEQUI_TEST_TLS}"),
+            Value::String("true".to_string())
+        );
+        assert_eq!(
+            interpolate("$${JEQUI_TEST_PORT}"),
+            Value::String("${JEQUI_TEST_PORT}".to_string())
+        );
+        assert_eq!(interpolate("yes"), Value::String("yes".to_string()));
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Listener {
+        port: u16,
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Options {
+        port: u16,
+        tls: bool,
+        max_age: Option<u64>,
+        password: String,
+        user: String,
+        listen: Vec<Listener>,
+    }
+
+    #[test]
+    fn lenient_value_test() {
+        let value: Value = serde_yaml::from_str(
+            "
+port: '8080'
+tls: 'true'
+max_age: '60'
+password: '1234'
+user: 'yes'
+listen: [{port: '443'}]
+",
+        )
+        .unwrap();
+        assert_eq!(
+            Options::deserialize(LenientValue(&value)).unwrap(),
+            Options {
+                port: 8080,
+                tls: true,
+                max_age: Some(60),
+                password: "1234".to_string(),
+                user: "yes".to_string(),
+                listen: vec![Listener { port: 443 }],
+            }
+        );
+
+        let value: Value = serde_yaml::from_str("port: http").unwrap();
+        let err = Listener::deserialize(LenientValue(&value)).unwrap_err();
+        assert!(err.to_string().contains("expected u16"), "{}", err);
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use serde::{
+    de::{self, Visitor},
+    forward_to_deserialize_any, Deserialize, Deserializer,
+};
+
+use crate::{OptionInfo, ScopeKind};
+
+// Keys injected by `ConfigMap::load`, they are never written by users
+pub(crate) const INJECTED_KEYS: &[&str] = &["config_host", "config_path"];
+
+/// Options of the plugins and modules that can be loaded, with the scopes they can be set in.
+#[derive(Default)]
+pub(crate) struct KnownKeys {
+    pub(crate) keys: HashSet<&'static str>,
+    pub(crate) scopes: HashMap<&'static str, &'static [ScopeKind]>,
+}
+
+impl KnownKeys {
+    /// Keys of `options`, as returned by `registry::config_options`, and of the options of the
+    /// modules, which can be set in every scope.
+    pub(crate) fn new(
+        options: &[(&'static str, Vec<OptionInfo>)],
+        module_options: Vec<&'static str>,
+    ) -> KnownKeys {
+        let mut known = KnownKeys::default();
+        for option in options.iter().flat_map(|(_, options)| options) {
+            known.keys.insert(option.key);
+            known.scopes.insert(option.key, option.scopes);
+        }
+        known.keys.extend(module_options);
+        known
+    }
+}
+
+#[derive(Debug)]
+struct FieldsFound(&'static [&'static str]);
+
+impl fmt::Display for FieldsFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "config is not a struct")
+    }
+}
+
+impl std::error::Error for FieldsFound {}
+
+impl de::Error for FieldsFound {
+    fn custom<T: fmt::Display>(_msg: T) -> Self {
+        FieldsFound(&[])
+    }
+}
+
+struct FieldsDeserializer;
+
+impl<'de> Deserializer<'de> for FieldsDeserializer {
+    type Error = FieldsFound;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+        Err(FieldsFound(&[]))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        Err(FieldsFound(fields))
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map enum identifier ignored_any
+    }
+}
+
+/// Keys accepted by a config struct, taken from its `Deserialize` implementation.
+pub fn fields<'de, T: Deserialize<'de>>() -> &'static [&'static str] {
+    match T::deserialize(FieldsDeserializer) {
+        Ok(_) => &[],
+        Err(FieldsFound(fields)) => fields,
+    }
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Most similar known key to `key`, if any of them is close enough to be a typo.
+pub(crate) fn closest<'a>(key: &str, known: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    known
+        .map(|candidate| (edit_distance(key, candidate), candidate))
+        .filter(|(distance, _)| *distance <= (key.len() / 3).max(1))
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Config;
+
+    use super::{closest, fields};
+
+    #[test]
+    fn fields_test() {
+        let fields = fields::<Config>();
+        assert!(fields.contains(&"ip"));
+        assert!(fields.contains(&"ssl_certificate"));
+        // Skipped fields can't be set in the config
+        assert!(!fields.contains(&"ssl_identity"));
+    }
+
+    #[test]
+    fn closest_test() {
+        let known = ["proxy_address", "proxy_protocol", "static_files_path", "ip"];
+        assert_eq!(
+            closest("proxy_adress", known.into_iter()),
+            Some("proxy_address")
+        );
+        assert_eq!(
+            closest("static_file_path", known.into_iter()),
+            Some("static_files_path")
+        );
+        assert_eq!(closest("id", known.into_iter()), Some("ip"));
+        assert_eq!(closest("something_else", known.into_iter()), None);
+    }
+}
@@ -0,0 +1,265 @@
+use std::path::PathBuf;
+
+use serde_json::{json, Map, Value};
+
+use crate::{OptionInfo, ScopeKind};
+
+/// Type of a config option, describes it in the configuration reference and the JSON Schema.
+pub trait ConfigType {
+    /// Name of the type in the configuration reference.
+    fn type_name() -> String;
+    /// JSON Schema of the values accepted.
+    fn schema() -> Value;
+    /// The value as JSON, used to show the default of an option.
+    fn to_json(&self) -> Value;
+}
+
+/// Implements `ConfigType`, either for types serialized as they are shown:
+///
+/// ```ignore
+/// config_type!("int", "integer", u8, u16);
+/// ```
+///
+/// or for a type with its name, its JSON Schema (as written in `json!`) and how a value is
+/// shown:
+///
+/// ```ignore
+/// config_type!(Cidr, "string", {"type": "string"}, |cidr| cidr.to_string());
+/// ```
+#[macro_export]
+macro_rules! config_type {
+    ($name:literal, $schema_type:literal, $($ty:ty),*) => {$(
+        $crate::config_type!($ty, $name, {"type": $schema_type}, |value| value);
+    )*};
+    ($ty:ty, $name:literal, $schema:tt, |$value:ident| $to_json:expr) => {
+        impl $crate::config_schema::ConfigType for $ty {
+            fn type_name() -> std::string::String {
+                $name.to_string()
+            }
+
+            fn schema() -> $crate::serde_json::Value {
+                $crate::serde_json::json!($schema)
+            }
+
+            fn to_json(&self) -> $crate::serde_json::Value {
+                let $value = self;
+                $crate::serde_json::json!($to_json)
+            }
+        }
+    };
+}
+
+config_type!("bool", "boolean", bool);
+config_type!("int", "integer", u8, u16, u32, u64, usize);
+config_type!("string", "string", String);
+config_type!(PathBuf, "string", {"type": "string"}, |path| path.to_string_lossy());
+
+impl<T: ConfigType> ConfigType for Option<T> {
+    fn type_name() -> String {
+        T::type_name()
+    }
+
+    fn schema() -> Value {
+        T::schema()
+    }
+
+    fn to_json(&self) -> Value {
+        self.as_ref().map_or(Value::Null, T::to_json)
+    }
+}
+
+impl<T: ConfigType> ConfigType for Vec<T> {
+    fn type_name() -> String {
+        format!("list of {}s", T::type_name())
+    }
+
+    fn schema() -> Value {
+        json!({"type": "array", "items": T::schema()})
+    }
+
+    fn to_json(&self) -> Value {
+        self.iter().map(T::to_json).collect()
+    }
+}
+
+/// Schema of a value that can be a string or a list of strings.
+pub fn string_or_list_schema() -> Value {
+    json!({"oneOf": [{"type": "string"}, {"type": "array", "items": {"type": "string"}}]})
+}
+
+// Defaults that are the same as the option not being set aren't shown
+fn default_text(default: &Value) -> Option<String> {
+    match default {
+        Value::Null | Value::Bool(false) => None,
+        Value::String(string) if string.is_empty() => None,
+        Value::Array(array) if array.is_empty() => None,
+        Value::String(string) => Some(string.clone()),
+        default => Some(default.to_string()),
+    }
+}
+
+fn option_schema(option: &OptionInfo) -> Value {
+    let mut schema = option.schema.clone();
+    if let Value::Object(schema) = &mut schema {
+        schema.insert("description".to_string(), option.description.into());
+        if default_text(&option.default).is_some() {
+            schema.insert("default".to_string(), option.default.clone());
+        }
+    }
+    schema
+}
+
+/// Schema of an object that accepts `options`.
+pub fn object_schema(options: &[OptionInfo]) -> Value {
+    let properties: Map<String, Value> = options
+        .iter()
+        .map(|option| (option.key.to_string(), option_schema(option)))
+        .collect();
+    json!({"type": "object", "properties": properties})
+}
+
+fn scope_properties(plugins: &[(&str, Vec<OptionInfo>)], kind: ScopeKind) -> Map<String, Value> {
+    plugins
+        .iter()
+        .flat_map(|(_, options)| options)
+        .filter(|option| option.scopes.contains(&kind))
+        .map(|option| (option.key.to_string(), option_schema(option)))
+        .collect()
+}
+
+fn object(properties: Map<String, Value>) -> Value {
+    json!({"type": "object", "properties": properties})
+}
+
+/// JSON Schema of the configuration file, `plugins` has the options of every plugin, as
+/// returned by `registry::config_options`.
+pub fn json_schema(plugins: &[(&str, Vec<OptionInfo>)]) -> Value {
+    let include = json!({
+        "description": "Files merged into this scope, relative to the including file",
+        "oneOf": [{"type": "string"}, {"type": "array", "items": {"type": "string"}}],
+    });
+    let inherit = json!({
+        "description": "Whether the options of the parent scope are used",
+        "type": "boolean",
+    });
+
+    let mut match_entry = scope_properties(plugins, ScopeKind::Path);
+    match_entry.insert("method".to_string(), string_or_list_schema());
+    match_entry.insert(
+        "header".to_string(),
+        json!({"type": "object", "additionalProperties": {"type": "string"}}),
+    );
+    match_entry.insert("query".to_string(), string_or_list_schema());
+    match_entry.insert("client".to_string(), string_or_list_schema());
+
+    let mut path = scope_properties(plugins, ScopeKind::Path);
+    path.insert(
+        "match".to_string(),
+        json!({"type": "array", "items": object(match_entry)}),
+    );
+    path.insert("inherit".to_string(), inherit.clone());
+    let paths = json!({"type": "object", "additionalProperties": object(path)});
+
+    let mut host = scope_properties(plugins, ScopeKind::Host);
+    host.insert("path".to_string(), paths.clone());
+    host.insert("include".to_string(), include.clone());
+    host.insert("inherit".to_string(), inherit);
+
+    let mut default = scope_properties(plugins, ScopeKind::Default);
+    default.insert(
+        "host".to_string(),
+        json!({"type": "object", "additionalProperties": object(host)}),
+    );
+    default.insert("path".to_string(), paths);
+    default.insert("include".to_string(), include);
+
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "Jequi configuration",
+        "type": "object",
+        "properties": default,
+    })
+}
+
+/// Markdown reference of every option, it is the `Configuration Options` section of the README.
+pub fn reference(plugins: &[(&str, Vec<OptionInfo>)]) -> String {
+    let mut reference = String::new();
+    for (plugin, options) in plugins {
+        for option in options {
+            reference.push_str(&format!("## {}\n", option.key));
+            if *plugin != "jequi" {
+                reference.push_str(&format!("**From {} plugin**\n\n", plugin));
+            }
+            let scopes: Vec<String> = option.scopes.iter().map(ToString::to_string).collect();
+            reference.push_str(&format!("**scope:** {}\n\n", scopes.join(", ")));
+            reference.push_str(&format!("**type:** {}\n\n", option.type_name));
+            if let Some(default) = default_text(&option.default) {
+                reference.push_str(&format!("**default:** `{}`\n\n", default));
+            }
+            reference.push_str(option.description);
+            reference.push_str("\n\n");
+        }
+    }
+    reference
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::{Config, ConfigOptions, ModuleConfig, ScopeKind};
+
+    use super::{json_schema, reference};
+
+    #[test]
+    fn json_schema_test() {
+        let plugins = [
+            ("jequi", Config::options()),
+            ("jequi_module", ModuleConfig::options()),
+        ];
+        let schema = json_schema(&plugins);
+        let properties = &schema["properties"];
+
+        assert_eq!(properties["port"]["type"], "integer");
+        assert_eq!(properties["port"]["default"], 7878);
+        assert_eq!(
+            properties["listen"]["items"]["properties"]["tls"]["type"],
+            "boolean"
+        );
+        assert_eq!(
+            properties["proxy_protocol_trusted"],
+            json!({
+                "type": "array",
+                "items": {"type": "string"},
+                "description": Config::options()
+                    .iter()
+                    .find(|option| option.key == "proxy_protocol_trusted")
+                    .unwrap()
+                    .description,
+            })
+        );
+
+        // Options are only accepted in their scopes
+        let host = &properties["host"]["additionalProperties"]["properties"];
+        assert!(host["listeners"].is_object());
+        assert!(properties["load_module"].is_object());
+        assert!(host["load_module"].is_null());
+        let path = &properties["path"]["additionalProperties"]["properties"];
+        assert!(path["chunk_size"].is_object());
+        assert!(path["match"]["items"]["properties"]["method"].is_object());
+    }
+
+    #[test]
+    fn reference_test() {
+        let options = ModuleConfig::options();
+        assert_eq!(options[0].scopes, [ScopeKind::Default]);
+
+        let reference = reference(&[("jequi", Config::options()), ("jequi_module", options)]);
+        assert!(reference.contains(
+            "## port\n**scope:** default, host, path\n\n**type:** int\n\n**default:** `7878`\n\n"
+        ));
+        assert!(reference.contains(
+            "## load_module\n**From jequi_module plugin**\n\n**scope:** default\n\n**type:** list of strings\n\n"
+        ));
+    }
+}
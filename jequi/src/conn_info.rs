@@ -0,0 +1,35 @@
+use std::net::SocketAddr;
+
+use openssl::ssl::{NameType, SslRef};
+
+use crate::ConnInfo;
+
+impl ConnInfo {
+    pub fn new(remote_addr: Option<SocketAddr>, local_addr: Option<SocketAddr>) -> ConnInfo {
+        ConnInfo {
+            remote_addr,
+            local_addr,
+            ..Default::default()
+        }
+    }
+
+    pub fn scheme(&self) -> &'static str {
+        match self.tls {
+            true => "https",
+            false => "http",
+        }
+    }
+
+    /// Fills the TLS information after the handshake finished.
+    pub fn set_tls(&mut self, ssl: &SslRef) {
+        self.tls = true;
+        self.tls_version = Some(ssl.version_str().to_string());
+        self.tls_cipher = ssl.current_cipher().map(|cipher| cipher.name().to_string());
+        self.sni = ssl
+            .servername(NameType::HOST_NAME)
+            .map(|name| name.to_string());
+        self.alpn = ssl
+            .selected_alpn_protocol()
+            .map(|protocol| String::from_utf8_lossy(protocol).to_string());
+    }
+}
@@ -0,0 +1,306 @@
+use std::{collections::HashMap, fmt, path::Path};
+
+use serde_yaml::{Mapping, Value};
+
+use crate::{
+    config::{merge_scope, resolve_tags, take_matches},
+    config_error::SourceMap,
+    config_interpolation::restore_references,
+    ConfigError, ConfigMap, ConfigMapParser, ConfigScope, ConfigSources, OptionOrigin, Phase,
+    RouteExplanation,
+};
+
+// Whether `value` is merged with `current` instead of replacing it, see `merge_yaml`
+fn merged_with(current: Option<&Value>, value: &Value) -> bool {
+    if let Value::Tagged(_) = value {
+        return false;
+    }
+    let current = match current {
+        Some(Value::Tagged(tagged)) => &tagged.value,
+        Some(current) => current,
+        None => return false,
+    };
+    matches!(
+        (current, value),
+        (Value::Mapping(_), Value::Mapping(_)) | (Value::Sequence(_), Value::Sequence(_))
+    )
+}
+
+impl ConfigSources {
+    pub(crate) fn new(parser: ConfigMapParser, source_map: SourceMap) -> ConfigSources {
+        ConfigSources { parser, source_map }
+    }
+
+    /// Merges the options of the default scope, the `host` block and the `path` block inside
+    /// it (or at the top level without a host), the plugins of the explanation are left empty.
+    /// Interpolated values are shown with their `${...}` references, not with the secrets they
+    /// can be replaced by.
+    pub(crate) fn merge(
+        &self,
+        host: Option<&str>,
+        path: Option<&Path>,
+    ) -> Result<RouteExplanation, ConfigError> {
+        let mut scope = ConfigScope::Default;
+        let mut layers = vec![(scope.clone(), self.parser.config.clone())];
+        let mut paths = self.parser.path.as_ref();
+        if let Some(host) = host
+            && let Some(host_parser) = self.parser.host.as_ref().and_then(|hosts| hosts.get(host))
+        {
+            scope = ConfigScope::Host(host.to_string());
+            layers.push((scope.clone(), host_parser.config.clone()));
+            paths = host_parser.path.as_ref();
+        }
+        let mut matches = 0;
+        if let Some(path) = path
+            && let Some(path_config) = paths.and_then(|paths| paths.get(path))
+        {
+            let mut path_config = path_config.clone();
+            matches = take_matches(&mut path_config)
+                .and_then(|matches| matches.as_sequence().map(Vec::len))
+                .unwrap_or_default();
+            scope = match scope {
+                ConfigScope::Host(host) => ConfigScope::HostPath(host, path.to_path_buf()),
+                _ => ConfigScope::Path(path.to_path_buf()),
+            };
+            layers.push((scope.clone(), path_config));
+        }
+
+        let mut config = Value::Mapping(Mapping::new());
+        let mut origins: HashMap<String, Vec<OptionOrigin>> = HashMap::new();
+        for (layer_scope, mut layer) in layers {
+            restore_references(&mut layer, &mut layer_scope.yaml_path(), &self.source_map);
+            let inherit = layer.get("inherit") != Some(&Value::Bool(false));
+            if !inherit {
+                origins.clear();
+            }
+            for (key, value) in layer.as_mapping().into_iter().flatten() {
+                let Some(key) = key.as_str().filter(|key| *key != "inherit") else {
+                    continue;
+                };
+                let origin = OptionOrigin {
+                    scope: layer_scope.clone(),
+                    location: self
+                        .source_map
+                        .get(&[layer_scope.yaml_path(), vec![key.to_string()]].concat()),
+                };
+                let merged = inherit && merged_with(config.get(key), value);
+                let key_origins = origins.entry(key.to_string()).or_default();
+                if !merged {
+                    key_origins.clear();
+                }
+                key_origins.push(origin);
+            }
+            merge_scope(&mut config, layer)
+                .map_err(|err| err.with_scope(layer_scope.clone(), &self.source_map))?;
+        }
+
+        let config = resolve_tags(config)
+            .map_err(|err| ConfigError::new(err).with_scope(scope.clone(), &self.source_map))?;
+        origins.retain(|key, _| config.get(key).is_some());
+        Ok(RouteExplanation {
+            scope,
+            config,
+            origins,
+            plugins: Vec::new(),
+            matches,
+        })
+    }
+}
+
+impl ConfigMap {
+    /// Explains which config is used for requests to `host` and `path` received on `listener`,
+    /// like `get_config_for_listener` chooses it. The `match` entries of the path block aren't
+    /// checked since they depend on the request.
+    pub fn explain_route(
+        &self,
+        listener: Option<&str>,
+        host: Option<&str>,
+        path: Option<&str>,
+    ) -> Result<RouteExplanation, ConfigError> {
+        let Some(sources) = &self.sources else {
+            return Err(ConfigError::new(
+                "the configuration wasn't loaded from a file",
+            ));
+        };
+        let route = self.select(listener, host, path);
+        let mut explanation = sources.merge(route.host, route.path)?;
+        explanation.plugins = route
+            .config
+            .iter()
+            .map(|plugin| (plugin.name, plugin.handlers.phases()))
+            .collect();
+        Ok(explanation)
+    }
+}
+
+impl fmt::Display for OptionOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.scope)?;
+        if let Some(location) = &self.location {
+            write!(
+                f,
+                " ({}:{}:{})",
+                location.file.display(),
+                location.line,
+                location.column
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The merged config as YAML, with comments for the scope, the plugins and where each option
+/// is set.
+impl fmt::Display for RouteExplanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "# scope: {}", self.scope)?;
+        for phase in Phase::ALL {
+            let names: Vec<_> = self
+                .plugins
+                .iter()
+                .filter(|(_, phases)| phases.contains(&phase))
+                .map(|(name, _)| *name)
+                .collect();
+            if !names.is_empty() {
+                writeln!(f, "# {} handlers: {}", phase, names.join(", "))?;
+            }
+        }
+        let without_handlers: Vec<_> = self
+            .plugins
+            .iter()
+            .filter(|(_, phases)| phases.is_empty())
+            .map(|(name, _)| *name)
+            .collect();
+        if !without_handlers.is_empty() {
+            writeln!(
+                f,
+                "# plugins without handlers: {}",
+                without_handlers.join(", ")
+            )?;
+        }
+        if self.matches > 0 {
+            writeln!(
+                f,
+                "# {} match entries can replace these options depending on the request",
+                self.matches
+            )?;
+        }
+
+        for (key, value) in self.config.as_mapping().into_iter().flatten() {
+            let Some(key) = key.as_str() else {
+                continue;
+            };
+            let origins: Vec<String> = self
+                .origins
+                .get(key)
+                .into_iter()
+                .flatten()
+                .map(ToString::to_string)
+                .collect();
+            writeln!(f, "# {}", origins.join(", "))?;
+            let option = Mapping::from_iter([(key.into(), value.clone())]);
+            write!(
+                f,
+                "{}",
+                serde_yaml::to_string(&option).map_err(|_| fmt::Error)?
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{load_plugin, ConfigLocation, ConfigMap, ConfigScope, OptionOrigin};
+
+    #[test]
+    fn explain_route_test() {
+        let config_map = ConfigMap::load("test/inherit.conf", |val| {
+            Ok(vec![load_plugin(val, &mut Vec::new())?.unwrap()])
+        })
+        .unwrap();
+        let origin = |scope: ConfigScope, line, column| OptionOrigin {
+            scope,
+            location: Some(ConfigLocation {
+                file: PathBuf::from("test/inherit.conf"),
+                line,
+                column,
+            }),
+        };
+        let host = || ConfigScope::Host("jequi.com".to_string());
+        let api = ConfigScope::HostPath("jequi.com".to_string(), PathBuf::from("/api"));
+
+        let explanation = config_map
+            .explain_route(None, Some("jequi.com:8080"), Some("/api/users"))
+            .unwrap();
+        assert_eq!(explanation.scope, api);
+        assert_eq!(explanation.plugins, [("jequi", Vec::new())]);
+        assert_eq!(
+            explanation.origins["ip"],
+            [origin(ConfigScope::Default, 1, 1)]
+        );
+        // `!replace` in the host discards the list of the default scope
+        assert_eq!(
+            explanation.origins["proxy_protocol_trusted"],
+            [origin(host(), 7, 9), origin(api.clone(), 10, 17)]
+        );
+        assert!(!explanation.origins.contains_key("hsts_max_age"));
+        assert_eq!(
+            explanation.to_string(),
+            "# scope: host jequi.com path /api\n\
+             # plugins without handlers: jequi\n\
+             # default (test/inherit.conf:1:1)\n\
+             ip: 1.1.1.1\n\
+             # host jequi.com (test/inherit.conf:7:9), host jequi.com path /api (test/inherit.conf:10:17)\n\
+             proxy_protocol_trusted:\n\
+             - 192.168.0.0/16\n\
+             - 172.16.0.0/12\n"
+        );
+
+        let explanation = config_map
+            .explain_route(None, Some("other.com"), Some("/app"))
+            .unwrap();
+        assert_eq!(
+            explanation.scope,
+            ConfigScope::Host("other.com".to_string())
+        );
+        assert_eq!(explanation.origins.len(), 1);
+        assert_eq!(
+            explanation.origins["chunk_size"],
+            [origin(ConfigScope::Host("other.com".to_string()), 15, 9)]
+        );
+
+        let explanation = config_map.explain_route(None, None, Some("/app")).unwrap();
+        assert_eq!(explanation.scope, ConfigScope::Path(PathBuf::from("/app")));
+        assert_eq!(
+            explanation.origins["hsts_max_age"],
+            [origin(ConfigScope::Path(PathBuf::from("/app")), 18, 9)]
+        );
+    }
+
+    #[test]
+    fn explain_route_hides_interpolated_test() {
+        std::env::set_var("JEQUI_TEST_LISTEN_PORT", "8080");
+        std::env::remove_var("JEQUI_TEST_IP");
+        std::env::remove_var("JEQUI_TEST_CHUNK_SIZE");
+        let config_map = ConfigMap::load("test/interpolation.conf", |val| {
+            Ok(vec![load_plugin(val, &mut Vec::new())?.unwrap()])
+        })
+        .unwrap();
+
+        let explanation = config_map
+            .explain_route(None, Some("jequi.com"), Some("/"))
+            .unwrap()
+            .to_string();
+        assert!(
+            explanation.contains("\nip: ${file:secret.txt}\n"),
+            "{}",
+            explanation
+        );
+        assert!(explanation.contains("\nport: ${JEQUI_TEST_LISTEN_PORT}\n"));
+        assert!(!explanation.contains("jequi-secret"));
+        assert!(!explanation.contains("8080"));
+    }
+}
@@ -0,0 +1,181 @@
+use std::mem;
+
+use crate::{BodyFilters, ConfigMap, Request, Response};
+
+impl BodyFilters {
+    /// Passes `chunk` through every filter and returns the bytes to send.
+    pub fn filter(&mut self, chunk: Vec<u8>, last: bool) -> Vec<u8> {
+        self.0
+            .iter_mut()
+            .fold(chunk, |chunk, filter| filter.filter_chunk(chunk, last))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl Request {
+    /// Runs the header filters of the plugins used for the request and starts their body
+    /// filters, called between `handle_request` and writing the response.
+    pub fn filter_response(&self, response: &mut Response, config_map: &ConfigMap) -> BodyFilters {
+        let config = config_map.request_config(self);
+        for filter in config
+            .iter()
+            .filter_map(|plugin| plugin.handlers.header_filter.as_ref())
+        {
+            filter.filter_headers(self, response);
+        }
+        BodyFilters(
+            config
+                .iter()
+                .filter_map(|plugin| plugin.handlers.body_filter.as_ref())
+                .filter_map(|filter| filter.filter_body(self, response))
+                .collect(),
+        )
+    }
+}
+
+impl Response {
+    /// Filters the whole `body_buffer` as a single chunk.
+    pub fn filter_body(&mut self, filters: &mut BodyFilters) {
+        if filters.is_empty() {
+            return;
+        }
+        self.body_buffer = filters.filter(mem::take(&mut self.body_buffer), true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        BodyChunkFilter, BodyFilter, Config, ConfigMap, HeaderFilter, PhaseHandlers, Plugin,
+        Request, Response,
+    };
+
+    struct Upper;
+
+    impl HeaderFilter for Upper {
+        fn filter_headers(&self, _request: &Request, response: &mut Response) {
+            response.set_header("x-filters", "upper");
+        }
+    }
+
+    impl BodyFilter for Upper {
+        fn filter_body(
+            &self,
+            _request: &Request,
+            _response: &Response,
+        ) -> Option<Box<dyn BodyChunkFilter>> {
+            Some(Box::new(Upper))
+        }
+    }
+
+    impl BodyChunkFilter for Upper {
+        fn filter_chunk(&mut self, chunk: Vec<u8>, _last: bool) -> Vec<u8> {
+            chunk.to_ascii_uppercase()
+        }
+    }
+
+    // Counts the chunks and writes the count at the end of the body, only when the previous
+    // header filter ran
+    struct Count(usize);
+
+    impl HeaderFilter for Count {
+        fn filter_headers(&self, _request: &Request, response: &mut Response) {
+            let filters = response.get_header("x-filters").cloned();
+            if let Some(filters) = filters {
+                response.set_header(
+                    "x-filters",
+                    &format!("{}, count", filters.to_str().unwrap()),
+                );
+            }
+        }
+    }
+
+    impl BodyFilter for Count {
+        fn filter_body(
+            &self,
+            _request: &Request,
+            response: &Response,
+        ) -> Option<Box<dyn BodyChunkFilter>> {
+            response
+                .headers
+                .contains_key("x-filters")
+                .then(|| Box::new(Count(0)) as Box<dyn BodyChunkFilter>)
+        }
+    }
+
+    impl BodyChunkFilter for Count {
+        fn filter_chunk(&mut self, mut chunk: Vec<u8>, last: bool) -> Vec<u8> {
+            self.0 += 1;
+            if last {
+                chunk.extend_from_slice(format!(" ({} chunks)", self.0).as_bytes());
+            }
+            chunk
+        }
+    }
+
+    fn config_map(with_upper: bool) -> ConfigMap {
+        let upper = Arc::new(Upper);
+        let count = Arc::new(Count(0));
+        let mut config = vec![Plugin {
+            name: "jequi",
+            config: Arc::new(Config::default()),
+            handlers: PhaseHandlers::default(),
+        }];
+        if with_upper {
+            config.push(Plugin {
+                name: "upper",
+                config: Arc::new(Config::default()),
+                handlers: PhaseHandlers {
+                    header_filter: Some(upper.clone()),
+                    body_filter: Some(upper),
+                    ..Default::default()
+                },
+            });
+        }
+        config.push(Plugin {
+            name: "count",
+            config: Arc::new(Config::default()),
+            handlers: PhaseHandlers {
+                header_filter: Some(count.clone()),
+                body_filter: Some(count),
+                ..Default::default()
+            },
+        });
+        ConfigMap {
+            config,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn filter_response_test() {
+        let request = Request::new();
+
+        // Filters run in the order of the plugins, each one on the output of the previous
+        let mut response = Response::new();
+        response.write_body(b"hello").unwrap();
+        let mut filters = request.filter_response(&mut response, &config_map(true));
+        assert_eq!(response.get_header("x-filters").unwrap(), "upper, count");
+        response.filter_body(&mut filters);
+        assert_eq!(response.body_buffer, b"HELLO (1 chunks)");
+
+        // Streamed bodies are filtered chunk by chunk with the same filters
+        let mut filters = request.filter_response(&mut Response::new(), &config_map(true));
+        assert_eq!(filters.filter(b"hello ".to_vec(), false), b"HELLO ");
+        assert_eq!(filters.filter(b"world".to_vec(), false), b"WORLD");
+        assert_eq!(filters.filter(Vec::new(), true), b" (3 chunks)");
+
+        // A body filter can choose not to filter a response
+        let mut response = Response::new();
+        response.write_body(b"hello").unwrap();
+        let mut filters = request.filter_response(&mut response, &config_map(false));
+        assert!(filters.is_empty());
+        response.filter_body(&mut filters);
+        assert_eq!(response.body_buffer, b"hello");
+    }
+}
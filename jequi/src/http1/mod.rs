@@ -8,14 +8,14 @@ use std::{
 use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, BufStream};
 
 use crate::hijack::DynAsyncRWSend;
-use crate::{AsyncRWSend, AsyncRWSendBuf, ConfigMap, PostRequestHandler, Request, Response};
+use crate::{
+    AsyncRWSend, AsyncRWSendBuf, ConfigMap, ConnInfo, PostRequestHandler, Request, Response,
+};
 
 use plugins::get_plugin;
 
 use crate::Config;
 
-use crate as jequi;
-
 pub trait ReadUntilHandleEof {
     async fn read_until_handle_eof(&mut self, byte: u8, buf: &mut Vec<u8>) -> Result<()>;
 }
@@ -35,6 +35,7 @@ pub struct Http1Conn<T: AsyncRWSendBuf> {
     pub version: String,
     pub request: Request,
     pub response: Response,
+    pub conn_info: Arc<ConnInfo>,
 }
 
 impl<T: AsyncRWSend> Http1Conn<BufStream<T>> {
@@ -44,6 +45,7 @@ impl<T: AsyncRWSend> Http1Conn<BufStream<T>> {
             version: String::new(),
             request: Request::new(),
             response: Response::new(),
+            conn_info: Arc::new(ConnInfo::default()),
         }
     }
 }
@@ -55,9 +57,16 @@ impl<T: AsyncRWSendBuf> Http1Conn<T> {
             version: self.version,
             request: self.request,
             response: self.response,
+            conn_info: self.conn_info,
         }
     }
 
+    pub fn with_conn_info(mut self, conn_info: Arc<ConnInfo>) -> Self {
+        self.request.conn_info = conn_info.clone();
+        self.conn_info = conn_info;
+        self
+    }
+
     pub fn into_conn(self) -> T {
         self.conn
     }
@@ -76,6 +85,7 @@ impl<T: AsyncRWSendBuf> Http1Conn<T> {
         {
             loop {
                 self.request = Request::new();
+                self.request.conn_info = self.conn_info.clone();
                 self.response = Response::new();
                 self.handle_request(conf, config_map.clone()).await;
             }
@@ -96,6 +106,7 @@ impl<T: AsyncRWSendBuf> Http1Conn<T> {
 
         let request = &mut self.request;
         let mut post_handler = PostRequestHandler::Continue;
+        let handler_config_map = config_map.clone();
         tokio_scoped::scope(|scope| {
             scope.spawn(async move {
                 match read_body.await {
@@ -106,11 +117,18 @@ impl<T: AsyncRWSendBuf> Http1Conn<T> {
             });
 
             scope.spawn(async {
-                post_handler = request.handle_request(&mut self.response, config_map).await;
+                post_handler = request
+                    .handle_request(&mut self.response, handler_config_map)
+                    .await;
             });
         });
 
+        let mut body_filters = self
+            .request
+            .filter_response(&mut self.response, &config_map);
+        self.response.filter_body(&mut body_filters);
         self.write_response(conf.chunk_size).await.unwrap();
+        self.request.log(&mut self.response, &config_map).await;
 
         post_handler
     }
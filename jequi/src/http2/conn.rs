@@ -10,11 +10,9 @@ use tokio::{
 
 use crate::{
     http2::{BufStreamRaw, FrameType, END_HEADERS_FLAG, PREFACE},
-    AsyncRWSend, AsyncRWSendBuf, ConfigMap,
+    AsyncRWSend, AsyncRWSendBuf, ConfigMap, ConnInfo,
 };
 
-use crate as jequi;
-
 use super::{Http2Conn, Http2Frame, Settings, END_STREAM_FLAG};
 
 impl<T: AsyncRWSend> Http2Conn<BufStream<T>> {
@@ -23,11 +21,21 @@ impl<T: AsyncRWSend> Http2Conn<BufStream<T>> {
             conn: BufStream::new(stream),
             settings: Settings::default(),
             streams: HashMap::new(),
+            conn_info: Arc::new(ConnInfo::default()),
         }
     }
 }
 
 impl<T: AsyncRWSendBuf> Http2Conn<T> {
+    pub fn with_conn_info(mut self, conn_info: Arc<ConnInfo>) -> Self {
+        self.conn_info = conn_info;
+        self
+    }
+
+    pub fn conn_info(&self) -> Arc<ConnInfo> {
+        self.conn_info.clone()
+    }
+
     async fn write_response(
         &mut self,
         stream_id: Option<u32>,
@@ -38,7 +46,17 @@ impl<T: AsyncRWSendBuf> Http2Conn<T> {
         println!("response: {}", stream_id);
         let stream = self.streams.remove(&stream_id).unwrap();
         let (_, request, response) = Arc::into_inner(stream).unwrap().consume();
-        let response = Arc::into_inner(response).unwrap();
+        let mut request =
+            Arc::into_inner(request).expect("request still used after its stream was handled");
+        let mut response =
+            Arc::into_inner(response).expect("response still used after its stream was handled");
+        let mut body_filters = request.filter_response(&mut response, &config_map);
+        response.filter_body(&mut body_filters);
+        // The length set by the handler, or copied from upstream, is the one before the filters
+        response.set_header(
+            header::CONTENT_LENGTH.as_str(),
+            &response.body_buffer.len().to_string(),
+        );
         let compressed_headers = encoder.encode(
             [(":status".as_bytes(), response.status.to_string().as_bytes())]
                 .into_iter()
@@ -62,8 +80,7 @@ impl<T: AsyncRWSendBuf> Http2Conn<T> {
             compressed_headers,
         );
 
-        let config =
-            config_map.get_config_for_request(request.host.as_deref(), Some(request.uri.path()));
+        let config = config_map.request_config(&request);
         let conf = get_plugin!(config, jequi).unwrap();
 
         self.conn
@@ -78,24 +95,25 @@ impl<T: AsyncRWSendBuf> Http2Conn<T> {
 
             self.conn.write_all(&response_body.encode()).await.unwrap();
             self.conn.flush().await.unwrap();
-            return;
+        } else {
+            let frame_size = std::cmp::min(self.settings.max_frame_size as usize, conf.chunk_size);
+
+            let last = body_len.div_ceil(frame_size) - 1;
+            for (i, chunk) in response.body_buffer.chunks(frame_size).enumerate() {
+                let response_body = Http2Frame::new(
+                    FrameType::Data,
+                    if i == last { END_STREAM_FLAG } else { 0 },
+                    stream_id,
+                    chunk,
+                );
+                println!("send: {:?}", response_body.length);
+
+                self.conn.write_all(&response_body.encode()).await.unwrap();
+                self.conn.flush().await.unwrap();
+            }
         }
 
-        let frame_size = std::cmp::min(self.settings.max_frame_size as usize, conf.chunk_size);
-
-        let last = body_len.div_ceil(frame_size) - 1;
-        for (i, chunk) in response.body_buffer.chunks(frame_size).enumerate() {
-            let response_body = Http2Frame::new(
-                FrameType::Data,
-                if i == last { END_STREAM_FLAG } else { 0 },
-                stream_id,
-                chunk,
-            );
-            println!("send: {:?}", response_body.length);
-
-            self.conn.write_all(&response_body.encode()).await.unwrap();
-            self.conn.flush().await.unwrap();
-        }
+        request.log(&mut response, &config_map).await;
     }
 
     pub async fn handle_connection(mut self, config_map: Arc<ConfigMap>) {
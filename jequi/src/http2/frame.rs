@@ -11,7 +11,7 @@ use tokio::{
     sync::mpsc::Sender,
 };
 
-use crate::{AsyncRWSend, AsyncRWSendBuf, ConfigMap, Request, Response, Uri};
+use crate::{AsyncRWSend, AsyncRWSendBuf, ConfigMap, ConnInfo, Request, Response, Uri};
 
 use super::{
     BufStreamRaw, FrameType, Http2Conn, Http2Frame, Settings, Stream, END_STREAM_FLAG, PADDED_FLAG,
@@ -99,7 +99,8 @@ impl Http2Frame<Vec<u8>> {
             FrameType::Settings => self.process_settings(&mut conn.settings).await,
             FrameType::Data => self.process_data(&conn.streams),
             FrameType::Headers => {
-                self.process_headers(&mut conn.streams, decoder, tx, config_map)
+                let conn_info = conn.conn_info.clone();
+                self.process_headers(&mut conn.streams, decoder, tx, config_map, conn_info)
                     .await
             }
             _ => (),
@@ -151,6 +152,7 @@ impl<P: AsRef<[u8]>> Http2Frame<P> {
         decoder: &mut Decoder<'_>,
         tx: Sender<u32>,
         config_map: Arc<ConfigMap>,
+        conn_info: Arc<ConnInfo>,
     ) {
         let flags = self.flags;
         let mut read_body = false;
@@ -166,6 +168,7 @@ impl<P: AsRef<[u8]>> Http2Frame<P> {
         }
 
         let mut request = Request::new();
+        request.conn_info = conn_info;
 
         decoder
             .decode_with_cb(payload, |h, v| {
@@ -202,19 +205,23 @@ impl<P: AsRef<[u8]>> Http2Frame<P> {
         streams.insert(stream_id, stream.clone());
 
         tokio::spawn(async move {
-            let request = unsafe { Arc::get_mut_unchecked(&mut request) };
-            let response = unsafe { Arc::get_mut_unchecked(&mut response) };
+            {
+                let request = unsafe { Arc::get_mut_unchecked(&mut request) };
+                let response = unsafe { Arc::get_mut_unchecked(&mut response) };
 
-            if !read_body {
-                request.body.get_mut().write_body(None);
-            }
+                if !read_body {
+                    request.body.get_mut().write_body(None);
+                }
 
-            request.handle_request(response, config_map).await;
+                request.handle_request(response, config_map).await;
 
-            if read_body {
-                request.body.clone().get_body().await;
+                if read_body {
+                    request.body.clone().get_body().await;
+                }
             }
 
+            // The connection takes the request and response back once the stream is sent
+            drop((request, response));
             tx.send(stream_id).await.unwrap();
         });
     }
@@ -1,7 +1,7 @@
 use derivative::Derivative;
 use std::{collections::HashMap, sync::Arc};
 
-use crate::{AsyncRWSendBuf, Request, Response};
+use crate::{AsyncRWSendBuf, ConnInfo, Request, Response};
 
 pub mod conn;
 pub mod frame;
@@ -30,6 +30,7 @@ pub struct Http2Conn<T: AsyncRWSendBuf> {
     pub conn: T,
     settings: Settings,
     streams: HashMap<u32, Arc<Stream>>,
+    conn_info: Arc<ConnInfo>,
 }
 
 #[derive(Debug)]
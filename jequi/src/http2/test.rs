@@ -1 +1,122 @@
+use std::sync::Arc;
 
+use futures::FutureExt;
+use hpack_patched::{Decoder, Encoder};
+use tokio::io::{duplex, AsyncWriteExt, BufStream};
+
+use crate::{
+    http2::{BufStreamRaw, FrameType, END_HEADERS_FLAG, END_STREAM_FLAG, PREFACE},
+    BodyChunkFilter, BodyFilter, Config, ConfigMap, PhaseHandlers, Plugin, PostRequestHandler,
+    Request, RequestHandler, Response,
+};
+
+use super::{Http2Conn, Http2Frame};
+
+struct Append;
+
+impl BodyFilter for Append {
+    fn filter_body(
+        &self,
+        _request: &Request,
+        _response: &Response,
+    ) -> Option<Box<dyn BodyChunkFilter>> {
+        Some(Box::new(Append))
+    }
+}
+
+impl BodyChunkFilter for Append {
+    fn filter_chunk(&mut self, mut chunk: Vec<u8>, last: bool) -> Vec<u8> {
+        if last {
+            chunk.extend_from_slice(b", filtered");
+        }
+        chunk
+    }
+}
+
+fn config_map() -> ConfigMap {
+    ConfigMap {
+        config: vec![
+            Plugin {
+                name: "jequi",
+                config: Arc::new(Config::default()),
+                handlers: PhaseHandlers::default(),
+            },
+            Plugin {
+                name: "append",
+                config: Arc::new(Config::default()),
+                handlers: PhaseHandlers {
+                    // Like a proxied response, the length is set before the filters run
+                    content: RequestHandler(Some(Arc::new(
+                        |_req: &mut Request, resp: &mut Response| {
+                            resp.set_header("content-length", "5");
+                            resp.body_buffer = b"hello".to_vec();
+                            async { PostRequestHandler::Continue }.boxed()
+                        },
+                    ))),
+                    body_filter: Some(Arc::new(Append)),
+                    ..Default::default()
+                },
+            },
+        ],
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn filtered_content_length_test() {
+    let (client, server) = duplex(1 << 16);
+    let server = tokio::spawn(Http2Conn::new(server).handle_connection(Arc::new(config_map())));
+
+    let mut client = BufStream::new(client);
+    let headers = Encoder::new().encode([
+        (&b":method"[..], &b"GET"[..]),
+        (b":path", b"/"),
+        (b":scheme", b"http"),
+        (b":authority", b"jequi.com"),
+    ]);
+    client.write_all(PREFACE).await.unwrap();
+    client
+        .write_all(&Http2Frame::new(FrameType::Settings, 0, 0, Vec::new()).encode())
+        .await
+        .unwrap();
+    client
+        .write_all(
+            &Http2Frame::new(
+                FrameType::Headers,
+                END_HEADERS_FLAG | END_STREAM_FLAG,
+                1,
+                headers,
+            )
+            .encode(),
+        )
+        .await
+        .unwrap();
+    client.flush().await.unwrap();
+
+    let mut decoder = Decoder::new();
+    let mut content_length = None;
+    let mut body = Vec::new();
+    loop {
+        let frame = Http2Frame::read_frame(BufStreamRaw(&mut client)).await;
+        match frame.typ {
+            FrameType::Headers => {
+                for (name, value) in decoder.decode(&frame.payload).unwrap() {
+                    if name == b"content-length" {
+                        content_length = Some(String::from_utf8(value).unwrap());
+                    }
+                }
+            }
+            FrameType::Data => {
+                body.extend_from_slice(&frame.payload);
+                if frame.flags & END_STREAM_FLAG != 0 {
+                    break;
+                }
+            }
+            _ => (),
+        }
+    }
+    server.abort();
+
+    assert_eq!(body, b"hello, filtered");
+    assert_eq!(content_length.as_deref(), Some("15"));
+}
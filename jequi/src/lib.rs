@@ -7,9 +7,25 @@
 #![feature(trait_alias)]
 pub mod body;
 pub mod config;
+pub mod config_error;
+pub mod config_interpolation;
+pub mod config_keys;
+pub mod config_schema;
+pub mod conn_info;
+pub mod explain;
+pub mod filter;
 pub mod hijack;
 pub mod http1;
 pub mod http2;
+pub mod lifecycle;
+pub mod match_conditions;
+pub mod module;
+pub mod path_match;
+pub mod phase;
+pub mod plugin;
+pub mod plugin_order;
+pub mod proxy_protocol;
+pub mod registry;
 pub mod request;
 pub mod response;
 pub mod ssl;
@@ -19,6 +35,8 @@ use std::{
     any::Any,
     collections::HashMap,
     fmt::{self, Debug},
+    marker::PhantomData,
+    net::SocketAddr,
     path::PathBuf,
     sync::Arc,
 };
@@ -28,16 +46,24 @@ use futures::future::BoxFuture;
 use http::HeaderMap;
 use http1::Http1Conn;
 use http2::Http2Conn;
+use indexmap::IndexMap;
 use plugins::get_plugin;
+use plugins::ConfigOptions;
+use regex::Regex;
 use serde::Deserialize;
 use serde_yaml::Value;
 use ssl::ssl_new;
 use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, BufStream};
 use tokio_openssl::SslStream;
 
-use crate as jequi;
+// `#[derive(JequiConfig)]` and `#[derive(ConfigOptions)]` emit `::jequi::` paths
+extern crate self as jequi;
 
+pub use futures;
 pub use hijack::PostRequestHandler;
+pub use inventory;
+pub use serde_json;
+pub use serde_yaml;
 
 pub trait RequestHandlerFn =
     for<'a> Fn(&'a mut Request, &'a mut Response) -> BoxFuture<'a, PostRequestHandler>;
@@ -45,6 +71,7 @@ pub trait RequestHandlerFn =
 pub trait AsyncRWSendBuf = AsyncBufRead + AsyncRWSend;
 pub trait AsyncRWSend = AsyncRead + AsyncWrite + Unpin + Send + 'static;
 
+#[derive(Default)]
 pub struct RequestHandler(pub Option<Arc<dyn RequestHandlerFn + Send + Sync>>);
 
 impl Debug for RequestHandler {
@@ -56,10 +83,74 @@ impl Debug for RequestHandler {
         write!(f, "{}", text)
     }
 }
+
+/// Phases of a request, in the order they run. The handlers of a phase run in the order of
+/// the plugins.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    /// Right after the request is read and its host and path are matched.
+    PostRead,
+    /// Changes the request before it is checked, like its uri or headers.
+    Rewrite,
+    /// Allows or denies the request.
+    Access,
+    /// Generates the response.
+    Content,
+    /// Changes the status and headers of the response, it runs even if a handler of the
+    /// previous phases returned `PostRequestHandler::Exit`.
+    HeaderFilter,
+    /// Changes the body of the response.
+    BodyFilter,
+    /// Runs once the response is sent, changes to it are ignored.
+    Log,
+}
+
+/// Changes the status and headers of a response, once the request handlers are done and
+/// before it is written. The filters of the plugins run in their order, each one seeing the
+/// changes of the previous ones.
+pub trait HeaderFilter: Send + Sync {
+    fn filter_headers(&self, request: &Request, response: &mut Response);
+}
+
+/// Changes the body of a response, after the header filters.
+pub trait BodyFilter: Send + Sync {
+    /// Starts filtering the body of `response`, `None` leaves it unchanged.
+    fn filter_body(
+        &self,
+        request: &Request,
+        response: &Response,
+    ) -> Option<Box<dyn BodyChunkFilter>>;
+}
+
+/// Filter of the body of a single response, it receives the body in chunks, so it can keep
+/// state between them, and returns the bytes sent in their place.
+pub trait BodyChunkFilter: Send {
+    /// `last` is true for the final chunk of the body, which can be empty.
+    fn filter_chunk(&mut self, chunk: Vec<u8>, last: bool) -> Vec<u8>;
+}
+
+/// Body filters started for a response, the output of each one is the input of the next.
+#[derive(Default)]
+pub struct BodyFilters(Vec<Box<dyn BodyChunkFilter>>);
+
+/// Handlers of a plugin, at most one for each phase.
+#[derive(Default)]
+pub struct PhaseHandlers {
+    pub post_read: RequestHandler,
+    pub rewrite: RequestHandler,
+    pub access: RequestHandler,
+    pub content: RequestHandler,
+    pub header_filter: Option<Arc<dyn HeaderFilter>>,
+    pub body_filter: Option<Arc<dyn BodyFilter>>,
+    pub log: RequestHandler,
+}
+
 #[derive(Debug)]
 pub struct Plugin {
+    /// Name of the plugin crate, `jequi` for the main config.
+    pub name: &'static str,
     pub config: Arc<dyn JequiConfig>,
-    pub request_handler: RequestHandler,
+    pub handlers: PhaseHandlers,
 }
 
 pub type ConfigList = Vec<Plugin>;
@@ -67,6 +158,8 @@ pub type ConfigList = Vec<Plugin>;
 #[derive(Debug)]
 pub struct HostConfig {
     pub path: Option<HashMap<PathBuf, ConfigList>>,
+    pub path_matcher: PathMatcher,
+    pub path_match: HashMap<PathBuf, Vec<MatchConfig>>,
     pub config: ConfigList,
 }
 
@@ -74,50 +167,389 @@ pub struct HostConfig {
 pub struct ConfigMap {
     pub host: Option<HashMap<String, HostConfig>>,
     pub path: Option<HashMap<PathBuf, ConfigList>>,
+    pub path_matcher: PathMatcher,
+    pub path_match: HashMap<PathBuf, Vec<MatchConfig>>,
+    pub config: ConfigList,
+    /// Configuration file and every file included by it.
+    pub files: Vec<PathBuf>,
+    /// Globs with wildcards of the `include` keys, relative to the current directory like
+    /// `files`, new files matching them change the configuration too.
+    pub include_globs: Vec<PathBuf>,
+    /// Parsed files, used to explain the options of a scope.
+    pub sources: Option<ConfigSources>,
+}
+
+/// Options of every scope before they are merged, with the location of each key in the files.
+#[derive(Debug)]
+pub struct ConfigSources {
+    parser: ConfigMapParser,
+    source_map: config_error::SourceMap,
+}
+
+/// Scope where an option of a `RouteExplanation` is set.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OptionOrigin {
+    pub scope: ConfigScope,
+    pub location: Option<ConfigLocation>,
+}
+
+/// Config used for a host and path, returned by `ConfigMap::explain_route`.
+#[derive(Debug)]
+pub struct RouteExplanation {
+    pub scope: ConfigScope,
+    /// Merged options of the scope, the plugins are loaded with them.
+    pub config: Value,
+    /// Scopes each option of `config` comes from, lists and maps can be merged from several.
+    pub origins: HashMap<String, Vec<OptionOrigin>>,
+    /// Plugins of the scope in the order their handlers run, with the phases they handle.
+    pub plugins: Vec<(&'static str, Vec<Phase>)>,
+    /// Entries of the `match` list of the path block, they replace the options for the
+    /// requests that meet their conditions.
+    pub matches: usize,
+}
+
+/// Host and path blocks, and `match` entry, chosen for a request, see
+/// `ConfigMap::request_config`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RouteKey {
+    pub host: Option<String>,
+    pub path: Option<PathBuf>,
+    /// Index of the `match` entry of the path block used.
+    pub match_index: Option<usize>,
+}
+
+/// Entry of the `match` list of a path block, its config is used instead of the path config
+/// when the request meets every condition.
+#[derive(Debug)]
+pub struct MatchConfig {
+    pub conditions: MatchConditions,
     pub config: ConfigList,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct MatchConditions {
+    #[serde(deserialize_with = "match_conditions::one_or_many")]
+    pub method: Vec<String>,
+    pub header: HashMap<String, HeaderCondition>,
+    #[serde(deserialize_with = "match_conditions::one_or_many")]
+    pub query: Vec<String>,
+    #[serde(deserialize_with = "match_conditions::one_or_many")]
+    pub client: Vec<proxy_protocol::Cidr>,
+}
+
+/// Expected value of a header, `~ regex` (or `~* regex` to ignore case) matches it with a
+/// regex.
+#[derive(Debug, Clone)]
+pub enum HeaderCondition {
+    Equals(String),
+    Regex(Regex),
+}
+
+/// How a `path` block matches request paths, set by the modifier before the path.
+#[derive(Debug, Clone)]
+pub enum PathPattern {
+    /// `= /path`, only the path itself.
+    Exact(String),
+    /// `/path`, the path and everything under it.
+    Prefix(PathBuf),
+    /// `^~ /path`, like `Prefix` but regexes aren't tried when it is the longest prefix.
+    PriorityPrefix(PathBuf),
+    /// `~ regex`, or `~* regex` to ignore case.
+    Regex(Regex),
+}
+
+/// Patterns of the `path` blocks of a scope, in the order they are in the configuration, with
+/// the key of the block they select.
+#[derive(Default, Debug)]
+pub struct PathMatcher {
+    patterns: Vec<(PathPattern, PathBuf)>,
+}
+
+/// Groups captured by the regex `path` block that matched the request, group 0 is the whole
+/// path.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct PathCaptures {
+    groups: Vec<Option<String>>,
+    names: HashMap<String, usize>,
+}
+
+#[derive(Debug, Clone)]
 pub struct HostConfigParser {
-    pub path: Option<HashMap<PathBuf, Value>>,
-    #[serde(flatten)]
+    pub path: Option<IndexMap<PathBuf, Value>>,
     pub config: Value,
 }
 
-#[derive(Deserialize)]
+#[derive(Debug, Clone)]
 pub struct ConfigMapParser {
     pub host: Option<HashMap<String, HostConfigParser>>,
-    pub path: Option<HashMap<PathBuf, Value>>,
-    #[serde(flatten)]
+    pub path: Option<IndexMap<PathBuf, Value>>,
     pub config: Value,
 }
 
+/// Scope of the configuration where an option was set.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigScope {
+    Default,
+    Host(String),
+    Path(PathBuf),
+    HostPath(String, PathBuf),
+}
+
+/// Kind of a `ConfigScope`, plugins use it to restrict the scopes their options can be set in.
+/// Paths inside a host and `match` entries are `Path` scopes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScopeKind {
+    Default,
+    Host,
+    Path,
+}
+
+/// Position in the configuration file, line and column start at 1.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigLocation {
+    pub file: PathBuf,
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Error loading the configuration, the context is filled as the error goes up from the plugin
+/// that found it to `ConfigMap::load`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    pub message: String,
+    pub plugin: Option<&'static str>,
+    pub scope: Option<ConfigScope>,
+    pub key: Option<String>,
+    pub location: Option<Box<ConfigLocation>>,
+}
+
 pub trait JequiConfig: Any + Send + Sync + Debug {
-    fn load(config_yaml: &Value, configs: &mut Vec<Option<Plugin>>) -> Option<Arc<Self>>
+    /// Loads the plugin configuration, `Ok(None)` means the plugin isn't used in this scope.
+    fn load(
+        config_yaml: &Value,
+        configs: &mut Vec<Option<Plugin>>,
+    ) -> Result<Option<Arc<Self>>, ConfigError>
     where
         Self: Sized;
     fn as_any(&self) -> &dyn Any;
     fn as_any_mut(&mut self) -> &mut dyn Any;
 }
 
-pub fn load_plugin(config_yaml: &Value, configs: &'_ mut Vec<Option<Plugin>>) -> Option<Plugin> {
-    let config = Config::load(config_yaml, configs)?;
-    Some(Plugin {
+/// Hooks called during the life of the server, for plugins that keep state outside of their
+/// configs, like pooled resources, background tasks or per-connection state. `load` runs for
+/// every scope on every reload, these run once for the whole configuration. Every hook is
+/// optional, `#[derive(JequiConfig)]` implements none of them unless `#[jequi(lifecycle)]` is
+/// set, and `PluginLifecycle` calls the hooks of every plugin.
+pub trait Lifecycle {
+    /// Called once when the server starts, with the first configuration.
+    fn init(_config_map: &ConfigMap) {}
+    /// Called after a successful reload, once `config_map` replaced the previous configuration.
+    fn commit(_config_map: &ConfigMap) {}
+    /// Called when a replaced configuration isn't used by any connection anymore.
+    fn teardown(_config_map: &ConfigMap) {}
+    /// Called when the server shuts down gracefully, with the current configuration.
+    fn shutdown(_config_map: &ConfigMap) {}
+    /// Called when a connection is accepted, after the proxy protocol header and the TLS
+    /// handshake are read.
+    fn connection_open(_conn_info: &ConnInfo, _config_map: &ConfigMap) {}
+    /// Called when the connection is closed, however it ends.
+    fn connection_close(_conn_info: &ConnInfo, _config_map: &ConfigMap) {}
+}
+
+/// Calls the lifecycle hooks of every registered plugin, in the order they are loaded when
+/// something starts and in the reverse order when it ends.
+pub struct PluginLifecycle;
+
+/// Lifecycle hooks of a plugin as function pointers, so they can be registered.
+#[derive(Clone, Copy)]
+pub struct LifecycleHooks {
+    pub init: fn(&ConfigMap),
+    pub commit: fn(&ConfigMap),
+    pub teardown: fn(&ConfigMap),
+    pub shutdown: fn(&ConfigMap),
+    pub connection_open: fn(&ConnInfo, &ConfigMap),
+    pub connection_close: fn(&ConnInfo, &ConfigMap),
+}
+
+/// A plugin linked into the server, `#[derive(JequiConfig)]` registers it with
+/// `inventory::submit!` so depending on its crate is enough to use it.
+pub struct PluginRegistration {
+    /// Name of the plugin crate.
+    pub name: &'static str,
+    pub load: config::LoadPlugin,
+    pub options: fn() -> Vec<OptionInfo>,
+    /// Plugins loaded before this one because it uses them, they must be linked.
+    pub requires: &'static [&'static str],
+    /// Plugins loaded before this one when they are linked.
+    pub after: &'static [&'static str],
+    /// Plugins loaded after this one when they are linked.
+    pub before: &'static [&'static str],
+    pub lifecycle: LifecycleHooks,
+}
+
+/// A connection that calls `Lifecycle::connection_open` when created and
+/// `Lifecycle::connection_close` when dropped.
+pub struct OpenConnection<L: Lifecycle> {
+    conn_info: Arc<ConnInfo>,
+    config_map: Arc<ConfigMap>,
+    lifecycle: PhantomData<L>,
+}
+
+/// Description of a config option, used to generate the configuration reference and the JSON
+/// Schema.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OptionInfo {
+    pub key: &'static str,
+    pub type_name: String,
+    pub schema: serde_json::Value,
+    pub default: serde_json::Value,
+    pub scopes: &'static [ScopeKind],
+    pub description: &'static str,
+}
+
+/// Options accepted by a config, usually implemented with `#[derive(ConfigOptions)]` or
+/// `#[derive(JequiConfig)]`.
+pub trait ConfigOptions {
+    fn options() -> Vec<OptionInfo>;
+}
+
+pub fn load_plugin(
+    config_yaml: &Value,
+    configs: &'_ mut Vec<Option<Plugin>>,
+) -> Result<Option<Plugin>, ConfigError> {
+    let Some(config) = Config::load(config_yaml, configs)? else {
+        return Ok(None);
+    };
+    Ok(Some(Plugin {
+        name: "jequi",
         config: config.clone(),
-        request_handler: RequestHandler(None),
-    })
+        handlers: PhaseHandlers::default(),
+    }))
 }
 
-#[derive(Deserialize, Debug, PartialEq)]
+#[derive(Deserialize, Debug, PartialEq, ConfigOptions)]
 #[serde(default)]
 pub struct Config {
+    /// Defines the ip address that server will listen, only used when `listen` isn't set.
     pub ip: String,
+    /// Defines the port that server will listen, only used when `listen` isn't set.
     pub port: u16,
+    /// Defines if tls is active for server, only used when `listen` isn't set.
     pub tls_active: bool,
+    /// Defines if the server accepts http2.
     pub http2: bool,
+    /// Defines the maximum chunk size for http responses.
     pub chunk_size: usize,
+    /// Path of the PEM private key used for tls, with `ssl_certificate`. Without them tls
+    /// handshakes fail unless a plugin like `jequi_acme` provides the certificate.
     pub ssl_key: Option<ssl::SslKeyConfig>,
+    /// Path of the PEM certificate chain used for tls, the certificate of the server comes first.
     pub ssl_certificate: Option<ssl::SslCertConfig>,
+    /// Path to a DER encoded OCSP response that will be stapled to the certificate during TLS
+    /// handshakes, the file is read again when the configuration is reloaded. The response is
+    /// only served while it is valid for the configured certificate chain and not expired.
+    pub ssl_stapling_file: Option<ssl::SslStaplingConfig>,
+    /// Expects every connection to start with a PROXY protocol header (version 1 or 2), as sent
+    /// by load balancers like haproxy or AWS NLB. The client address from the header is used as
+    /// the remote address of the requests. The header is read before the TLS handshake,
+    /// connections without a valid header are closed. Only used when `listen` isn't set,
+    /// otherwise it is configured per listener.
+    pub proxy_protocol: bool,
+    /// Addresses or networks in CIDR notation (`10.0.0.0/8`) allowed to send the PROXY protocol
+    /// header, connections from other addresses are closed. Required when `proxy_protocol` is
+    /// enabled, by the option or by a listener.
+    pub proxy_protocol_trusted: Vec<proxy_protocol::Cidr>,
+    /// Addresses the server listens on, each listener accepts these options:
+    ///
+    /// - `name`: name used to reference the listener in `listeners`, defaults to `ip:port`.
+    /// - `ip`: ip address to listen, defaults to `127.0.0.1`.
+    /// - `port`: port to listen, defaults to `7878`.
+    /// - `tls`: if connections on this listener use tls.
+    /// - `http2`: if http2 is accepted on this listener, when not set the `http2` option of the host is used.
+    /// - `proxy_protocol`: same as the `proxy_protocol` option, for this listener.
+    ///
+    /// ```yaml
+    /// listen:
+    ///   - name: http
+    ///     ip: 0.0.0.0
+    ///     port: 80
+    ///   - name: https
+    ///     ip: 0.0.0.0
+    ///     port: 443
+    ///     tls: true
+    ///     http2: true
+    /// ```
+    ///
+    /// Listeners are only created when the server starts, changing them requires a restart.
+    pub listen: Vec<ListenConfig>,
+    /// Names of the listeners that serve this host, requests for the host received on other
+    /// listeners use the default configuration. If empty the host is served by every listener.
+    pub listeners: Vec<String>,
+    /// Redirects requests received without tls to the same url using https, the path and query
+    /// are preserved. Requests for ACME http-01 challenges (`/.well-known/acme-challenge/`) are
+    /// never redirected.
+    pub redirect_to_https: bool,
+    /// Status code used by `redirect_to_https`, one of `301`, `302`, `307` or `308`. Use `308` if
+    /// the request method should be kept.
+    pub redirect_to_https_status: usize,
+    /// Port used in the url of `redirect_to_https`.
+    pub redirect_to_https_port: u16,
+    /// When set, responses sent over tls include a `Strict-Transport-Security` header with this
+    /// max-age in seconds.
+    pub hsts_max_age: Option<u64>,
+    /// Options that aren't used by any plugin are reported as warnings when the config is
+    /// loaded, with the closest valid option if it looks like a typo. With `strict_config` they
+    /// are errors and the config isn't loaded.
+    pub strict_config: bool,
+    /// Reloads the configuration when `conf.yaml` or any file included by it changes, without
+    /// needing `make reload`. Changes are only applied once the files stop changing for half a
+    /// second, every reload is logged and a config that fails to load leaves the running one
+    /// untouched.
+    pub watch_config: bool,
+    /// Path where the admin endpoints are served, for example `/.jequi`. They are disabled when
+    /// not set. `GET <admin_path>/explain-route?host=<host>&path=<path>` shows the same as the
+    /// `--explain-route` command for the running configuration.
+    pub admin_path: Option<String>,
+    /// Addresses or networks in CIDR notation allowed to use the admin endpoints, other clients
+    /// receive a 403.
+    pub admin_clients: Vec<proxy_protocol::Cidr>,
+    #[serde(skip)]
+    pub ssl_identity: ssl::SslIdentity,
+}
+
+/// Config of the `jequi_module` plugin, it loads the modules of `load_module` and runs them.
+#[derive(Deserialize, Default, Debug, ConfigOptions)]
+#[serde(default)]
+pub struct ModuleConfig {
+    /// Paths of native modules loaded at runtime, `cdylib` crates built with the
+    /// `jequi_module_api` crate. Their options can then be set like the options of any plugin
+    /// and they handle requests in the content phase, in the order of this list. A module is
+    /// loaded once, replacing its library requires a restart.
+    #[jequi(scopes(default))]
+    pub load_module: Vec<PathBuf>,
+    #[serde(skip)]
+    modules: Vec<module::ModuleInstance>,
+}
+
+/// Address the server listens on, with the options of the connections accepted there.
+#[derive(Deserialize, Debug, PartialEq, Clone, ConfigOptions)]
+#[serde(default)]
+#[jequi(scopes(default))]
+pub struct ListenConfig {
+    /// Name used to reference the listener in `listeners`, `ip:port` when not set.
+    pub name: Option<String>,
+    /// Ip address to listen.
+    pub ip: String,
+    /// Port to listen.
+    pub port: u16,
+    /// If connections on this listener use tls.
+    pub tls: bool,
+    /// If http2 is accepted on this listener, when not set the `http2` option of the host is
+    /// used.
+    pub http2: Option<bool>,
+    /// Same as the `proxy_protocol` option, for this listener.
+    pub proxy_protocol: bool,
 }
 
 pub enum RawStream<T: AsyncRWSend> {
@@ -127,12 +559,30 @@ pub enum RawStream<T: AsyncRWSend> {
 
 pub struct Uri(String);
 
+/// Information about the connection a request was received on, it is shared by every
+/// request of the connection.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct ConnInfo {
+    pub listener: Option<String>,
+    pub remote_addr: Option<SocketAddr>,
+    pub local_addr: Option<SocketAddr>,
+    pub tls: bool,
+    pub tls_version: Option<String>,
+    pub tls_cipher: Option<String>,
+    pub sni: Option<String>,
+    pub alpn: Option<String>,
+}
+
 pub struct Request {
     pub method: String,
     pub uri: Uri,
     pub headers: HeaderMap,
     pub host: Option<String>,
     pub body: Arc<RequestBody>,
+    pub conn_info: Arc<ConnInfo>,
+    pub path_captures: PathCaptures,
+    /// Blocks of the configuration chosen when the request was handled.
+    pub route: Option<RouteKey>,
 }
 
 #[repr(C)]
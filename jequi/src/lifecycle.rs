@@ -0,0 +1,207 @@
+use std::{marker::PhantomData, mem, sync::Arc, time::Duration};
+
+use tokio::{
+    sync::RwLock,
+    time::{sleep, timeout},
+};
+
+use crate::{
+    config::LoadPlugins, ConfigError, ConfigMap, ConnInfo, Lifecycle, LifecycleHooks,
+    OpenConnection,
+};
+
+// How often a replaced configuration is checked to see if connections still use it
+const TEARDOWN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// How often the connections still open are counted while shutting down
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+impl LifecycleHooks {
+    pub const fn of<L: Lifecycle>() -> LifecycleHooks {
+        LifecycleHooks {
+            init: L::init,
+            commit: L::commit,
+            teardown: L::teardown,
+            shutdown: L::shutdown,
+            connection_open: L::connection_open,
+            connection_close: L::connection_close,
+        }
+    }
+}
+
+impl<L: Lifecycle> OpenConnection<L> {
+    pub fn new(conn_info: Arc<ConnInfo>, config_map: Arc<ConfigMap>) -> OpenConnection<L> {
+        L::connection_open(&conn_info, &config_map);
+        OpenConnection {
+            conn_info,
+            config_map,
+            lifecycle: PhantomData,
+        }
+    }
+}
+
+// Connection tasks can end by panicking, dropping still runs while unwinding
+impl<L: Lifecycle> Drop for OpenConnection<L> {
+    fn drop(&mut self) {
+        L::connection_close(&self.conn_info, &self.config_map);
+    }
+}
+
+/// Calls `Lifecycle::teardown` once `config_map` is the last reference to the configuration,
+/// after it was replaced by a reload and the connections using it are closed.
+pub async fn teardown_when_unused<L: Lifecycle>(config_map: Arc<ConfigMap>) {
+    while Arc::strong_count(&config_map) > 1 {
+        sleep(TEARDOWN_CHECK_INTERVAL).await;
+    }
+    L::teardown(&config_map);
+}
+
+/// Loads the configuration at `path` again and replaces the one in `config_map` with it, then
+/// calls `Lifecycle::commit` and tears the previous one down once it is unused. The current
+/// configuration is kept when the new one can't be loaded.
+pub async fn reload<L: Lifecycle + 'static>(
+    config_map: &RwLock<Arc<ConfigMap>>,
+    path: &str,
+    load_plugins: LoadPlugins,
+) -> Result<Arc<ConfigMap>, ConfigError> {
+    let loaded = Arc::new(ConfigMap::load(path, load_plugins)?);
+    let old = mem::replace(&mut *config_map.write().await, loaded.clone());
+    L::commit(&loaded);
+    tokio::spawn(teardown_when_unused::<L>(old));
+    Ok(loaded)
+}
+
+/// Calls `Lifecycle::shutdown` once the connections holding a clone of `connections` are
+/// closed, so their `connection_close` hooks run first, or after `grace` when some are still
+/// open. The server stops accepting connections before calling it.
+pub async fn shutdown_when_closed<L: Lifecycle>(
+    config_map: &ConfigMap,
+    connections: Arc<()>,
+    grace: Duration,
+) {
+    let closed = async {
+        while Arc::strong_count(&connections) > 1 {
+            sleep(SHUTDOWN_CHECK_INTERVAL).await;
+        }
+    };
+    if timeout(grace, closed).await.is_err() {
+        println!(
+            "[WARN] shutting down with {} connections still open",
+            Arc::strong_count(&connections) - 1
+        );
+    }
+    L::shutdown(config_map);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, sync::Mutex, time::Duration};
+
+    use tokio::time::{sleep, timeout};
+
+    use crate::{ConfigMap, ConnInfo, Lifecycle, OpenConnection};
+
+    use super::{shutdown_when_closed, teardown_when_unused};
+
+    static CALLS: Mutex<Vec<&str>> = Mutex::new(Vec::new());
+
+    struct Recorder;
+
+    impl Lifecycle for Recorder {
+        fn teardown(_config_map: &ConfigMap) {
+            CALLS.lock().unwrap().push("teardown");
+        }
+
+        fn connection_open(conn_info: &ConnInfo, _config_map: &ConfigMap) {
+            assert!(conn_info.tls);
+            CALLS.lock().unwrap().push("open");
+        }
+
+        fn connection_close(_conn_info: &ConnInfo, _config_map: &ConfigMap) {
+            CALLS.lock().unwrap().push("close");
+        }
+    }
+
+    #[tokio::test]
+    async fn lifecycle_test() {
+        let config_map = Arc::new(ConfigMap::default());
+        let conn_info = Arc::new(ConnInfo {
+            tls: true,
+            ..Default::default()
+        });
+
+        let connection = OpenConnection::<Recorder>::new(conn_info, config_map.clone());
+        let teardown = tokio::spawn(teardown_when_unused::<Recorder>(config_map));
+        // The connection still uses the configuration
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(*CALLS.lock().unwrap(), ["open"]);
+
+        // Closing it calls the hook even when its task panics
+        tokio::spawn(async move {
+            let _connection = connection;
+            panic!("connection failed");
+        })
+        .await
+        .unwrap_err();
+        timeout(Duration::from_secs(5), teardown)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(*CALLS.lock().unwrap(), ["open", "close", "teardown"]);
+    }
+
+    static SHUTDOWN_CALLS: Mutex<Vec<&str>> = Mutex::new(Vec::new());
+
+    struct ShutdownRecorder;
+
+    impl Lifecycle for ShutdownRecorder {
+        fn shutdown(_config_map: &ConfigMap) {
+            SHUTDOWN_CALLS.lock().unwrap().push("shutdown");
+        }
+
+        fn connection_close(_conn_info: &ConnInfo, _config_map: &ConfigMap) {
+            SHUTDOWN_CALLS.lock().unwrap().push("close");
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_test() {
+        let config_map = Arc::new(ConfigMap::default());
+        let connections = Arc::new(());
+
+        // Connections still handling a request are closed before the plugins shut down
+        let connection =
+            OpenConnection::<ShutdownRecorder>::new(Arc::default(), config_map.clone());
+        let tracked = connections.clone();
+        tokio::spawn(async move {
+            let _tracked = tracked;
+            let _connection = connection;
+            sleep(Duration::from_millis(200)).await;
+        });
+        shutdown_when_closed::<ShutdownRecorder>(
+            &config_map,
+            connections.clone(),
+            Duration::from_secs(5),
+        )
+        .await;
+        assert_eq!(*SHUTDOWN_CALLS.lock().unwrap(), ["close", "shutdown"]);
+
+        // Connections left open don't keep the server from stopping
+        let _connection =
+            OpenConnection::<ShutdownRecorder>::new(Arc::default(), config_map.clone());
+        let _tracked = connections.clone();
+        timeout(
+            Duration::from_secs(5),
+            shutdown_when_closed::<ShutdownRecorder>(
+                &config_map,
+                connections,
+                Duration::from_millis(200),
+            ),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            *SHUTDOWN_CALLS.lock().unwrap(),
+            ["close", "shutdown", "shutdown"]
+        );
+    }
+}
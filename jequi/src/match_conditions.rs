@@ -0,0 +1,172 @@
+use std::fmt;
+
+use regex::RegexBuilder;
+use serde::{de, Deserialize, Deserializer};
+
+use crate::{HeaderCondition, MatchConditions, Request};
+
+/// Deserializes a single value or a list of values as a list.
+pub fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+impl<'de> Deserialize<'de> for HeaderCondition {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct HeaderConditionVisitor;
+
+        impl<'de> de::Visitor<'de> for HeaderConditionVisitor {
+            type Value = HeaderCondition;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("HeaderCondition")
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                let (regex, case_insensitive) = match (v.strip_prefix("~* "), v.strip_prefix("~ "))
+                {
+                    (Some(regex), _) => (regex, true),
+                    (_, Some(regex)) => (regex, false),
+                    _ => return Ok(HeaderCondition::Equals(v.to_string())),
+                };
+                RegexBuilder::new(regex.trim_start())
+                    .case_insensitive(case_insensitive)
+                    .build()
+                    .map(HeaderCondition::Regex)
+                    .map_err(|err| E::custom(format!("invalid regex: {}", err)))
+            }
+        }
+
+        deserializer.deserialize_str(HeaderConditionVisitor {})
+    }
+}
+
+impl HeaderCondition {
+    pub fn matches(&self, value: &str) -> bool {
+        match self {
+            HeaderCondition::Equals(expected) => expected == value,
+            HeaderCondition::Regex(regex) => regex.is_match(value),
+        }
+    }
+}
+
+impl MatchConditions {
+    pub fn is_empty(&self) -> bool {
+        self.method.is_empty()
+            && self.header.is_empty()
+            && self.query.is_empty()
+            && self.client.is_empty()
+    }
+
+    /// Checks every condition against `request`, a list matches if any of its values does.
+    pub fn matches(&self, request: &Request) -> bool {
+        let method = self.method.is_empty()
+            || self
+                .method
+                .iter()
+                .any(|method| method.eq_ignore_ascii_case(&request.method));
+
+        let headers = self.header.iter().all(|(name, condition)| {
+            request
+                .get_header(name)
+                .and_then(|value| value.to_str().ok())
+                .is_some_and(|value| condition.matches(value))
+        });
+
+        let query = self.query.iter().all(|name| {
+            request.uri.query_string().is_some_and(|query| {
+                query
+                    .split('&')
+                    .any(|param| param.split('=').next() == Some(name.as_str()))
+            })
+        });
+
+        let client = self.client.is_empty()
+            || request
+                .conn_info
+                .remote_addr
+                .is_some_and(|addr| self.client.iter().any(|cidr| cidr.contains(&addr.ip())));
+
+        method && headers && query && client
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use http::HeaderValue;
+
+    use crate::{ConnInfo, MatchConditions, Request, Uri};
+
+    fn request(method: &str, uri: &str) -> Request {
+        let mut request = Request::new();
+        request.method = method.to_string();
+        request.uri = Uri::from(uri.to_string());
+        request
+            .headers
+            .insert("user-agent", HeaderValue::from_static("Googlebot/2.1"));
+        request.conn_info = Arc::new(ConnInfo {
+            remote_addr: Some("10.1.2.3:56324".parse().unwrap()),
+            ..Default::default()
+        });
+        request
+    }
+
+    fn conditions(yaml: &str) -> MatchConditions {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn match_conditions_test() {
+        let post = conditions("method: POST");
+        assert!(post.matches(&request("POST", "/api")));
+        assert!(!post.matches(&request("GET", "/api")));
+
+        let read = conditions("method: [GET, HEAD]");
+        assert!(read.matches(&request("HEAD", "/api")));
+        assert!(!read.matches(&request("DELETE", "/api")));
+
+        let bot = conditions("header: {User-Agent: \"~* googlebot\"}");
+        assert!(bot.matches(&request("GET", "/")));
+        let exact = conditions("header: {user-agent: Googlebot}");
+        assert!(!exact.matches(&request("GET", "/")));
+        let missing = conditions("header: {x-debug: \"1\"}");
+        assert!(!missing.matches(&request("GET", "/")));
+
+        let debug = conditions("query: debug");
+        assert!(debug.matches(&request("GET", "/?page=2&debug")));
+        assert!(debug.matches(&request("GET", "/?debug=1")));
+        assert!(!debug.matches(&request("GET", "/?debugging=1")));
+        assert!(!debug.matches(&request("GET", "/")));
+
+        let internal = conditions("client: [192.168.0.0/16, 10.0.0.0/8]");
+        assert!(internal.matches(&request("GET", "/")));
+        assert!(!conditions("client: 127.0.0.1").matches(&request("GET", "/")));
+
+        let all = conditions("{method: GET, query: debug, client: 10.0.0.0/8}");
+        assert!(all.matches(&request("GET", "/?debug")));
+        assert!(!all.matches(&request("POST", "/?debug")));
+
+        assert!(serde_yaml::from_str::<MatchConditions>("header: {x: \"~ (\"}").is_err());
+    }
+}
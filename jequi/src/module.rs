@@ -0,0 +1,396 @@
+use std::{
+    collections::HashMap,
+    ffi::c_void,
+    fmt::{self, Debug},
+    path::{Path, PathBuf},
+    ptr,
+    sync::{Arc, Mutex},
+};
+
+use futures::FutureExt;
+use http::{HeaderName, HeaderValue};
+use jequi_module_api::{
+    AbiStr, DeclarationFn, Flow, HostApi, ModuleDeclaration, RawError, RawRequest, RawResponse,
+    ABI_VERSION, DECLARATION_SYMBOL,
+};
+use libloading::Library;
+use serde_yaml::Value;
+
+use crate::{
+    config, ConfigError, ConfigOptions, JequiConfig, Lifecycle, LifecycleHooks, ModuleConfig,
+    PhaseHandlers, Plugin, PluginRegistration, PostRequestHandler, Request, RequestHandler,
+    Response,
+};
+
+// Modules by path, their libraries are never unloaded so the declarations stay valid
+static MODULES: Mutex<Option<HashMap<PathBuf, Arc<LoadedModule>>>> = Mutex::new(None);
+
+static HOST_API: HostApi = HostApi {
+    request_method,
+    request_uri,
+    request_header,
+    request_body,
+    set_status,
+    set_header,
+    write_body,
+    set_error,
+};
+
+inventory::submit! {
+    PluginRegistration {
+        name: "jequi_module",
+        load: load_plugin,
+        options: ModuleConfig::options,
+        requires: &[],
+        // Modules run after the content handlers of the plugins linked into the server
+        after: &["jequi_serve_static", "jequi_proxy", "jequi_go"],
+        before: &[],
+        lifecycle: LifecycleHooks::of::<ModuleConfig>(),
+    }
+}
+
+/// Shared library of a module, loaded by `load_module`.
+struct LoadedModule {
+    name: &'static str,
+    declaration: &'static ModuleDeclaration,
+    _library: Library,
+}
+
+impl LoadedModule {
+    fn get(path: &Path) -> Result<Arc<LoadedModule>, ConfigError> {
+        let mut modules = MODULES.lock().unwrap();
+        let modules = modules.get_or_insert_default();
+        if let Some(module) = modules.get(path) {
+            return Ok(module.clone());
+        }
+        let module = Arc::new(LoadedModule::open(path)?);
+        modules.insert(path.to_path_buf(), module.clone());
+        Ok(module)
+    }
+
+    fn open(path: &Path) -> Result<LoadedModule, ConfigError> {
+        let library = unsafe { Library::new(path) }.map_err(|err| {
+            ConfigError::new(format!("can't load module {}: {}", path.display(), err))
+        })?;
+        let declaration =
+            unsafe { library.get::<DeclarationFn>(DECLARATION_SYMBOL) }.map_err(|_| {
+                ConfigError::new(format!(
+                    "{} isn't a jequi module, it doesn't export `jequi_module_declaration`",
+                    path.display()
+                ))
+            })?;
+        let declaration = unsafe { check_declaration(path, declaration())? };
+        let name = unsafe { declaration.name.as_str() }.ok_or_else(|| {
+            ConfigError::new(format!("module {} has an invalid name", path.display()))
+        })?;
+        Ok(LoadedModule {
+            name,
+            declaration,
+            _library: library,
+        })
+    }
+
+    fn options(&self) -> Vec<&'static str> {
+        let declaration = self.declaration;
+        if declaration.options.is_null() {
+            return Vec::new();
+        }
+        let options =
+            unsafe { std::slice::from_raw_parts(declaration.options, declaration.options_len) };
+        options
+            .iter()
+            .filter_map(|option| unsafe { option.key.as_str() })
+            .collect()
+    }
+
+    fn load(self: &Arc<Self>, config_yaml: &str) -> Result<Option<ModuleInstance>, ConfigError> {
+        let mut error: Option<String> = None;
+        let config = unsafe {
+            (self.declaration.load)(
+                &HOST_API,
+                AbiStr::new(config_yaml.as_bytes()),
+                ptr::addr_of_mut!(error).cast(),
+            )
+        };
+        if let Some(message) = error {
+            return Err(ConfigError::new(format!(
+                "module {}: {}",
+                self.name, message
+            )));
+        }
+        Ok((!config.is_null()).then(|| ModuleInstance {
+            module: self.clone(),
+            config,
+        }))
+    }
+}
+
+/// The version is read before anything else, the layout of the rest of the declaration is
+/// only known when it matches.
+unsafe fn check_declaration(
+    path: &Path,
+    declaration: *const ModuleDeclaration,
+) -> Result<&'static ModuleDeclaration, ConfigError> {
+    if declaration.is_null() {
+        return Err(ConfigError::new(format!(
+            "module {} returned no declaration",
+            path.display()
+        )));
+    }
+    let abi_version = unsafe { ptr::addr_of!((*declaration).abi_version).read() };
+    if abi_version != ABI_VERSION {
+        return Err(ConfigError::new(format!(
+            "module {} was built for version {} of the module interface, this server supports \
+             version {}, rebuild it with the jequi_module_api of this server",
+            path.display(),
+            abi_version,
+            ABI_VERSION
+        )));
+    }
+    Ok(unsafe { &*declaration })
+}
+
+/// Config returned by a module for a scope, freed by the module when dropped.
+pub struct ModuleInstance {
+    module: Arc<LoadedModule>,
+    config: *mut c_void,
+}
+
+// `jequi_module_api::Module` requires configs to be `Send` and `Sync`
+unsafe impl Send for ModuleInstance {}
+unsafe impl Sync for ModuleInstance {}
+
+impl ModuleInstance {
+    fn handle_request(&self, req: &mut Request, resp: &mut Response) -> Flow {
+        unsafe {
+            (self.module.declaration.handle_request)(
+                &HOST_API,
+                self.config,
+                (req as *mut Request).cast(),
+                (resp as *mut Response).cast(),
+            )
+        }
+    }
+}
+
+impl Drop for ModuleInstance {
+    fn drop(&mut self) {
+        unsafe { (self.module.declaration.drop)(self.config) }
+    }
+}
+
+impl Debug for ModuleInstance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.module.name)
+    }
+}
+
+impl JequiConfig for ModuleConfig {
+    fn load(
+        config_yaml: &Value,
+        _configs: &mut Vec<Option<Plugin>>,
+    ) -> Result<Option<Arc<Self>>, ConfigError> {
+        let mut conf: ModuleConfig = config::deserialize(config_yaml)?;
+        if conf.load_module.is_empty() {
+            return Ok(None);
+        }
+
+        // Modules get the options of the scope like plugins, but in YAML
+        let yaml = serde_yaml::to_string(config_yaml).map_err(ConfigError::new)?;
+        for path in &conf.load_module {
+            let module = LoadedModule::get(path).map_err(|err| err.with_key("load_module"))?;
+            conf.modules.extend(module.load(&yaml)?);
+        }
+        if conf.modules.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Arc::new(conf)))
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+}
+
+impl Lifecycle for ModuleConfig {}
+
+impl ModuleConfig {
+    async fn handle_request(
+        self: Arc<Self>,
+        req: &mut Request,
+        resp: &mut Response,
+    ) -> PostRequestHandler {
+        for module in &self.modules {
+            if module.handle_request(req, resp) == Flow::Exit {
+                return PostRequestHandler::Exit;
+            }
+        }
+        PostRequestHandler::Continue
+    }
+}
+
+/// Options of the modules of `load_module` in the default scope `config_yaml`, so they are
+/// known before the plugins are loaded. The modules are opened once, like when they are used.
+pub(crate) fn module_options(config_yaml: &Value) -> Result<Vec<&'static str>, ConfigError> {
+    let conf: ModuleConfig = config::deserialize(config_yaml)?;
+    let mut options = Vec::new();
+    for path in &conf.load_module {
+        let module = LoadedModule::get(path).map_err(|err| err.with_key("load_module"))?;
+        options.extend(module.options());
+    }
+    Ok(options)
+}
+
+fn load_plugin(
+    config_yaml: &Value,
+    configs: &mut Vec<Option<Plugin>>,
+) -> Result<Option<Plugin>, ConfigError> {
+    let Some(config) = ModuleConfig::load(config_yaml, configs)? else {
+        return Ok(None);
+    };
+    Ok(Some(Plugin {
+        name: "jequi_module",
+        config: config.clone(),
+        handlers: PhaseHandlers {
+            content: RequestHandler(Some(Arc::new({
+                let config = config.clone();
+                move |req: &mut Request, resp: &mut Response| {
+                    config.clone().handle_request(req, resp).boxed()
+                }
+            }))),
+            ..Default::default()
+        },
+    }))
+}
+
+// Functions of `HOST_API`, modules only call them with the request and response passed to
+// `handle_request` and they must not panic
+
+unsafe extern "C" fn request_method(request: *const RawRequest) -> AbiStr {
+    let request = unsafe { &*request.cast::<Request>() };
+    AbiStr::new(request.method.as_bytes())
+}
+
+unsafe extern "C" fn request_uri(request: *const RawRequest) -> AbiStr {
+    let request = unsafe { &*request.cast::<Request>() };
+    AbiStr::new(request.uri.raw().as_bytes())
+}
+
+unsafe extern "C" fn request_header(request: *const RawRequest, name: AbiStr) -> AbiStr {
+    let request = unsafe { &*request.cast::<Request>() };
+    match unsafe { name.as_str() }.and_then(|name| request.get_header(name)) {
+        Some(value) => AbiStr::new(value.as_bytes()),
+        None => AbiStr::NONE,
+    }
+}
+
+unsafe extern "C" fn request_body(request: *const RawRequest) -> AbiStr {
+    let request = unsafe { &*request.cast::<Request>() };
+    // The request keeps the body, it outlives the `Arc` returned
+    match request.try_get_body() {
+        Some(body) => match body.as_deref() {
+            Some(body) => AbiStr::new(body),
+            None => AbiStr::new(b""),
+        },
+        None => AbiStr::NONE,
+    }
+}
+
+unsafe extern "C" fn set_status(response: *mut RawResponse, status: u16) {
+    let response = unsafe { &mut *response.cast::<Response>() };
+    response.status = status as usize;
+}
+
+unsafe extern "C" fn set_header(response: *mut RawResponse, name: AbiStr, value: AbiStr) {
+    let response = unsafe { &mut *response.cast::<Response>() };
+    let (Some(name), Some(value)) = (unsafe { name.as_bytes() }, unsafe { value.as_bytes() })
+    else {
+        return;
+    };
+    match (HeaderName::from_bytes(name), HeaderValue::from_bytes(value)) {
+        (Ok(name), Ok(value)) => {
+            response.headers.insert(name, value);
+        }
+        _ => println!(
+            "[WARN] module set an invalid header: {}",
+            String::from_utf8_lossy(name)
+        ),
+    }
+}
+
+unsafe extern "C" fn write_body(response: *mut RawResponse, bytes: AbiStr) {
+    let response = unsafe { &mut *response.cast::<Response>() };
+    if let Some(bytes) = unsafe { bytes.as_bytes() } {
+        response.body_buffer.extend_from_slice(bytes);
+    }
+}
+
+unsafe extern "C" fn set_error(error: *mut RawError, message: AbiStr) {
+    let error = unsafe { &mut *error.cast::<Option<String>>() };
+    let message = unsafe { message.as_bytes() }.unwrap_or_default();
+    *error = Some(String::from_utf8_lossy(message).into_owned());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::c_void, path::Path, ptr};
+
+    use jequi_module_api::{
+        AbiStr, Flow, HostApi, ModuleDeclaration, RawError, RawRequest, RawResponse,
+    };
+
+    use super::check_declaration;
+
+    unsafe extern "C" fn load(_: &HostApi, _: AbiStr, _: *mut RawError) -> *mut c_void {
+        ptr::null_mut()
+    }
+
+    unsafe extern "C" fn handle_request(
+        _: &HostApi,
+        _: *const c_void,
+        _: *mut RawRequest,
+        _: *mut RawResponse,
+    ) -> Flow {
+        Flow::Continue
+    }
+
+    unsafe extern "C" fn drop(_: *mut c_void) {}
+
+    #[test]
+    fn check_declaration_test() {
+        let mut declaration = ModuleDeclaration {
+            abi_version: jequi_module_api::ABI_VERSION,
+            name: AbiStr::new(b"test"),
+            options: ptr::null(),
+            options_len: 0,
+            load,
+            handle_request,
+            drop,
+        };
+        let path = Path::new("libtest.so");
+        let checked = unsafe { check_declaration(path, &declaration) }.unwrap();
+        assert_eq!(unsafe { checked.name.as_str() }, Some("test"));
+
+        declaration.abi_version += 1;
+        let err = unsafe { check_declaration(path, &declaration) }
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.message,
+            format!(
+                "module libtest.so was built for version {} of the module interface, this server \
+                 supports version {}, rebuild it with the jequi_module_api of this server",
+                declaration.abi_version,
+                declaration.abi_version - 1
+            )
+        );
+        let err = unsafe { check_declaration(path, ptr::null()) }
+            .err()
+            .unwrap();
+        assert_eq!(err.message, "module libtest.so returned no declaration");
+    }
+}
@@ -0,0 +1,283 @@
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use regex::{Captures, Regex, RegexBuilder};
+
+use crate::{PathCaptures, PathMatcher, PathPattern};
+
+// Splits the modifier of a `path` key from the path or regex after it
+fn split_modifier(key: &str) -> (&str, &str) {
+    for modifier in ["^~", "~*", "~", "="] {
+        if let Some(rest) = key.strip_prefix(modifier) {
+            return (modifier, rest.trim_start());
+        }
+    }
+    ("", key)
+}
+
+impl FromStr for PathPattern {
+    type Err = String;
+
+    fn from_str(key: &str) -> Result<Self, Self::Err> {
+        let (modifier, path) = split_modifier(key);
+        if path.is_empty() {
+            return Err(format!("missing path after `{}`", modifier));
+        }
+        let regex = |case_insensitive| {
+            RegexBuilder::new(path)
+                .case_insensitive(case_insensitive)
+                .build()
+                .map_err(|err| format!("invalid regex: {}", err))
+        };
+        Ok(match modifier {
+            "=" => PathPattern::Exact(path.to_string()),
+            "^~" => PathPattern::PriorityPrefix(PathBuf::from(path)),
+            "~" => PathPattern::Regex(regex(false)?),
+            "~*" => PathPattern::Regex(regex(true)?),
+            _ => PathPattern::Prefix(PathBuf::from(path)),
+        })
+    }
+}
+
+impl PathPattern {
+    /// Path of a prefix `path` key without its modifier, `None` for exact and regex keys.
+    pub fn key_prefix(key: &str) -> Option<&str> {
+        match split_modifier(key) {
+            ("" | "^~", path) => Some(path),
+            _ => None,
+        }
+    }
+}
+
+/// Resolves the `.` and `..` segments of a request path and removes repeated slashes, so
+/// `/api/../admin` is matched as `/admin`.
+pub fn normalize(path: &str) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => (),
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    let mut normalized = String::with_capacity(path.len());
+    for segment in &segments {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if normalized.is_empty() || path.ends_with('/') {
+        normalized.push('/');
+    }
+    normalized
+}
+
+impl PathMatcher {
+    /// Adds the `path` block with `key`, the blocks must be added in the configuration order.
+    pub fn push(&mut self, key: PathBuf) -> Result<(), String> {
+        let pattern = key.to_string_lossy().parse()?;
+        self.patterns.push((pattern, key));
+        Ok(())
+    }
+
+    /// Finds the key of the `path` block used for `path`. An exact match is used first, then
+    /// the longest prefix if it is a `^~` one, then the first regex that matches and at last
+    /// the longest prefix.
+    pub fn find(&self, path: &str) -> Option<(&Path, PathCaptures)> {
+        let path = normalize(path);
+        let mut longest_prefix: Option<(usize, &PathPattern, &Path)> = None;
+
+        for (pattern, key) in &self.patterns {
+            match pattern {
+                PathPattern::Exact(exact) if *exact == path => {
+                    return Some((key, PathCaptures::default()));
+                }
+                PathPattern::Prefix(prefix) | PathPattern::PriorityPrefix(prefix)
+                    if Path::new(&path).starts_with(prefix) =>
+                {
+                    let length = prefix.components().count();
+                    if longest_prefix.map_or(true, |(longest, ..)| length > longest) {
+                        longest_prefix = Some((length, pattern, key));
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        if let Some((_, PathPattern::PriorityPrefix(_), key)) = longest_prefix {
+            return Some((key, PathCaptures::default()));
+        }
+
+        for (pattern, key) in &self.patterns {
+            if let PathPattern::Regex(regex) = pattern
+                && let Some(captures) = regex.captures(&path)
+            {
+                return Some((key, PathCaptures::new(regex, captures)));
+            }
+        }
+
+        longest_prefix.map(|(_, _, key)| (key, PathCaptures::default()))
+    }
+}
+
+impl PathCaptures {
+    fn new(regex: &Regex, captures: Captures) -> PathCaptures {
+        PathCaptures {
+            groups: captures
+                .iter()
+                .map(|group| group.map(|group| group.as_str().to_string()))
+                .collect(),
+            names: regex
+                .capture_names()
+                .enumerate()
+                .filter_map(|(i, name)| Some((name?.to_string(), i)))
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    /// Group number `i`, `None` if the group didn't participate in the match.
+    pub fn get(&self, i: usize) -> Option<&str> {
+        self.groups.get(i)?.as_deref()
+    }
+
+    pub fn name(&self, name: &str) -> Option<&str> {
+        self.get(*self.names.get(name)?)
+    }
+
+    /// Replaces `$1` and `$name` in `template` by the captured groups, `$$` is a literal `$`.
+    /// Groups that didn't match are replaced by an empty string.
+    pub fn expand(&self, template: &str) -> String {
+        let mut expanded = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find('$') {
+            expanded.push_str(&rest[..start]);
+            rest = &rest[start + 1..];
+
+            if let Some(after) = rest.strip_prefix('$') {
+                expanded.push('$');
+                rest = after;
+                continue;
+            }
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            let (reference, after) = rest.split_at(end);
+            let group = match reference.parse() {
+                Ok(i) => self.get(i),
+                Err(_) => self.name(reference),
+            };
+            if reference.is_empty() {
+                expanded.push('$');
+            }
+            expanded.push_str(group.unwrap_or_default());
+            rest = after;
+        }
+        expanded.push_str(rest);
+        expanded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use crate::{PathMatcher, PathPattern};
+
+    use super::normalize;
+
+    fn new_matcher(keys: &[&str]) -> PathMatcher {
+        let mut matcher = PathMatcher::default();
+        for key in keys {
+            matcher.push(PathBuf::from(key)).unwrap();
+        }
+        matcher
+    }
+
+    #[test]
+    fn normalize_test() {
+        assert_eq!(normalize("/api/../admin"), "/admin");
+        assert_eq!(normalize("//api/./v1/"), "/api/v1/");
+        assert_eq!(normalize("/../.."), "/");
+        assert_eq!(normalize(""), "/");
+    }
+
+    #[test]
+    fn find_test() {
+        let matcher = new_matcher(&[
+            "/",
+            "/api",
+            "= /api",
+            "~ ^/api/v(\\d+)/",
+            "~* \\.(?<ext>png|jpg)$",
+            "^~ /static",
+            "~ ^/img/(.*)\\.png$",
+        ]);
+        let find = |path| {
+            let (key, captures) = matcher.find(path).unwrap();
+            (key.to_str().unwrap().to_string(), captures)
+        };
+
+        assert_eq!(find("/api").0, "= /api");
+        assert_eq!(find("/api/users").0, "/api");
+        assert_eq!(find("/apiv2").0, "/");
+        assert_eq!(find("/api/../api").0, "= /api");
+        assert_eq!(find("/api/../hello").0, "/");
+
+        let (key, captures) = find("/api/v2/users");
+        assert_eq!(key, "~ ^/api/v(\\d+)/");
+        assert_eq!(captures.get(0), Some("/api/v2/"));
+        assert_eq!(captures.get(1), Some("2"));
+
+        // Regexes are tried in order, the first that matches is used
+        let (key, captures) = find("/img/logo.PNG");
+        assert_eq!(key, "~* \\.(?<ext>png|jpg)$");
+        assert_eq!(captures.name("ext"), Some("PNG"));
+
+        assert_eq!(find("/static/logo.png").0, "^~ /static");
+        assert!(find("/static/logo.png").1.is_empty());
+
+        assert!(new_matcher(&["/api"]).find("/app").is_none());
+        assert_eq!(
+            new_matcher(&["/api", "/api/v1"])
+                .find("/api/v1/x")
+                .unwrap()
+                .0,
+            Path::new("/api/v1")
+        );
+    }
+
+    #[test]
+    fn parse_pattern_test() {
+        assert!(matches!(
+            "=/favicon.ico".parse(),
+            Ok(PathPattern::Exact(path)) if path == "/favicon.ico"
+        ));
+        assert!(matches!("/api".parse(), Ok(PathPattern::Prefix(_))));
+        assert!("~ ^/(api".parse::<PathPattern>().is_err());
+        assert!("~ ".parse::<PathPattern>().is_err());
+
+        assert_eq!(PathPattern::key_prefix("^~ /static"), Some("/static"));
+        assert_eq!(PathPattern::key_prefix("/api"), Some("/api"));
+        assert_eq!(PathPattern::key_prefix("= /api"), None);
+        assert_eq!(PathPattern::key_prefix("~ ^/api"), None);
+    }
+
+    #[test]
+    fn expand_test() {
+        let matcher = new_matcher(&["~ ^/img/(?<name>[^/]+)/(\\d+)?"]);
+        let (_, captures) = matcher.find("/img/logo/").unwrap();
+        assert_eq!(
+            captures.expand("/images/$name-$2.png $$1 $"),
+            "/images/logo-.png $1 $"
+        );
+        assert_eq!(captures.expand("$1.x"), "logo.x");
+    }
+}
@@ -0,0 +1,91 @@
+use std::fmt::{self, Debug};
+
+use crate::{Phase, PhaseHandlers, Plugin, PostRequestHandler, Request, RequestHandler, Response};
+
+impl Phase {
+    pub const ALL: [Phase; 7] = [
+        Phase::PostRead,
+        Phase::Rewrite,
+        Phase::Access,
+        Phase::Content,
+        Phase::HeaderFilter,
+        Phase::BodyFilter,
+        Phase::Log,
+    ];
+
+    /// Phases before the response is complete, `PostRequestHandler::Exit` skips the ones left.
+    pub const REQUEST: [Phase; 4] = [
+        Phase::PostRead,
+        Phase::Rewrite,
+        Phase::Access,
+        Phase::Content,
+    ];
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Phase::PostRead => "post_read",
+            Phase::Rewrite => "rewrite",
+            Phase::Access => "access",
+            Phase::Content => "content",
+            Phase::HeaderFilter => "header_filter",
+            Phase::BodyFilter => "body_filter",
+            Phase::Log => "log",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl PhaseHandlers {
+    /// Request handler of `phase`, `None` for the filter phases, which use `HeaderFilter` and
+    /// `BodyFilter`.
+    pub fn get(&self, phase: Phase) -> Option<&RequestHandler> {
+        match phase {
+            Phase::PostRead => Some(&self.post_read),
+            Phase::Rewrite => Some(&self.rewrite),
+            Phase::Access => Some(&self.access),
+            Phase::Content => Some(&self.content),
+            Phase::HeaderFilter | Phase::BodyFilter => None,
+            Phase::Log => Some(&self.log),
+        }
+    }
+
+    /// Phases the plugin has a handler for.
+    pub fn phases(&self) -> Vec<Phase> {
+        Phase::ALL
+            .into_iter()
+            .filter(|phase| match phase {
+                Phase::HeaderFilter => self.header_filter.is_some(),
+                Phase::BodyFilter => self.body_filter.is_some(),
+                phase => self.get(*phase).is_some_and(|handler| handler.0.is_some()),
+            })
+            .collect()
+    }
+}
+
+impl Debug for PhaseHandlers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.phases()).finish()
+    }
+}
+
+/// Runs the handlers of `phase` of every plugin in `config`, stops at the first one that
+/// doesn't return `PostRequestHandler::Continue` and returns its result.
+pub(crate) async fn run_phase(
+    config: &[Plugin],
+    phase: Phase,
+    request: &mut Request,
+    response: &mut Response,
+) -> PostRequestHandler {
+    let handlers = config
+        .iter()
+        .filter_map(|plugin| plugin.handlers.get(phase)?.0.as_ref());
+    for handler in handlers {
+        match handler(request, response).await {
+            PostRequestHandler::Continue => (),
+            post_handler => return post_handler,
+        }
+    }
+    PostRequestHandler::Continue
+}
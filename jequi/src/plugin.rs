@@ -0,0 +1,84 @@
+use std::sync::Arc;
+
+use crate::{JequiConfig, Plugin};
+
+/// Lists of plugins that can be searched by `get` and `get_mut`, a `ConfigList` or the list of
+/// plugins being loaded, where plugins that aren't used in the scope are `None`.
+pub trait PluginSlot {
+    fn plugin(&self) -> Option<&Plugin>;
+    fn plugin_mut(&mut self) -> Option<&mut Plugin>;
+}
+
+impl PluginSlot for Plugin {
+    fn plugin(&self) -> Option<&Plugin> {
+        Some(self)
+    }
+
+    fn plugin_mut(&mut self) -> Option<&mut Plugin> {
+        Some(self)
+    }
+}
+
+impl PluginSlot for Option<Plugin> {
+    fn plugin(&self) -> Option<&Plugin> {
+        self.as_ref()
+    }
+
+    fn plugin_mut(&mut self) -> Option<&mut Plugin> {
+        self.as_mut()
+    }
+}
+
+/// Config of type `T` in `plugins`, plugins are found by the type of their config so it
+/// doesn't matter which other plugins are loaded. Used by `get_plugin!`.
+pub fn get<T: JequiConfig>(plugins: &[impl PluginSlot]) -> Option<&T> {
+    plugins
+        .iter()
+        .filter_map(PluginSlot::plugin)
+        .find_map(|plugin| plugin.config.as_any().downcast_ref::<T>())
+}
+
+/// Same as `get`, for plugins that change the config of another plugin while they are loaded.
+pub fn get_mut<T: JequiConfig>(plugins: &mut [impl PluginSlot]) -> Option<&mut T> {
+    plugins
+        .iter_mut()
+        .filter_map(PluginSlot::plugin_mut)
+        .find(|plugin| plugin.config.as_any().is::<T>())
+        .map(|plugin| {
+            // The configs are only shared after every plugin of the scope is loaded
+            unsafe { Arc::get_mut_unchecked(&mut plugin.config) }
+                .as_any_mut()
+                .downcast_mut::<T>()
+                .unwrap()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{Config, PhaseHandlers, Plugin};
+
+    use super::{get, get_mut};
+
+    #[test]
+    fn get_plugin_test() {
+        let plugin = |config: Config| Plugin {
+            name: "jequi",
+            config: Arc::new(config),
+            handlers: PhaseHandlers::default(),
+        };
+
+        let plugins = vec![plugin(Config {
+            ip: "1.1.1.1".to_string(),
+            ..Default::default()
+        })];
+        assert_eq!(get::<Config>(&plugins).unwrap().ip, "1.1.1.1");
+        assert!(get::<Config>(&plugins[1..]).is_none());
+
+        let mut loading = vec![None, Some(plugin(Config::default())), None];
+        get_mut::<Config>(&mut loading).unwrap().port = 8080;
+        assert_eq!(get::<Config>(&loading).unwrap().port, 8080);
+        assert!(get_mut::<Config>(&mut [None::<Plugin>, None]).is_none());
+    }
+}
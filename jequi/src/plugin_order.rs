@@ -0,0 +1,92 @@
+/// Name and `require`, `after` and `before` constraints of a plugin, what `sort` orders
+/// plugins by.
+pub trait PluginOrder {
+    fn name(&self) -> &str;
+    fn requires(&self) -> impl Iterator<Item = &str>;
+    fn after(&self) -> impl Iterator<Item = &str>;
+    fn before(&self) -> impl Iterator<Item = &str>;
+}
+
+/// Orders `registrations` so every plugin comes after the ones it requires or is ordered
+/// after, and before the ones it is ordered before. Otherwise plugins are sorted by name, so
+/// the order doesn't depend on how they were linked.
+pub fn sort<T: PluginOrder>(mut registrations: Vec<T>) -> Result<Vec<T>, String> {
+    registrations.sort_by(|a, b| a.name().cmp(b.name()));
+    if let Some(pair) = registrations
+        .windows(2)
+        .find(|pair| pair[0].name() == pair[1].name())
+    {
+        return Err(format!("plugin {} is registered twice", pair[0].name()));
+    }
+
+    let index = |name: &str| {
+        registrations
+            .iter()
+            .position(|registration| registration.name() == name)
+    };
+    let mut dependencies: Vec<Vec<usize>> = vec![Vec::new(); registrations.len()];
+    for (i, registration) in registrations.iter().enumerate() {
+        for required in registration.requires() {
+            let Some(required) = index(required) else {
+                return Err(format!(
+                    "plugin {} requires {}, which isn't linked into the server",
+                    registration.name(),
+                    required
+                ));
+            };
+            dependencies[i].push(required);
+        }
+        // Ordering constraints only apply to the plugins that are linked
+        dependencies[i].extend(registration.after().filter_map(index));
+        for before in registration.before().filter_map(index) {
+            dependencies[before].push(i);
+        }
+    }
+
+    // The first plugin whose dependencies are loaded is always the next one
+    let mut order = Vec::with_capacity(registrations.len());
+    let mut loaded = vec![false; registrations.len()];
+    while order.len() < registrations.len() {
+        let next = (0..registrations.len())
+            .find(|&i| !loaded[i] && dependencies[i].iter().all(|&dependency| loaded[dependency]));
+        let Some(next) = next else {
+            return Err(cycle_error(&registrations, &dependencies, &loaded));
+        };
+        loaded[next] = true;
+        order.push(next);
+    }
+    let mut registrations: Vec<Option<T>> = registrations.into_iter().map(Some).collect();
+    Ok(order
+        .into_iter()
+        .map(|i| registrations[i].take().unwrap())
+        .collect())
+}
+
+// Every plugin left waits for another plugin left, following them always finds a cycle
+fn cycle_error(
+    registrations: &[impl PluginOrder],
+    dependencies: &[Vec<usize>],
+    loaded: &[bool],
+) -> String {
+    let mut path = vec![loaded.iter().position(|loaded| !loaded).unwrap()];
+    loop {
+        let current = *path.last().unwrap();
+        let dependency = *dependencies[current]
+            .iter()
+            .find(|&&dependency| !loaded[dependency])
+            .unwrap();
+        if let Some(start) = path.iter().position(|&plugin| plugin == dependency) {
+            let mut cycle: Vec<&str> = path[start..]
+                .iter()
+                .rev()
+                .map(|&plugin| registrations[plugin].name())
+                .collect();
+            cycle.push(registrations[current].name());
+            return format!(
+                "plugin dependency cycle, {} must be loaded before itself",
+                cycle.join(" -> ")
+            );
+        }
+        path.push(dependency);
+    }
+}
@@ -0,0 +1,307 @@
+use core::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+use serde::{de, Deserialize};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+// Longest possible v1 header, including the CRLF
+const V1_MAX_LENGTH: usize = 107;
+
+/// Network in CIDR notation (`10.0.0.0/8`), a single address is accepted as well.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid address in cidr: {}", s))?;
+        let max_prefix = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max_prefix)
+                .ok_or(format!("invalid prefix in cidr: {}", s))?,
+            None => max_prefix,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct CidrVisitor;
+
+        impl<'de> de::Visitor<'de> for CidrVisitor {
+            type Value = Cidr;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("Cidr")
+            }
+
+            fn visit_str<E>(self, v: &str) -> std::result::Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                v.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(CidrVisitor {})
+    }
+}
+
+crate::config_type!(Cidr, "string", {"type": "string"}, |cidr| format!(
+    "{}/{}",
+    cidr.addr, cidr.prefix
+));
+
+fn invalid_header<E: ToString>(err: E) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("invalid proxy protocol header: {}", err.to_string()),
+    )
+}
+
+/// Reads a PROXY protocol v1 or v2 header from the start of `stream`, returns the source and
+/// destination addresses of the proxied connection or `None` if the proxy didn't send them
+/// (health checks for example), in that case the connection addresses should be kept.
+///
+/// Only the header is consumed so the stream can be used for TLS or HTTP afterwards.
+pub async fn read_header<T: AsyncRead + Unpin>(
+    stream: &mut T,
+) -> Result<Option<(SocketAddr, SocketAddr)>> {
+    // The shortest v1 header ("PROXY UNKNOWN\r\n") is longer than the v2 signature
+    let mut header = vec![0; V2_SIGNATURE.len()];
+    stream.read_exact(&mut header).await?;
+
+    if header == V2_SIGNATURE {
+        return read_v2(stream).await;
+    }
+    if !header.starts_with(b"PROXY ") {
+        return Err(invalid_header("missing signature"));
+    }
+
+    while !header.ends_with(b"\r\n") {
+        if header.len() == V1_MAX_LENGTH {
+            return Err(invalid_header("v1 header too long"));
+        }
+        header.push(stream.read_u8().await?);
+    }
+
+    parse_v1(&header[..header.len() - 2])
+}
+
+fn parse_v1(header: &[u8]) -> Result<Option<(SocketAddr, SocketAddr)>> {
+    let header = std::str::from_utf8(header).map_err(invalid_header)?;
+    let mut fields = header.split(' ').skip(1);
+
+    match fields.next() {
+        Some("TCP4") | Some("TCP6") => {}
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(invalid_header("unknown v1 protocol")),
+    }
+
+    let fields: Vec<&str> = fields.collect();
+    let [src_ip, dst_ip, src_port, dst_port] = fields[..] else {
+        return Err(invalid_header("wrong number of v1 fields"));
+    };
+    let src_ip: IpAddr = src_ip.parse().map_err(invalid_header)?;
+    let dst_ip: IpAddr = dst_ip.parse().map_err(invalid_header)?;
+    let src_port: u16 = src_port.parse().map_err(invalid_header)?;
+    let dst_port: u16 = dst_port.parse().map_err(invalid_header)?;
+
+    Ok(Some((
+        SocketAddr::new(src_ip, src_port),
+        SocketAddr::new(dst_ip, dst_port),
+    )))
+}
+
+async fn read_v2<T: AsyncRead + Unpin>(stream: &mut T) -> Result<Option<(SocketAddr, SocketAddr)>> {
+    let version_command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let length = stream.read_u16().await?;
+    let mut addresses = vec![0; length as usize];
+    stream.read_exact(&mut addresses).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid_header("unsupported version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL, sent by the proxy for its own connections
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid_header("unknown v2 command")),
+    }
+
+    let port = |bytes: &[u8]| u16::from_be_bytes([bytes[0], bytes[1]]);
+    match family >> 4 {
+        1 if addresses.len() >= 12 => {
+            let src_ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[0..4]).unwrap());
+            let dst_ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[4..8]).unwrap());
+            Ok(Some((
+                SocketAddr::new(src_ip.into(), port(&addresses[8..10])),
+                SocketAddr::new(dst_ip.into(), port(&addresses[10..12])),
+            )))
+        }
+        2 if addresses.len() >= 36 => {
+            let src_ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[0..16]).unwrap());
+            let dst_ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[16..32]).unwrap());
+            Ok(Some((
+                SocketAddr::new(src_ip.into(), port(&addresses[32..34])),
+                SocketAddr::new(dst_ip.into(), port(&addresses[34..36])),
+            )))
+        }
+        1 | 2 => Err(invalid_header("v2 addresses too short")),
+        // Unspecified or unix sockets, there is no address to use
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::io::AsyncReadExt;
+
+    use super::{read_header, Cidr, V2_SIGNATURE};
+
+    #[tokio::test]
+    async fn read_v1_header_test() {
+        let mut stream: &[u8] = b"PROXY TCP4 192.168.0.1 10.0.0.1 56324 443\r\nGET / HTTP/1.1\r\n";
+        let addresses = read_header(&mut stream).await.unwrap();
+        assert_eq!(
+            addresses,
+            Some((
+                "192.168.0.1:56324".parse().unwrap(),
+                "10.0.0.1:443".parse().unwrap()
+            ))
+        );
+        let mut rest = String::new();
+        stream.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "GET / HTTP/1.1\r\n");
+
+        let mut stream: &[u8] = b"PROXY TCP6 ::1 ::2 56324 443\r\n";
+        let addresses = read_header(&mut stream).await.unwrap();
+        assert_eq!(
+            addresses,
+            Some(("[::1]:56324".parse().unwrap(), "[::2]:443".parse().unwrap()))
+        );
+
+        let mut stream: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_header(&mut stream).await.unwrap(), None);
+
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\nHost: jequi\r\n";
+        assert!(read_header(&mut stream).await.is_err());
+
+        let mut stream: &[u8] = b"PROXY TCP4 192.168.0.1 10.0.0.1 56324\r\n";
+        assert!(read_header(&mut stream).await.is_err());
+
+        let stream = [b"PROXY TCP4 ".as_slice(), &[b'1'; 200]].concat();
+        assert!(read_header(&mut stream.as_slice()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn read_v2_header_test() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x21, 0x11, 0, 12]);
+        header.extend([192, 168, 0, 1, 10, 0, 0, 1]);
+        header.extend(56324u16.to_be_bytes());
+        header.extend(443u16.to_be_bytes());
+        header.extend(b"GET");
+        let mut stream = header.as_slice();
+        let addresses = read_header(&mut stream).await.unwrap();
+        assert_eq!(
+            addresses,
+            Some((
+                "192.168.0.1:56324".parse().unwrap(),
+                "10.0.0.1:443".parse().unwrap()
+            ))
+        );
+        assert_eq!(stream, b"GET");
+
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x21, 0x21, 0, 36]);
+        header.extend([0; 15]);
+        header.push(1);
+        header.extend([0; 15]);
+        header.push(2);
+        header.extend(56324u16.to_be_bytes());
+        header.extend(443u16.to_be_bytes());
+        let addresses = read_header(&mut header.as_slice()).await.unwrap();
+        assert_eq!(
+            addresses,
+            Some((
+                "[::1]:56324".parse::<SocketAddr>().unwrap(),
+                "[::2]:443".parse().unwrap()
+            ))
+        );
+
+        // LOCAL command
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x20, 0x00, 0, 0]);
+        assert_eq!(read_header(&mut header.as_slice()).await.unwrap(), None);
+
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x21, 0x11, 0, 4, 192, 168, 0, 1]);
+        assert!(read_header(&mut header.as_slice()).await.is_err());
+    }
+
+    #[test]
+    fn cidr_test() {
+        let cidr: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(cidr.contains(&"10.1.2.3".parse().unwrap()));
+        assert!(cidr.contains(&"::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!cidr.contains(&"11.1.2.3".parse().unwrap()));
+        assert!(!cidr.contains(&"::1".parse().unwrap()));
+
+        let cidr: Cidr = "127.0.0.1".parse().unwrap();
+        assert!(cidr.contains(&"127.0.0.1".parse().unwrap()));
+        assert!(!cidr.contains(&"127.0.0.2".parse().unwrap()));
+
+        let cidr: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(cidr.contains(&"8.8.8.8".parse().unwrap()));
+
+        let cidr: Cidr = "fd00::/8".parse().unwrap();
+        assert!(cidr.contains(&"fd12::1".parse().unwrap()));
+        assert!(!cidr.contains(&"fe80::1".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("jequi/8".parse::<Cidr>().is_err());
+    }
+}
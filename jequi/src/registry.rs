@@ -0,0 +1,226 @@
+use std::sync::OnceLock;
+
+use serde_yaml::Value;
+
+use crate::{
+    load_plugin,
+    plugin_order::{sort, PluginOrder},
+    Config, ConfigError, ConfigList, ConfigMap, ConfigOptions, ConnInfo, Lifecycle, LifecycleHooks,
+    OptionInfo, Plugin, PluginLifecycle, PluginRegistration,
+};
+
+inventory::collect!(PluginRegistration);
+
+// Sorted once, the registered plugins can't change while the server runs
+static PLUGINS: OnceLock<Result<Vec<&'static PluginRegistration>, String>> = OnceLock::new();
+
+impl PluginOrder for &PluginRegistration {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn requires(&self) -> impl Iterator<Item = &str> {
+        self.requires.iter().copied()
+    }
+
+    fn after(&self) -> impl Iterator<Item = &str> {
+        self.after.iter().copied()
+    }
+
+    fn before(&self) -> impl Iterator<Item = &str> {
+        self.before.iter().copied()
+    }
+}
+
+/// Plugins linked into the server in the order they are loaded and their handlers run, the
+/// main config isn't included.
+pub fn plugins() -> Result<&'static [&'static PluginRegistration], ConfigError> {
+    PLUGINS
+        .get_or_init(|| sort(inventory::iter::<PluginRegistration>.into_iter().collect()))
+        .as_deref()
+        .map_err(ConfigError::new)
+}
+
+/// Loads the main config and every registered plugin for a scope, passed to `ConfigMap::load`.
+pub fn load_plugins(config: &Value) -> Result<ConfigList, ConfigError> {
+    let registrations = plugins()?;
+    let mut plugins: Vec<Option<Plugin>> = Vec::with_capacity(registrations.len() + 1);
+    plugins.resize_with(registrations.len() + 1, Default::default);
+    let plugin = load_plugin(config, &mut plugins)
+        .map_err(|err| err.with_plugin("jequi"))?
+        .expect("main config is required");
+    plugins[0] = Some(plugin);
+    for (i, registration) in registrations.iter().enumerate() {
+        plugins[i + 1] = (registration.load)(config, &mut plugins)
+            .map_err(|err| err.with_plugin(registration.name))?;
+    }
+    Ok(plugins.into_iter().flatten().collect())
+}
+
+/// Options of the main config and of every registered plugin, used to generate the
+/// configuration reference and the JSON Schema.
+pub fn config_options() -> Result<Vec<(&'static str, Vec<OptionInfo>)>, ConfigError> {
+    let plugins = plugins()?.iter();
+    Ok([("jequi", Config::options())]
+        .into_iter()
+        .chain(plugins.map(|plugin| (plugin.name, (plugin.options)())))
+        .collect())
+}
+
+// Hooks only run for configs loaded by `load_plugins`, which fails when the plugins can't be
+// sorted
+fn hooks() -> impl DoubleEndedIterator<Item = LifecycleHooks> {
+    let plugins = plugins()
+        .expect("plugins are sorted when the config is loaded")
+        .iter();
+    [LifecycleHooks::of::<Config>()]
+        .into_iter()
+        .chain(plugins.map(|plugin| plugin.lifecycle))
+}
+
+impl Lifecycle for PluginLifecycle {
+    fn init(config_map: &ConfigMap) {
+        hooks().for_each(|hooks| (hooks.init)(config_map));
+    }
+
+    fn commit(config_map: &ConfigMap) {
+        hooks().for_each(|hooks| (hooks.commit)(config_map));
+    }
+
+    fn teardown(config_map: &ConfigMap) {
+        hooks().rev().for_each(|hooks| (hooks.teardown)(config_map));
+    }
+
+    fn shutdown(config_map: &ConfigMap) {
+        hooks().rev().for_each(|hooks| (hooks.shutdown)(config_map));
+    }
+
+    fn connection_open(conn_info: &ConnInfo, config_map: &ConfigMap) {
+        hooks().for_each(|hooks| (hooks.connection_open)(conn_info, config_map));
+    }
+
+    fn connection_close(conn_info: &ConnInfo, config_map: &ConfigMap) {
+        hooks()
+            .rev()
+            .for_each(|hooks| (hooks.connection_close)(conn_info, config_map));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_yaml::Value;
+
+    use crate::{
+        config::LoadPlugin, Config, ConfigError, ConfigOptions, LifecycleHooks, PluginRegistration,
+    };
+
+    use super::{config_options, load_plugins, plugins, sort};
+
+    const LOAD_TEST_PLUGIN: LoadPlugin = |config, _configs| match config.get("test_plugin") {
+        Some(_) => Err(ConfigError::new("test plugin error")),
+        None => Ok(None),
+    };
+
+    const fn registration(
+        name: &'static str,
+        requires: &'static [&'static str],
+        after: &'static [&'static str],
+        before: &'static [&'static str],
+    ) -> PluginRegistration {
+        PluginRegistration {
+            name,
+            load: LOAD_TEST_PLUGIN,
+            options: Config::options,
+            requires,
+            after,
+            before,
+            lifecycle: LifecycleHooks::of::<Config>(),
+        }
+    }
+
+    inventory::submit!(registration("test_plugin", &[], &[], &["missing"]));
+
+    fn names(registrations: &[PluginRegistration]) -> Result<Vec<&'static str>, String> {
+        let order = sort(registrations.iter().collect())?;
+        Ok(order.iter().map(|registration| registration.name).collect())
+    }
+
+    #[test]
+    fn sort_test() {
+        // Plugins move after what they need, the rest are sorted by name
+        let order = names(&[
+            registration("serve_static", &[], &[], &["proxy"]),
+            registration("proxy", &[], &[], &[]),
+            registration("go", &["proxy"], &[], &[]),
+            registration("acme", &[], &[], &[]),
+        ]);
+        assert_eq!(order.unwrap(), ["acme", "serve_static", "proxy", "go"]);
+
+        // Several plugins can require the same one, ordering constraints with plugins that
+        // aren't linked are ignored
+        let order = names(&[
+            registration("a", &["c"], &[], &[]),
+            registration("b", &["c", "d"], &["e", "missing"], &[]),
+            registration("c", &[], &[], &[]),
+            registration("d", &[], &[], &[]),
+            registration("e", &[], &[], &["c", "missing"]),
+        ]);
+        assert_eq!(order.unwrap(), ["d", "e", "c", "a", "b"]);
+    }
+
+    #[test]
+    fn sort_error_test() {
+        let order = names(&[
+            registration("a", &["b"], &[], &[]),
+            registration("b", &[], &["c"], &[]),
+            registration("c", &["b"], &[], &["a"]),
+        ]);
+        assert_eq!(
+            order.unwrap_err(),
+            "plugin dependency cycle, c -> b -> c must be loaded before itself"
+        );
+        let order = names(&[registration("a", &["a"], &[], &[])]);
+        assert_eq!(
+            order.unwrap_err(),
+            "plugin dependency cycle, a -> a must be loaded before itself"
+        );
+        let order = names(&[
+            registration("a", &[], &[], &[]),
+            registration("b", &["c"], &[], &[]),
+        ]);
+        assert_eq!(
+            order.unwrap_err(),
+            "plugin b requires c, which isn't linked into the server"
+        );
+        let order = names(&[
+            registration("a", &[], &[], &[]),
+            registration("a", &[], &[], &[]),
+        ]);
+        assert_eq!(order.unwrap_err(), "plugin a is registered twice");
+    }
+
+    #[test]
+    fn registry_test() {
+        let registered: Vec<_> = plugins()
+            .unwrap()
+            .iter()
+            .map(|plugin| plugin.name)
+            .collect();
+        assert_eq!(registered, ["jequi_module", "test_plugin"]);
+        let options: Vec<_> = config_options()
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(options, ["jequi", "jequi_module", "test_plugin"]);
+
+        let config: Value = serde_yaml::from_str("port: 8080").unwrap();
+        let plugins = load_plugins(&config).unwrap();
+        assert_eq!(plugins.len(), 1);
+        assert_eq!(plugins[0].name, "jequi");
+
+        let config: Value = serde_yaml::from_str("test_plugin: true").unwrap();
+        let err = load_plugins(&config).unwrap_err();
+        assert_eq!(err.plugin, Some("test_plugin"));
+    }
+}
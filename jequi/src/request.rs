@@ -3,10 +3,15 @@ use std::sync::Arc;
 
 use chrono::Utc;
 use http::{HeaderMap, HeaderValue};
+use plugins::get_plugin;
 
 use crate::body::GetBody;
+use crate::phase::run_phase;
 use crate::{body::RequestBody, Request};
-use crate::{ConfigMap, PostRequestHandler, Response, Uri};
+use crate::{Config, ConfigMap, ConnInfo, PathCaptures, Phase, PostRequestHandler, Response, Uri};
+
+/// Path of the ACME http-01 challenges, it is never redirected to https.
+pub const ACME_CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
 
 impl From<String> for Uri {
     fn from(item: String) -> Self {
@@ -28,6 +33,48 @@ impl Uri {
         it.next();
         it.next()
     }
+
+    /// Value of the first query parameter called `name`, percent decoded.
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        self.query_string()?.split('&').find_map(|param| {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            (key == name).then(|| percent_decode(value))
+        })
+    }
+}
+
+// Decodes the `%XX` escapes and `+` as a space, invalid escapes are kept as they are
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|hex| bytes[i] == b'%' && hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (escaped, bytes[i]) {
+            (Some(byte), _) => {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+            (None, b'+') => decoded.push(b' '),
+            (None, byte) => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Host of a `Host` header without the port, IPv6 addresses like `[::1]:8443` keep their
+/// brackets.
+pub(crate) fn host_without_port(host: &str) -> &str {
+    let end = match host.starts_with('[') {
+        true => host.find(']').map_or(host.len(), |end| end + 1),
+        false => host.find(':').unwrap_or(host.len()),
+    };
+    &host[..end]
 }
 
 impl Request {
@@ -38,6 +85,9 @@ impl Request {
             headers: HeaderMap::new(),
             host: None,
             body: Arc::new(RequestBody::default()),
+            conn_info: Arc::new(ConnInfo::default()),
+            path_captures: PathCaptures::default(),
+            route: None,
         }
     }
 
@@ -60,6 +110,8 @@ impl Request {
             })
     }
 
+    /// Runs the phases of the request until the response is ready to be sent, the `Log` phase
+    /// runs in `log` once it is sent.
     pub async fn handle_request(
         &mut self,
         response: &mut Response,
@@ -71,20 +123,111 @@ impl Request {
             &Utc::now().format("%a, %e %b %Y %T GMT").to_string(),
         );
 
-        let config = config_map.get_config_for_request(self.host.as_deref(), Some(self.uri.path()));
+        let main_conf = &config_map.config;
+        let main_conf = get_plugin!(main_conf, jequi).unwrap();
+        if let Some(admin_path) = &main_conf.admin_path
+            && let Some(endpoint) = self.uri.path().strip_prefix(admin_path.as_str())
+            && (endpoint.is_empty() || endpoint.starts_with('/'))
+        {
+            self.handle_admin(endpoint, main_conf, &config_map, response);
+            return PostRequestHandler::Exit;
+        }
+
+        let (config, path_captures, route) = config_map.match_route(self);
+        self.path_captures = path_captures;
+        self.route = Some(route);
+
+        let conf = get_plugin!(config, jequi).unwrap();
+        if self.conn_info.tls
+            && let Some(max_age) = conf.hsts_max_age
+        {
+            response.set_header("strict-transport-security", &format!("max-age={}", max_age));
+        }
+        if let Some(location) = self.https_redirect_location(conf) {
+            response.status = conf.redirect_to_https_status;
+            response.set_header("location", &location);
+            return PostRequestHandler::Continue;
+        }
 
-        for handle_plugin in config.iter().map(|x| &x.request_handler.0).flat_map(|x| x) {
-            match handle_plugin(self, response).await {
-                PostRequestHandler::Continue => (),
-                post_handler => return post_handler,
+        let mut post_handler = PostRequestHandler::Continue;
+        for phase in Phase::REQUEST {
+            if !matches!(post_handler, PostRequestHandler::Continue) {
+                break;
             }
+            post_handler = run_phase(config, phase, self, response).await;
         }
 
         if response.status == 0 {
             response.status = 200;
         }
 
-        PostRequestHandler::Continue
+        post_handler
+    }
+
+    /// Runs the `Log` phase, once the response is sent, with the config that handled the
+    /// request.
+    pub async fn log(&mut self, response: &mut Response, config_map: &ConfigMap) {
+        let config = config_map.request_config(self);
+        run_phase(config, Phase::Log, self, response).await;
+    }
+
+    /// Serves the admin endpoints, `endpoint` is the path after `admin_path`.
+    fn handle_admin(
+        &self,
+        endpoint: &str,
+        conf: &Config,
+        config_map: &ConfigMap,
+        response: &mut Response,
+    ) {
+        let allowed = self.conn_info.remote_addr.is_some_and(|addr| {
+            conf.admin_clients
+                .iter()
+                .any(|cidr| cidr.contains(&addr.ip()))
+        });
+        if !allowed {
+            response.status = 403;
+            return;
+        }
+
+        let body = match endpoint {
+            "/explain-route" => config_map.explain_route(
+                self.uri.query_param("listener").as_deref(),
+                self.uri.query_param("host").as_deref(),
+                self.uri.query_param("path").as_deref(),
+            ),
+            _ => {
+                response.status = 404;
+                return;
+            }
+        };
+        let body = match body {
+            Ok(explanation) => {
+                response.status = 200;
+                explanation.to_string()
+            }
+            Err(err) => {
+                response.status = 500;
+                err.to_string()
+            }
+        };
+        response.set_header("content-type", "text/plain; charset=utf-8");
+        response.write_body(body.as_bytes()).unwrap();
+    }
+
+    /// Url to redirect a plaintext request to when `redirect_to_https` is enabled.
+    fn https_redirect_location(&self, conf: &Config) -> Option<String> {
+        if !conf.redirect_to_https
+            || self.conn_info.tls
+            || self.uri.path().starts_with(ACME_CHALLENGE_PATH)
+        {
+            return None;
+        }
+
+        let host = host_without_port(self.host.as_deref()?);
+        Some(match conf.redirect_to_https_port {
+            443 => format!("https://{}{}", host, self.uri.raw()),
+            port => format!("https://{}:{}{}", host, port, self.uri.raw()),
+        })
     }
 
     pub fn get_header(&self, header: &str) -> Option<&HeaderValue> {
@@ -105,3 +248,260 @@ impl Default for Request {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::{
+        load_plugin, Config, ConfigMap, ConnInfo, Phase, PhaseHandlers, Plugin, PostRequestHandler,
+        Request, RequestHandler, Response, Uri,
+    };
+
+    use super::host_without_port;
+
+    fn config_map(conf: Config) -> Arc<ConfigMap> {
+        Arc::new(ConfigMap {
+            config: vec![Plugin {
+                name: "jequi",
+                config: Arc::new(conf),
+                handlers: PhaseHandlers::default(),
+            }],
+            ..Default::default()
+        })
+    }
+
+    fn request(uri: &str, tls: bool) -> Request {
+        let mut request = Request::new();
+        request.uri = Uri::from(uri.to_string());
+        request.host = Some("jequi.com:80".to_string());
+        request.conn_info = Arc::new(ConnInfo {
+            tls,
+            ..Default::default()
+        });
+        request
+    }
+
+    #[tokio::test]
+    async fn redirect_to_https_test() {
+        let config_map = config_map(Config {
+            redirect_to_https: true,
+            hsts_max_age: Some(3600),
+            ..Default::default()
+        });
+
+        // The phases are skipped but the connection keeps going as after any response
+        let mut response = Response::new();
+        let post_handler = request("/hello?name=jequi", false)
+            .handle_request(&mut response, config_map.clone())
+            .await;
+        assert!(matches!(post_handler, PostRequestHandler::Continue));
+        assert_eq!(response.status, 301);
+        assert_eq!(
+            response.headers.get("location").unwrap(),
+            "https://jequi.com/hello?name=jequi"
+        );
+        assert!(response.headers.get("strict-transport-security").is_none());
+
+        let mut response = Response::new();
+        request("/.well-known/acme-challenge/token", false)
+            .handle_request(&mut response, config_map.clone())
+            .await;
+        assert_eq!(response.status, 200);
+        assert!(response.headers.get("location").is_none());
+
+        let mut response = Response::new();
+        request("/hello", true)
+            .handle_request(&mut response, config_map)
+            .await;
+        assert_eq!(response.status, 200);
+        assert!(response.headers.get("location").is_none());
+        assert_eq!(
+            response.headers.get("strict-transport-security").unwrap(),
+            "max-age=3600"
+        );
+
+        let config_map = self::config_map(Config {
+            redirect_to_https: true,
+            redirect_to_https_status: 308,
+            redirect_to_https_port: 8443,
+            ..Default::default()
+        });
+        let mut response = Response::new();
+        request("/hello", false)
+            .handle_request(&mut response, config_map.clone())
+            .await;
+        assert_eq!(response.status, 308);
+        assert_eq!(
+            response.headers.get("location").unwrap(),
+            "https://jequi.com:8443/hello"
+        );
+
+        let mut request = request("/hello", false);
+        request.host = Some("[::1]:80".to_string());
+        let mut response = Response::new();
+        request.handle_request(&mut response, config_map).await;
+        assert_eq!(
+            response.headers.get("location").unwrap(),
+            "https://[::1]:8443/hello"
+        );
+    }
+
+    #[test]
+    fn host_without_port_test() {
+        assert_eq!(host_without_port("jequi.com:8080"), "jequi.com");
+        assert_eq!(host_without_port("jequi.com"), "jequi.com");
+        assert_eq!(host_without_port("[::1]:8443"), "[::1]");
+        assert_eq!(host_without_port("[::1]"), "[::1]");
+    }
+
+    #[tokio::test]
+    async fn admin_test() {
+        let config_map = Arc::new(
+            ConfigMap::load("test/admin.conf", |val| {
+                Ok(vec![load_plugin(val, &mut Vec::new())?.unwrap()])
+            })
+            .unwrap(),
+        );
+        let admin_request = |uri: &str, addr: &str| {
+            let mut request = request(uri, false);
+            request.conn_info = Arc::new(ConnInfo {
+                remote_addr: Some(addr.parse().unwrap()),
+                ..Default::default()
+            });
+            request
+        };
+
+        let mut response = Response::new();
+        admin_request(
+            "/.jequi/explain-route?host=jequi.com&path=%2F",
+            "10.1.2.3:4000",
+        )
+        .handle_request(&mut response, config_map.clone())
+        .await;
+        assert_eq!(response.status, 200);
+        let body = String::from_utf8(response.body_buffer).unwrap();
+        assert!(body.starts_with("# scope: host jequi.com\n"));
+        assert!(body.contains("# host jequi.com (test/admin.conf:6:9)\nchunk_size: 10\n"));
+
+        // Only `admin_clients` can use it, the default loopback networks are replaced
+        let mut response = Response::new();
+        admin_request("/.jequi/explain-route", "127.0.0.1:4000")
+            .handle_request(&mut response, config_map.clone())
+            .await;
+        assert_eq!(response.status, 403);
+        assert!(response.body_buffer.is_empty());
+
+        let mut response = Response::new();
+        admin_request("/.jequi/unknown", "10.1.2.3:4000")
+            .handle_request(&mut response, config_map.clone())
+            .await;
+        assert_eq!(response.status, 404);
+
+        // Paths that only start with `admin_path` are handled by the plugins
+        let mut response = Response::new();
+        admin_request("/.jequiadmin", "10.1.2.3:4000")
+            .handle_request(&mut response, config_map)
+            .await;
+        assert_eq!(response.status, 200);
+        assert!(response.body_buffer.is_empty());
+    }
+
+    #[test]
+    fn query_param_test() {
+        let uri = Uri::from("/?path=%2Fapi%2Fa%20b&q=a+b&bad=%zz%2&empty&utf8=%C3%A9".to_string());
+        assert_eq!(uri.query_param("path").as_deref(), Some("/api/a b"));
+        assert_eq!(uri.query_param("q").as_deref(), Some("a b"));
+        assert_eq!(uri.query_param("bad").as_deref(), Some("%zz%2"));
+        assert_eq!(uri.query_param("empty").as_deref(), Some(""));
+        assert_eq!(uri.query_param("utf8").as_deref(), Some("é"));
+        assert_eq!(uri.query_param("missing"), None);
+    }
+
+    type Calls = Arc<Mutex<Vec<String>>>;
+
+    // Handler that records its call as `plugin:phase` and returns `result`
+    fn handler(
+        calls: &Calls,
+        plugin: &'static str,
+        phase: Phase,
+        result: fn() -> PostRequestHandler,
+    ) -> RequestHandler {
+        let calls = calls.clone();
+        RequestHandler(Some(Arc::new(move |_, _| {
+            calls.lock().unwrap().push(format!("{}:{}", plugin, phase));
+            Box::pin(async move { result() })
+        })))
+    }
+
+    #[tokio::test]
+    async fn phases_test() {
+        let calls = Calls::default();
+        let continue_ = || PostRequestHandler::Continue;
+        let exit = || PostRequestHandler::Exit;
+        let plugins = |access: fn() -> PostRequestHandler| {
+            vec![
+                Plugin {
+                    name: "jequi",
+                    config: Arc::new(Config::default()),
+                    handlers: PhaseHandlers::default(),
+                },
+                Plugin {
+                    name: "first",
+                    config: Arc::new(Config::default()),
+                    handlers: PhaseHandlers {
+                        content: handler(&calls, "first", Phase::Content, continue_),
+                        access: handler(&calls, "first", Phase::Access, access),
+                        log: handler(&calls, "first", Phase::Log, continue_),
+                        ..Default::default()
+                    },
+                },
+                Plugin {
+                    name: "second",
+                    config: Arc::new(Config::default()),
+                    handlers: PhaseHandlers {
+                        post_read: handler(&calls, "second", Phase::PostRead, continue_),
+                        access: handler(&calls, "second", Phase::Access, continue_),
+                        ..Default::default()
+                    },
+                },
+            ]
+        };
+
+        // Phases run in order, and the plugins in their order inside each phase
+        let config_map = Arc::new(ConfigMap {
+            config: plugins(continue_),
+            ..Default::default()
+        });
+        let mut request = request("/", false);
+        let mut response = Response::new();
+        let post_handler = request
+            .handle_request(&mut response, config_map.clone())
+            .await;
+        assert!(matches!(post_handler, PostRequestHandler::Continue));
+        request.log(&mut response, &config_map).await;
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                "second:post_read",
+                "first:access",
+                "second:access",
+                "first:content",
+                "first:log",
+            ]
+        );
+
+        // `Exit` skips the request phases left
+        calls.lock().unwrap().clear();
+        let config_map = ConfigMap {
+            config: plugins(exit),
+            ..Default::default()
+        };
+        let mut response = Response::new();
+        let post_handler = request
+            .handle_request(&mut response, Arc::new(config_map))
+            .await;
+        assert!(matches!(post_handler, PostRequestHandler::Exit));
+        assert_eq!(*calls.lock().unwrap(), ["second:post_read", "first:access"]);
+    }
+}
@@ -47,7 +47,9 @@ mod tests {
     use http::HeaderMap;
     use tokio::io::{AsyncReadExt, BufStream};
 
-    use crate::{body::RequestBody, http1::Http1Conn, Request, Response, Uri};
+    use crate::{
+        body::RequestBody, http1::Http1Conn, ConnInfo, PathCaptures, Request, Response, Uri,
+    };
 
     fn new_response(
         headers: HeaderMap,
@@ -65,12 +67,16 @@ mod tests {
                 headers: HeaderMap::new(),
                 host: None,
                 body: Arc::new(RequestBody::default()),
+                conn_info: Arc::new(ConnInfo::default()),
+                path_captures: PathCaptures::default(),
+                route: None,
             },
             response: Response {
                 status,
                 headers,
                 body_buffer: body,
             },
+            conn_info: Arc::new(ConnInfo::default()),
         }
     }
 
@@ -1,25 +1,44 @@
 use core::fmt;
 use plugins::get_plugin;
 use serde::{de, Deserialize};
-use std::path::PathBuf;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use tokio::io::{AsyncRead, AsyncWrite};
 
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::ocsp::{OcspCertId, OcspFlag, OcspResponse, OcspResponseStatus};
 use openssl::pkey::{PKey, Private};
 use openssl::ssl::{
     AlpnError, NameType, SniError, Ssl, SslAcceptor, SslAlert, SslContextBuilder, SslMethod, SslRef,
 };
+use openssl::stack::Stack;
+use openssl::x509::store::X509StoreBuilder;
 use openssl::x509::X509;
 
 use tokio_openssl::SslStream;
 
-use crate::{AsyncRWSend, AsyncRWSendBuf, ConfigMap};
-
-use crate as jequi;
+use crate::{AsyncRWSend, AsyncRWSendBuf, ConfigMap, ConnInfo, ListenConfig};
 
 #[derive(Clone, Debug)]
-pub struct SslKeyConfig(PKey<Private>);
+pub struct SslKeyConfig(PKey<Private>, Option<PathBuf>);
+
+impl SslKeyConfig {
+    pub fn from_pem(pem: &[u8]) -> Result<Self, ErrorStack> {
+        PKey::private_key_from_pem(pem).map(|key| SslKeyConfig(key, None))
+    }
+
+    pub fn key(&self) -> &PKey<Private> {
+        &self.0
+    }
+
+    /// File the key was loaded from.
+    pub fn path(&self) -> Option<&Path> {
+        self.1.as_deref()
+    }
+}
 
 impl<'de> Deserialize<'de> for SslKeyConfig {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
@@ -51,9 +70,9 @@ impl<'de> Deserialize<'de> for SslKeyConfig {
                     return Err(E::custom(format!("path doesn't exist: {}", path.display())));
                 }
 
-                let content = std::fs::read(path).unwrap();
+                let content = std::fs::read(&path).map_err(|err| E::custom(err.to_string()))?;
                 PKey::private_key_from_pem(&content)
-                    .map(|key| SslKeyConfig(key))
+                    .map(|key| SslKeyConfig(key, Some(path)))
                     .map_err(|err| E::custom(err.to_string()))
             }
         }
@@ -62,6 +81,8 @@ impl<'de> Deserialize<'de> for SslKeyConfig {
     }
 }
 
+crate::config_type!(SslKeyConfig, "string", {"type": "string"}, |key| key.path());
+
 impl PartialEq for SslKeyConfig {
     fn eq(&self, other: &Self) -> bool {
         self.0.public_eq(other.0.as_ref())
@@ -69,7 +90,22 @@ impl PartialEq for SslKeyConfig {
 }
 
 #[derive(PartialEq, Clone, Debug)]
-pub struct SslCertConfig(Vec<X509>);
+pub struct SslCertConfig(Vec<X509>, Option<PathBuf>);
+
+impl SslCertConfig {
+    pub fn from_pem(pem: &[u8]) -> Result<Self, ErrorStack> {
+        X509::stack_from_pem(pem).map(|chain| SslCertConfig(chain, None))
+    }
+
+    pub fn chain(&self) -> &[X509] {
+        &self.0
+    }
+
+    /// File the certificate chain was loaded from.
+    pub fn path(&self) -> Option<&Path> {
+        self.1.as_deref()
+    }
+}
 
 impl<'de> Deserialize<'de> for SslCertConfig {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
@@ -101,9 +137,9 @@ impl<'de> Deserialize<'de> for SslCertConfig {
                     return Err(E::custom(format!("path doesn't exist: {}", path.display())));
                 }
 
-                let content = std::fs::read(path).unwrap();
+                let content = std::fs::read(&path).map_err(|err| E::custom(err.to_string()))?;
                 X509::stack_from_pem(&content)
-                    .map(|cert| SslCertConfig(cert))
+                    .map(|cert| SslCertConfig(cert, Some(path)))
                     .map_err(|err| E::custom(err.to_string()))
             }
         }
@@ -112,10 +148,161 @@ impl<'de> Deserialize<'de> for SslCertConfig {
     }
 }
 
+/// Key and certificate chain used in handshakes for a scope, it is shared between clones so
+/// it can be replaced while the server is running (for example by jequi_acme after a renewal).
+#[derive(Clone, Default, Debug)]
+pub struct SslIdentity(Arc<RwLock<Option<(SslKeyConfig, SslCertConfig)>>>);
+
+crate::config_type!(SslCertConfig, "string", {"type": "string"}, |cert| cert.path());
+
+impl SslIdentity {
+    pub fn get(&self) -> Option<(SslKeyConfig, SslCertConfig)> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn set(&self, key: SslKeyConfig, cert: SslCertConfig) {
+        *self.0.write().unwrap() = Some((key, cert));
+    }
+
+    /// Reads the key and certificate from their files again, the current ones are kept if
+    /// the new files can't be parsed or the key doesn't match the certificate.
+    pub fn reload(&self, key_path: &Path, cert_path: &Path) -> std::io::Result<()> {
+        let invalid_data = |err| Error::new(ErrorKind::InvalidData, err);
+
+        let key = PKey::private_key_from_pem(&std::fs::read(key_path)?).map_err(invalid_data)?;
+        let chain = X509::stack_from_pem(&std::fs::read(cert_path)?).map_err(invalid_data)?;
+
+        let leaf = chain.first().ok_or(Error::new(
+            ErrorKind::InvalidData,
+            "certificate file is empty",
+        ))?;
+        if !leaf.public_key().map_err(invalid_data)?.public_eq(&key) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "key doesn't match the certificate",
+            ));
+        }
+
+        self.set(
+            SslKeyConfig(key, Some(key_path.to_path_buf())),
+            SslCertConfig(chain, Some(cert_path.to_path_buf())),
+        );
+        Ok(())
+    }
+}
+
+/// Identities of every scope that loads its key and certificate from files, with the paths
+/// of the key and of the certificate.
+pub fn file_identities(config_map: &ConfigMap) -> Vec<(PathBuf, PathBuf, SslIdentity)> {
+    config_map
+        .config_lists()
+        .filter_map(|config| get_plugin!(config, jequi))
+        .filter_map(|conf| {
+            let key_path = conf.ssl_key.as_ref()?.path()?;
+            let cert_path = conf.ssl_certificate.as_ref()?.path()?;
+            Some((
+                key_path.to_path_buf(),
+                cert_path.to_path_buf(),
+                conf.ssl_identity.clone(),
+            ))
+        })
+        .collect()
+}
+
+impl PartialEq for SslIdentity {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0) || *self.0.read().unwrap() == *other.0.read().unwrap()
+    }
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct SslStaplingConfig(Vec<u8>);
+
+impl<'de> Deserialize<'de> for SslStaplingConfig {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct SslStaplingConfigVisitor;
+
+        impl<'de> de::Visitor<'de> for SslStaplingConfigVisitor {
+            type Value = SslStaplingConfig;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("SslStaplingConfig")
+            }
+
+            fn visit_string<E>(self, v: String) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                self.visit_str(&v)
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                let path = PathBuf::from(v);
+                if !path.exists() {
+                    return Err(E::custom(format!("path doesn't exist: {}", path.display())));
+                }
+
+                let content = std::fs::read(path).map_err(|err| E::custom(err.to_string()))?;
+                OcspResponse::from_der(&content)
+                    .map(|_| SslStaplingConfig(content))
+                    .map_err(|err| E::custom(err.to_string()))
+            }
+        }
+
+        deserializer.deserialize_string(SslStaplingConfigVisitor {})
+    }
+}
+
+// Allowed clock skew, in seconds, when checking the validity period of an OCSP response.
+const OCSP_VALIDITY_LEEWAY: u32 = 300;
+
+// Only the response is kept, not the file it was read from
+crate::config_type!(SslStaplingConfig, "string", {"type": "string"}, |_stapling| ());
+
+impl SslStaplingConfig {
+    /// Returns the DER encoded OCSP response if it is signed by the issuer of the leaf
+    /// certificate in `chain`, has a status for the leaf and is not expired.
+    pub fn valid_response(&self, chain: &SslCertConfig) -> Option<&[u8]> {
+        let leaf = chain.0.first()?;
+        let issuer = chain.0.get(1)?;
+
+        let response = OcspResponse::from_der(&self.0).ok()?;
+        if response.status() != OcspResponseStatus::SUCCESSFUL {
+            return None;
+        }
+        let basic = response.basic().ok()?;
+
+        let mut store = X509StoreBuilder::new().ok()?;
+        store.add_cert(issuer.clone()).ok()?;
+        let store = store.build();
+        let mut certs = Stack::new().ok()?;
+        for cert in chain.0.iter().skip(1) {
+            certs.push(cert.clone()).ok()?;
+        }
+        basic.verify(&certs, &store, OcspFlag::TRUST_OTHER).ok()?;
+
+        let cert_id = OcspCertId::from_cert(MessageDigest::sha1(), leaf, issuer).ok()?;
+        let status = basic.find_status(&cert_id)?;
+        status.check_validity(OCSP_VALIDITY_LEEWAY, None).ok()?;
+
+        Some(&self.0)
+    }
+}
+
 pub async fn ssl_new<T: AsyncRWSend>(
     stream: T,
     config_map: Arc<ConfigMap>,
-) -> (SslStream<T>, String) {
+    listener: &ListenConfig,
+    conn_info: &mut ConnInfo,
+) -> SslStream<T> {
+    let listener_name = conn_info.listener.clone();
+    let listener_http2 = listener.http2;
     let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
     acceptor.set_servername_callback(
         move |ssl_ref: &mut SslRef, _ssl_alert: &mut SslAlert| -> Result<(), SniError> {
@@ -124,19 +311,33 @@ pub async fn ssl_new<T: AsyncRWSend>(
             // let intermediate = X509::from_pem(INTERMEDIATE_CERT).unwrap();
 
             let mut ctx_builder = SslContextBuilder::new(SslMethod::tls()).unwrap();
-            let config =
-                config_map.get_config_for_request(ssl_ref.servername(NameType::HOST_NAME), None);
+            let config = config_map.get_config_for_listener(
+                listener_name.as_deref(),
+                ssl_ref.servername(NameType::HOST_NAME),
+                None,
+            );
             let conf = get_plugin!(config, jequi).unwrap();
-            let http2 = conf.http2;
+            let http2 = listener_http2.unwrap_or(conf.http2);
 
-            ctx_builder
-                .set_private_key(&conf.ssl_key.as_ref().unwrap().0)
-                .unwrap();
-            let mut chain = conf.ssl_certificate.as_ref().unwrap().0.iter();
+            let (key, cert) = conf.ssl_identity.get().ok_or(SniError::ALERT_FATAL)?;
+            ctx_builder.set_private_key(&key.0).unwrap();
+            let mut chain = cert.0.iter();
             ctx_builder.set_certificate(chain.next().unwrap()).unwrap();
             for cert in chain {
                 ctx_builder.add_extra_chain_cert(cert.clone()).unwrap();
             }
+            if let Some(stapling) = conf.ssl_stapling_file.clone() {
+                let chain = cert.clone();
+                ctx_builder
+                    .set_status_callback(move |ssl_ref| match stapling.valid_response(&chain) {
+                        Some(response) => {
+                            ssl_ref.set_ocsp_status(response)?;
+                            Ok(true)
+                        }
+                        None => Ok(false),
+                    })
+                    .unwrap();
+            }
             ctx_builder.set_alpn_protos(b"\x02h2\x08http/1.1").unwrap();
             ctx_builder.set_alpn_select_callback(move |_, protos| {
                 if !http2 {
@@ -166,12 +367,9 @@ pub async fn ssl_new<T: AsyncRWSend>(
 
     Pin::new(&mut stream).accept().await.unwrap();
 
-    let version = match stream.ssl().selected_alpn_protocol() {
-        Some(protocol) => String::from_utf8_lossy(protocol).to_string(),
-        None => String::new(),
-    };
+    conn_info.set_tls(stream.ssl());
 
-    (stream, version)
+    stream
 }
 
 #[cfg(test)]
@@ -188,12 +386,81 @@ mod tests {
     use openssl::ssl::{SslConnector, SslMethod};
     use tokio_openssl::SslStream;
 
-    use crate::ssl::{SslCertConfig, SslKeyConfig};
+    use std::path::Path;
+
+    use crate::ssl::{SslCertConfig, SslIdentity, SslKeyConfig, SslStaplingConfig};
     use crate::JequiConfig;
-    use crate::{http1::Http1Conn, Config, ConfigMap, Plugin, RequestHandler};
+    use crate::{
+        http1::Http1Conn, Config, ConfigMap, ConnInfo, ListenConfig, PhaseHandlers, Plugin,
+    };
 
     static ROOT_CERT_PATH: &str = "test/root-ca.pem";
 
+    fn deserialize_path<'de, T: Deserialize<'de>>(path: &'de str) -> T {
+        T::deserialize::<StrDeserializer<'_, Error>>(path.into_deserializer()).unwrap()
+    }
+
+    #[test]
+    fn ocsp_stapling_response_test() {
+        let chain: SslCertConfig = deserialize_path("test/leaf-cert.pem");
+
+        let stapling: SslStaplingConfig = deserialize_path("test/leaf-ocsp.der");
+        assert!(stapling.valid_response(&chain).is_some());
+
+        // Without the issuer the response can't be validated
+        let leaf_only = SslCertConfig(chain.0[..1].to_vec(), None);
+        assert!(stapling.valid_response(&leaf_only).is_none());
+
+        // A response for another certificate is not served
+        let intermediate: SslCertConfig = deserialize_path("test/intermediate.pem");
+        let root: SslCertConfig = deserialize_path("test/root-ca.pem");
+        let other_chain = SslCertConfig([intermediate.0, root.0].concat(), None);
+        assert!(stapling.valid_response(&other_chain).is_none());
+
+        let expired: SslStaplingConfig = deserialize_path("test/leaf-ocsp-expired.der");
+        assert!(expired.valid_response(&chain).is_none());
+    }
+
+    #[test]
+    fn identity_reload_test() {
+        let identity = SslIdentity::default();
+        identity
+            .reload(
+                Path::new("test/leaf-cert.key"),
+                Path::new("test/leaf-cert.pem"),
+            )
+            .unwrap();
+        let (key, cert) = identity.get().unwrap();
+        assert_eq!(key, deserialize_path::<SslKeyConfig>("test/leaf-cert.key"));
+        assert_eq!(
+            cert,
+            deserialize_path::<SslCertConfig>("test/leaf-cert.pem")
+        );
+
+        // Key from another certificate is rejected and the old identity is kept
+        assert!(identity
+            .reload(
+                Path::new("test/root-ca.key"),
+                Path::new("test/leaf-cert.pem")
+            )
+            .is_err());
+        assert!(identity
+            .reload(
+                Path::new("test/leaf-cert.key"),
+                Path::new("test/leaf-cert.csr")
+            )
+            .is_err());
+        assert_eq!(identity.get().unwrap().1, cert);
+
+        identity
+            .reload(Path::new("test/root-ca.key"), Path::new("test/root-ca.pem"))
+            .unwrap();
+        assert_eq!(
+            identity.get().unwrap().1,
+            deserialize_path::<SslCertConfig>("test/root-ca.pem")
+        );
+    }
+
     #[tokio::test]
     async fn ssl_handshake_test() {
         let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
@@ -219,12 +486,29 @@ mod tests {
                 ),
                 ..Config::default()
             };
+            conf.ssl_identity.set(
+                conf.ssl_key.clone().unwrap(),
+                conf.ssl_certificate.clone().unwrap(),
+            );
             main_conf.config.push(Plugin {
+                name: "jequi",
                 config: Arc::new(conf),
-                request_handler: RequestHandler(None),
+                handlers: PhaseHandlers::default(),
             });
 
-            let (mut stream, _) = super::ssl_new(stream, Arc::new(main_conf)).await;
+            let mut conn_info = ConnInfo::default();
+            let mut stream = super::ssl_new(
+                stream,
+                Arc::new(main_conf),
+                &ListenConfig::default(),
+                &mut conn_info,
+            )
+            .await;
+
+            assert_eq!(conn_info.scheme(), "https");
+            assert_eq!(conn_info.sni.as_deref(), Some("localhost"));
+            assert!(conn_info.tls_version.is_some());
+            assert!(conn_info.tls_cipher.is_some());
 
             stream.write_all(b"hello").await.unwrap();
         });
@@ -1,18 +1,23 @@
 use plugins::get_plugin;
 use std::{
-    io::{IoSlice, Result},
+    io::{Error, ErrorKind, IoSlice, Result},
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::timeout,
 };
-use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
 use crate::{
-    http1::Http1Conn, http2::Http2Conn, ssl::ssl_new, AsyncRWSend, AsyncRWSendBuf, ConfigMap,
-    HttpConn, RawStream,
+    http1::Http1Conn, http2::Http2Conn, proxy_protocol, ssl::ssl_new, AsyncRWSend, AsyncRWSendBuf,
+    ConfigMap, ConnInfo, HttpConn, ListenConfig, RawStream,
 };
 
-use crate as jequi;
+// Time a client has to send the PROXY protocol header after connecting
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
 
 impl<S: AsyncRWSend> AsyncRead for RawStream<S> {
     fn poll_read(
@@ -73,18 +78,69 @@ impl<S: AsyncRWSend> AsyncWrite for RawStream<S> {
 }
 
 impl<T: AsyncRWSend> HttpConn<T> {
-    pub async fn new(stream: T, config_map: Arc<ConfigMap>) -> HttpConn<T> {
+    pub async fn new(
+        mut stream: T,
+        mut conn_info: ConnInfo,
+        listener: &ListenConfig,
+        config_map: Arc<ConfigMap>,
+    ) -> Result<HttpConn<T>> {
         let plugin_list = &config_map.config;
         let conf = get_plugin!(plugin_list, jequi).unwrap();
+        conn_info.listener = Some(listener.name());
+
+        if listener.proxy_protocol {
+            let trusted = conn_info.remote_addr.is_some_and(|addr| {
+                conf.proxy_protocol_trusted
+                    .iter()
+                    .any(|cidr| cidr.contains(&addr.ip()))
+            });
+            if !trusted {
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    "proxy protocol connection from untrusted address",
+                ));
+            }
+            let header = timeout(
+                PROXY_HEADER_TIMEOUT,
+                proxy_protocol::read_header(&mut stream),
+            )
+            .await
+            .map_err(|_| {
+                Error::new(
+                    ErrorKind::TimedOut,
+                    "timed out reading proxy protocol header",
+                )
+            })??;
+            if let Some((remote_addr, local_addr)) = header {
+                conn_info.remote_addr = Some(remote_addr);
+                conn_info.local_addr = Some(local_addr);
+            }
+        }
 
-        if conf.tls_active {
-            let (stream, version) = ssl_new(stream, config_map.clone()).await;
-            if version == "h2" {
-                return HttpConn::HTTP2(Http2Conn::new(RawStream::Ssl(stream)));
+        if listener.tls {
+            let stream = ssl_new(stream, config_map.clone(), listener, &mut conn_info).await;
+            let conn_info = Arc::new(conn_info);
+            if conn_info.alpn.as_deref() == Some("h2") {
+                return Ok(HttpConn::HTTP2(
+                    Http2Conn::new(RawStream::Ssl(stream)).with_conn_info(conn_info),
+                ));
             }
-            return HttpConn::HTTP1(Http1Conn::new(RawStream::Ssl(stream)));
+            return Ok(HttpConn::HTTP1(
+                Http1Conn::new(RawStream::Ssl(stream)).with_conn_info(conn_info),
+            ));
+        }
+        Ok(HttpConn::HTTP1(
+            Http1Conn::new(RawStream::Normal(stream)).with_conn_info(Arc::new(conn_info)),
+        ))
+    }
+
+    /// Information about the connection, with the proxy protocol addresses and the TLS details
+    /// already filled.
+    pub fn conn_info(&self) -> Arc<ConnInfo> {
+        match self {
+            HttpConn::HTTP1(conn) => conn.conn_info.clone(),
+            HttpConn::HTTP2(conn) => conn.conn_info(),
         }
-        HttpConn::HTTP1(Http1Conn::new(RawStream::Normal(stream)))
     }
 
     pub async fn handle_connection(self, config_map: Arc<ConfigMap>) {
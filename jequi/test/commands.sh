@@ -16,3 +16,10 @@ openssl req -x509 -days 1825 -newkey rsa:2048 -nodes -keyout root-ca.key -out ro
 	openssl x509 -req -CA intermediate.pem -CAkey intermediate.key -in leaf-cert.csr -out leaf-cert.pem -days 1550 -CAcreateserial &&
 	cat intermediate.pem >>leaf-cert.pem
 
+leaf_serial=$(openssl x509 -in leaf-cert.pem -noout -serial | cut -d= -f2)
+printf 'V\t300101000000Z\t\t%s\tunknown\t/CN=localhost\n' "$leaf_serial" >ocsp-index.txt &&
+	openssl ocsp -index ocsp-index.txt -rsigner intermediate.pem -rkey intermediate.key -CA intermediate.pem \
+		-issuer intermediate.pem -cert leaf-cert.pem -reqout /dev/null -respout leaf-ocsp.der -ndays 3650 &&
+	openssl ocsp -index ocsp-index.txt -rsigner intermediate.pem -rkey intermediate.key -CA intermediate.pem \
+		-issuer intermediate.pem -cert leaf-cert.pem -reqout /dev/null -respout leaf-ocsp-expired.der -nmin 1 &&
+	rm ocsp-index.txt
@@ -0,0 +1,285 @@
+//! Interface between jequi and the modules it loads at runtime with `load_module`.
+//!
+//! A module is a `cdylib` crate that implements `Module` and calls `declare_module!`. The
+//! server and the module can be built by different compilers, so only the `repr(C)` types and
+//! `extern "C"` functions of this crate cross between them. `ABI_VERSION` changes with any of
+//! them and the server refuses modules built for another version.
+//!
+//! Modules only have a synchronous content handler. They have no lifecycle hooks, can't handle
+//! the other phases of a request and can't filter responses, that still needs a plugin linked
+//! into the server.
+
+use std::{
+    ffi::c_void,
+    panic::{self, AssertUnwindSafe},
+    ptr, slice, str,
+};
+
+pub use serde_yaml;
+
+/// Version of the interface, checked by the server before using anything else of a module.
+pub const ABI_VERSION: u32 = 1;
+
+/// Symbol of the function exported by `declare_module!`, it returns the `ModuleDeclaration`.
+pub const DECLARATION_SYMBOL: &[u8] = b"jequi_module_declaration\0";
+
+pub type DeclarationFn = unsafe extern "C" fn() -> *const ModuleDeclaration;
+
+/// Borrowed bytes, a null `ptr` means there is no value.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct AbiStr {
+    pub ptr: *const u8,
+    pub len: usize,
+}
+
+impl AbiStr {
+    pub const NONE: AbiStr = AbiStr {
+        ptr: ptr::null(),
+        len: 0,
+    };
+
+    pub const fn new(bytes: &[u8]) -> AbiStr {
+        AbiStr {
+            ptr: bytes.as_ptr(),
+            len: bytes.len(),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` is null or points to `len` bytes that live for `'a`.
+    pub unsafe fn as_bytes<'a>(self) -> Option<&'a [u8]> {
+        if self.ptr.is_null() {
+            return None;
+        }
+        Some(unsafe { slice::from_raw_parts(self.ptr, self.len) })
+    }
+
+    /// Same as `as_bytes`, `None` if the bytes aren't UTF-8.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` is null or points to `len` bytes that live for `'a`.
+    pub unsafe fn as_str<'a>(self) -> Option<&'a str> {
+        str::from_utf8(unsafe { self.as_bytes()? }).ok()
+    }
+}
+
+/// Option of a module, the server accepts it in the configuration while the module is loaded.
+#[repr(C)]
+pub struct ModuleOption {
+    pub key: AbiStr,
+    pub description: AbiStr,
+}
+
+impl ModuleOption {
+    pub const fn new(key: &'static str, description: &'static str) -> ModuleOption {
+        ModuleOption {
+            key: AbiStr::new(key.as_bytes()),
+            description: AbiStr::new(description.as_bytes()),
+        }
+    }
+}
+
+/// Request of the server, only used through `HostApi`.
+#[repr(C)]
+pub struct RawRequest {
+    _private: [u8; 0],
+}
+
+/// Response of the server, only used through `HostApi`.
+#[repr(C)]
+pub struct RawResponse {
+    _private: [u8; 0],
+}
+
+/// Error of a module being loaded, set with `HostApi::set_error`.
+#[repr(C)]
+pub struct RawError {
+    _private: [u8; 0],
+}
+
+/// Functions of the server that modules call, the strings returned borrow the request and
+/// live until the module returns.
+#[repr(C)]
+pub struct HostApi {
+    pub request_method: unsafe extern "C" fn(request: *const RawRequest) -> AbiStr,
+    pub request_uri: unsafe extern "C" fn(request: *const RawRequest) -> AbiStr,
+    pub request_header: unsafe extern "C" fn(request: *const RawRequest, name: AbiStr) -> AbiStr,
+    /// `AbiStr::NONE` if the body wasn't read yet.
+    pub request_body: unsafe extern "C" fn(request: *const RawRequest) -> AbiStr,
+    pub set_status: unsafe extern "C" fn(response: *mut RawResponse, status: u16),
+    pub set_header: unsafe extern "C" fn(response: *mut RawResponse, name: AbiStr, value: AbiStr),
+    pub write_body: unsafe extern "C" fn(response: *mut RawResponse, bytes: AbiStr),
+    pub set_error: unsafe extern "C" fn(error: *mut RawError, message: AbiStr),
+}
+
+/// What the server does after a module handles a request, like `jequi::PostRequestHandler`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Flow {
+    Continue,
+    Exit,
+}
+
+/// Everything the server uses from a module, built by `declare_module!`.
+#[repr(C)]
+pub struct ModuleDeclaration {
+    /// Always the first field, so it can be read whatever version the module was built for.
+    pub abi_version: u32,
+    pub name: AbiStr,
+    pub options: *const ModuleOption,
+    pub options_len: usize,
+    /// Loads the config of a scope from its options in YAML. Returns null when the module
+    /// isn't used in the scope, or when it fails after calling `set_error`.
+    pub load:
+        unsafe extern "C" fn(host: &HostApi, config: AbiStr, error: *mut RawError) -> *mut c_void,
+    /// Content handler, called with a config returned by `load`.
+    pub handle_request: unsafe extern "C" fn(
+        host: &HostApi,
+        config: *const c_void,
+        request: *mut RawRequest,
+        response: *mut RawResponse,
+    ) -> Flow,
+    /// Frees a config returned by `load`.
+    pub drop: unsafe extern "C" fn(config: *mut c_void),
+}
+
+// Only points to the static name and options of the module
+unsafe impl Sync for ModuleDeclaration {}
+
+impl ModuleDeclaration {
+    pub const fn new<M: Module>() -> ModuleDeclaration {
+        ModuleDeclaration {
+            abi_version: ABI_VERSION,
+            name: AbiStr::new(M::NAME.as_bytes()),
+            options: M::OPTIONS.as_ptr(),
+            options_len: M::OPTIONS.len(),
+            load: load::<M>,
+            handle_request: handle_request::<M>,
+            drop: drop::<M>,
+        }
+    }
+}
+
+/// Config of a module for a scope, the runtime counterpart of `jequi::JequiConfig`.
+pub trait Module: Sized + Send + Sync + 'static {
+    /// Name of the module in errors and in `--explain-route`.
+    const NAME: &'static str;
+    /// Options read by `load`, other options of the scope are checked by the server.
+    const OPTIONS: &'static [ModuleOption];
+
+    /// Loads the config from the options of a scope, `Ok(None)` means the module isn't used in
+    /// this scope.
+    fn load(config: &serde_yaml::Value) -> Result<Option<Self>, String>;
+
+    /// Runs in the content phase, after the handlers of the plugins linked into the server.
+    fn handle_request(&self, request: &Request, response: &mut Response) -> Flow;
+}
+
+/// Exports `ModuleDeclaration::new::<$module>()` under `DECLARATION_SYMBOL`.
+#[macro_export]
+macro_rules! declare_module {
+    ($module:ty) => {
+        #[no_mangle]
+        pub extern "C" fn jequi_module_declaration() -> *const $crate::ModuleDeclaration {
+            static DECLARATION: $crate::ModuleDeclaration =
+                $crate::ModuleDeclaration::new::<$module>();
+            &DECLARATION
+        }
+    };
+}
+
+unsafe extern "C" fn load<M: Module>(
+    host: &HostApi,
+    config: AbiStr,
+    error: *mut RawError,
+) -> *mut c_void {
+    // Panics can't unwind into the server
+    let result = panic::catch_unwind(|| {
+        let config = unsafe { config.as_str() }.ok_or("config isn't valid UTF-8")?;
+        let config = serde_yaml::from_str(config).map_err(|err| err.to_string())?;
+        M::load(&config)
+    });
+    let message = match result {
+        Ok(Ok(Some(module))) => return Box::into_raw(Box::new(module)).cast(),
+        Ok(Ok(None)) => return ptr::null_mut(),
+        Ok(Err(message)) => message,
+        Err(_) => "module panicked while loading".to_string(),
+    };
+    unsafe { (host.set_error)(error, AbiStr::new(message.as_bytes())) };
+    ptr::null_mut()
+}
+
+unsafe extern "C" fn handle_request<M: Module>(
+    host: &HostApi,
+    config: *const c_void,
+    request: *mut RawRequest,
+    response: *mut RawResponse,
+) -> Flow {
+    let module = unsafe { &*config.cast::<M>() };
+    let request = Request { host, raw: request };
+    let mut response = Response {
+        host,
+        raw: response,
+    };
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        module.handle_request(&request, &mut response)
+    }))
+    .unwrap_or_else(|_| {
+        response.set_status(500);
+        Flow::Exit
+    })
+}
+
+unsafe extern "C" fn drop<M: Module>(config: *mut c_void) {
+    std::mem::drop(unsafe { Box::from_raw(config.cast::<M>()) });
+}
+
+/// Request being handled by a module.
+pub struct Request<'a> {
+    host: &'a HostApi,
+    raw: *mut RawRequest,
+}
+
+impl Request<'_> {
+    pub fn method(&self) -> &str {
+        unsafe { (self.host.request_method)(self.raw).as_str() }.unwrap_or_default()
+    }
+
+    pub fn uri(&self) -> &str {
+        unsafe { (self.host.request_uri)(self.raw).as_str() }.unwrap_or_default()
+    }
+
+    /// Value of the header, `None` if it isn't set or isn't UTF-8.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        unsafe { (self.host.request_header)(self.raw, AbiStr::new(name.as_bytes())).as_str() }
+    }
+
+    /// Body of the request, `None` if it wasn't read yet.
+    pub fn body(&self) -> Option<&[u8]> {
+        unsafe { (self.host.request_body)(self.raw).as_bytes() }
+    }
+}
+
+/// Response of the request being handled by a module.
+pub struct Response<'a> {
+    host: &'a HostApi,
+    raw: *mut RawResponse,
+}
+
+impl Response<'_> {
+    pub fn set_status(&mut self, status: u16) {
+        unsafe { (self.host.set_status)(self.raw, status) }
+    }
+
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        let (name, value) = (AbiStr::new(name.as_bytes()), AbiStr::new(value.as_bytes()));
+        unsafe { (self.host.set_header)(self.raw, name, value) }
+    }
+
+    pub fn write_body(&mut self, bytes: &[u8]) {
+        unsafe { (self.host.write_body)(self.raw, AbiStr::new(bytes)) }
+    }
+}
@@ -0,0 +1,104 @@
+use openssl::{
+    asn1::Asn1Time,
+    bn::{BigNum, MsbOption},
+    ec::{EcGroup, EcKey},
+    error::ErrorStack,
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, Private},
+    stack::Stack,
+    x509::{extension::SubjectAlternativeName, X509Name, X509NameBuilder, X509Req, X509},
+};
+
+use jequi::ssl::SslCertConfig;
+
+const SELF_SIGNED_DAYS: u32 = 365;
+
+pub fn new_ec_key() -> Result<EcKey<Private>, ErrorStack> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    EcKey::generate(&group)
+}
+
+fn subject_name(domains: &[String]) -> Result<X509Name, ErrorStack> {
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, &domains[0])?;
+    Ok(name.build())
+}
+
+fn subject_alt_names(domains: &[String]) -> SubjectAlternativeName {
+    let mut alt_names = SubjectAlternativeName::new();
+    for domain in domains {
+        alt_names.dns(domain);
+    }
+    alt_names
+}
+
+/// Certificate signing request for `domains` in DER.
+pub fn csr(domains: &[String], key: &PKey<Private>) -> Result<Vec<u8>, ErrorStack> {
+    let mut req = X509Req::builder()?;
+    req.set_pubkey(key)?;
+    let name = subject_name(domains)?;
+    req.set_subject_name(&name)?;
+
+    let mut extensions = Stack::new()?;
+    extensions.push(subject_alt_names(domains).build(&req.x509v3_context(None))?)?;
+    req.add_extensions(&extensions)?;
+
+    req.sign(key, MessageDigest::sha256())?;
+    req.build().to_der()
+}
+
+/// Certificate for `domains` signed by its own key, used for development and while the
+/// first certificate isn't issued.
+pub fn self_signed(domains: &[String], key: &PKey<Private>) -> Result<X509, ErrorStack> {
+    let mut cert = X509::builder()?;
+    cert.set_version(2)?;
+
+    let mut serial = BigNum::new()?;
+    serial.rand(128, MsbOption::MAYBE_ZERO, false)?;
+    let serial = serial.to_asn1_integer()?;
+    cert.set_serial_number(&serial)?;
+
+    let name = subject_name(domains)?;
+    cert.set_subject_name(&name)?;
+    cert.set_issuer_name(&name)?;
+    cert.set_pubkey(key)?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(SELF_SIGNED_DAYS)?;
+    cert.set_not_before(&not_before)?;
+    cert.set_not_after(&not_after)?;
+
+    let alt_names = subject_alt_names(domains).build(&cert.x509v3_context(None, None))?;
+    cert.append_extension(alt_names)?;
+
+    cert.sign(key, MessageDigest::sha256())?;
+    Ok(cert.build())
+}
+
+/// Checks if the leaf certificate expires in less than `days`.
+pub fn expires_within(cert: &SslCertConfig, days: u32) -> bool {
+    let leaf = match cert.chain().first() {
+        Some(leaf) => leaf,
+        None => return true,
+    };
+    match Asn1Time::days_from_now(days) {
+        Ok(limit) => leaf.not_after() < limit,
+        Err(_) => true,
+    }
+}
+
+/// Checks if the leaf certificate is valid for every domain in `domains`.
+pub fn covers(cert: &SslCertConfig, domains: &[String]) -> bool {
+    let names: Vec<String> = match cert
+        .chain()
+        .first()
+        .and_then(|leaf| leaf.subject_alt_names())
+    {
+        Some(names) => names
+            .iter()
+            .filter_map(|name| name.dnsname().map(|name| name.to_string()))
+            .collect(),
+        None => return false,
+    };
+    domains.iter().all(|domain| names.contains(domain))
+}
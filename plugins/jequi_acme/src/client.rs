@@ -0,0 +1,347 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    path::Path,
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hyper::{body, client::HttpConnector, header, Body, Client as HttpClient, Method, Request};
+use hyper_tls::HttpsConnector;
+use native_tls::{Certificate, TlsConnector};
+use openssl::{
+    bn::{BigNum, BigNumContext},
+    ec::EcKey,
+    ecdsa::EcdsaSig,
+    hash::{hash, MessageDigest},
+    pkey::{PKey, Private},
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{certificate, remove_challenge, set_challenge};
+
+const POLL_ATTEMPTS: usize = 30;
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+fn acme_error<E: ToString>(err: E) -> Error {
+    Error::new(ErrorKind::Other, err.to_string())
+}
+
+fn base64(bytes: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    typ: String,
+    url: String,
+    token: String,
+}
+
+struct AcmeResponse {
+    location: Option<String>,
+    body: Vec<u8>,
+}
+
+pub struct Client {
+    http: HttpClient<HttpsConnector<HttpConnector>>,
+    directory: Directory,
+    key: EcKey<Private>,
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl Client {
+    pub async fn new(
+        directory_url: &str,
+        ca_file: Option<&Path>,
+        key: EcKey<Private>,
+    ) -> Result<Client> {
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        let mut tls = TlsConnector::builder();
+        if let Some(ca_file) = ca_file {
+            let ca = Certificate::from_pem(&std::fs::read(ca_file)?).map_err(acme_error)?;
+            tls.add_root_certificate(ca);
+        }
+        let tls = tls.build().map_err(acme_error)?;
+        let https = HttpsConnector::from((http, tokio_native_tls::TlsConnector::from(tls)));
+        let http = HttpClient::builder().build::<_, Body>(https);
+
+        let response = http
+            .get(directory_url.parse().map_err(acme_error)?)
+            .await
+            .map_err(acme_error)?;
+        let directory = body::to_bytes(response.into_body())
+            .await
+            .map_err(acme_error)?;
+        let directory = serde_json::from_slice(&directory).map_err(acme_error)?;
+
+        Ok(Client {
+            http,
+            directory,
+            key,
+            kid: None,
+            nonce: None,
+        })
+    }
+
+    fn jwk(&self) -> Result<Value> {
+        let mut ctx = BigNumContext::new().map_err(acme_error)?;
+        let mut x = BigNum::new().map_err(acme_error)?;
+        let mut y = BigNum::new().map_err(acme_error)?;
+        self.key
+            .public_key()
+            .affine_coordinates(self.key.group(), &mut x, &mut y, &mut ctx)
+            .map_err(acme_error)?;
+
+        // Members must be in lexicographic order for the thumbprint
+        Ok(json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": base64(&x.to_vec_padded(32).map_err(acme_error)?),
+            "y": base64(&y.to_vec_padded(32).map_err(acme_error)?),
+        }))
+    }
+
+    /// Key authorization that must be served for an http-01 challenge `token`.
+    fn key_authorization(&self, token: &str) -> Result<String> {
+        let jwk = serde_json::to_vec(&self.jwk()?).map_err(acme_error)?;
+        let thumbprint = hash(MessageDigest::sha256(), &jwk).map_err(acme_error)?;
+        Ok(format!("{}.{}", token, base64(&thumbprint)))
+    }
+
+    async fn nonce(&mut self) -> Result<String> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+
+        let request = Request::builder()
+            .method(Method::HEAD)
+            .uri(&self.directory.new_nonce)
+            .body(Body::empty())
+            .map_err(acme_error)?;
+        let response = self.http.request(request).await.map_err(acme_error)?;
+        response
+            .headers()
+            .get("replay-nonce")
+            .and_then(|nonce| nonce.to_str().ok())
+            .map(|nonce| nonce.to_string())
+            .ok_or(acme_error("acme server didn't send a nonce"))
+    }
+
+    fn sign(&self, url: &str, nonce: String, payload: Option<&Value>) -> Result<Value> {
+        let mut protected = json!({
+            "alg": "ES256",
+            "nonce": nonce,
+            "url": url,
+        });
+        match &self.kid {
+            Some(kid) => protected["kid"] = kid.as_str().into(),
+            None => protected["jwk"] = self.jwk()?,
+        }
+
+        let protected = base64(&serde_json::to_vec(&protected).map_err(acme_error)?);
+        // An empty payload is a POST-as-GET request
+        let payload = match payload {
+            Some(payload) => base64(&serde_json::to_vec(payload).map_err(acme_error)?),
+            None => String::new(),
+        };
+
+        let digest = hash(
+            MessageDigest::sha256(),
+            format!("{}.{}", protected, payload).as_bytes(),
+        )
+        .map_err(acme_error)?;
+        let signature = EcdsaSig::sign(&digest, &self.key).map_err(acme_error)?;
+        let signature = [
+            signature.r().to_vec_padded(32).map_err(acme_error)?,
+            signature.s().to_vec_padded(32).map_err(acme_error)?,
+        ]
+        .concat();
+
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": base64(&signature),
+        }))
+    }
+
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<AcmeResponse> {
+        let mut retried = false;
+        loop {
+            let nonce = self.nonce().await?;
+            let jws = self.sign(url, nonce, payload)?;
+
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri(url)
+                .header(header::CONTENT_TYPE, "application/jose+json")
+                .body(Body::from(serde_json::to_vec(&jws).map_err(acme_error)?))
+                .map_err(acme_error)?;
+            let response = self.http.request(request).await.map_err(acme_error)?;
+
+            let headers = response.headers();
+            self.nonce = headers
+                .get("replay-nonce")
+                .and_then(|nonce| nonce.to_str().ok())
+                .map(|nonce| nonce.to_string());
+            let location = headers
+                .get(header::LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .map(|location| location.to_string());
+            let status = response.status();
+            let body = body::to_bytes(response.into_body())
+                .await
+                .map_err(acme_error)?
+                .to_vec();
+
+            if status.is_success() {
+                return Ok(AcmeResponse { location, body });
+            }
+
+            let problem: Value = serde_json::from_slice(&body).unwrap_or_default();
+            if !retried && problem["type"] == "urn:ietf:params:acme:error:badNonce" {
+                retried = true;
+                continue;
+            }
+
+            return Err(acme_error(format!(
+                "acme request to {} failed with status {}: {}",
+                url,
+                status,
+                String::from_utf8_lossy(&body)
+            )));
+        }
+    }
+
+    pub async fn register(&mut self, contact: Option<&str>) -> Result<()> {
+        let mut payload = json!({ "termsOfServiceAgreed": true });
+        if let Some(contact) = contact {
+            payload["contact"] = json!([format!("mailto:{}", contact)]);
+        }
+
+        let url = self.directory.new_account.clone();
+        let response = self.post(&url, Some(&payload)).await?;
+        self.kid = Some(
+            response
+                .location
+                .ok_or(acme_error("acme server didn't return the account url"))?,
+        );
+        Ok(())
+    }
+
+    /// Requests a certificate for `domains` answering the http-01 challenges, returns the
+    /// certificate chain in PEM.
+    pub async fn issue(&mut self, domains: &[String], key: &PKey<Private>) -> Result<Vec<u8>> {
+        let identifiers: Vec<Value> = domains
+            .iter()
+            .map(|domain| json!({ "type": "dns", "value": domain }))
+            .collect();
+        let url = self.directory.new_order.clone();
+        let response = self
+            .post(&url, Some(&json!({ "identifiers": identifiers })))
+            .await?;
+        let order_url = response
+            .location
+            .ok_or(acme_error("acme server didn't return the order url"))?;
+        let order: Order = serde_json::from_slice(&response.body).map_err(acme_error)?;
+
+        for authorization_url in &order.authorizations {
+            self.authorize(authorization_url).await?;
+        }
+
+        let csr = certificate::csr(domains, key).map_err(acme_error)?;
+        self.post(&order.finalize, Some(&json!({ "csr": base64(&csr) })))
+            .await?;
+
+        let mut order = order;
+        for _ in 0..POLL_ATTEMPTS {
+            let response = self.post(&order_url, None).await?;
+            order = serde_json::from_slice(&response.body).map_err(acme_error)?;
+            if order.status != "processing" && order.status != "ready" {
+                break;
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+
+        let certificate_url = match (order.status.as_str(), order.certificate) {
+            ("valid", Some(url)) => url,
+            (status, _) => {
+                return Err(acme_error(format!(
+                    "acme order finished with status {}",
+                    status
+                )))
+            }
+        };
+
+        Ok(self.post(&certificate_url, None).await?.body)
+    }
+
+    async fn authorize(&mut self, url: &str) -> Result<()> {
+        let response = self.post(url, None).await?;
+        let authorization: Authorization =
+            serde_json::from_slice(&response.body).map_err(acme_error)?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+
+        let challenge = authorization
+            .challenges
+            .iter()
+            .find(|challenge| challenge.typ == "http-01")
+            .ok_or(acme_error("acme server didn't offer a http-01 challenge"))?;
+
+        set_challenge(&challenge.token, &self.key_authorization(&challenge.token)?);
+        let result = self.validate_challenge(url, &challenge.url).await;
+        remove_challenge(&challenge.token);
+        result
+    }
+
+    async fn validate_challenge(&mut self, authorization_url: &str, url: &str) -> Result<()> {
+        self.post(url, Some(&json!({}))).await?;
+
+        for _ in 0..POLL_ATTEMPTS {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            let response = self.post(authorization_url, None).await?;
+            let authorization: Authorization =
+                serde_json::from_slice(&response.body).map_err(acme_error)?;
+            match authorization.status.as_str() {
+                "valid" => return Ok(()),
+                "pending" => continue,
+                status => {
+                    return Err(acme_error(format!(
+                        "acme authorization finished with status {}: {}",
+                        status,
+                        String::from_utf8_lossy(&response.body)
+                    )))
+                }
+            }
+        }
+
+        Err(acme_error("timed out waiting for acme authorization"))
+    }
+}
@@ -0,0 +1,549 @@
+#![feature(let_chains)]
+mod certificate;
+mod client;
+
+use client::Client;
+use jequi::request::ACME_CHALLENGE_PATH;
+use jequi::ssl::{SslCertConfig, SslIdentity, SslKeyConfig};
+use jequi::{ConfigError, ConfigMap, Lifecycle, Plugin, PostRequestHandler, Request, Response};
+use openssl::ec::EcKey;
+use openssl::pkey::PKey;
+use plugins::{get_plugin, JequiConfig};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::{Error, ErrorKind, Result, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
+};
+
+const RENEW_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+// Private keys are only readable by the user running the server, certificates by anyone
+const PRIVATE_KEY_MODE: u32 = 0o600;
+const CERTIFICATE_MODE: u32 = 0o644;
+
+// Key authorizations for the pending http-01 challenges, indexed by token
+static CHALLENGES: Mutex<Option<HashMap<String, String>>> = Mutex::new(None);
+
+// Managers that are running, they stop when the last config using them is dropped
+static MANAGERS: Mutex<Option<HashMap<Vec<String>, Weak<Manager>>>> = Mutex::new(None);
+
+/// Writes `contents` to a temporary file created with `mode` and renames it to `path`, so a
+/// crash never leaves a partly written file.
+fn write_file(path: &Path, contents: &[u8], mode: u32) -> Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    // The mode is only set when the file is created
+    match fs::remove_file(&temp_path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+        _ => (),
+    }
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(&temp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)
+}
+
+pub(crate) fn set_challenge(token: &str, key_authorization: &str) {
+    CHALLENGES
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .insert(token.to_string(), key_authorization.to_string());
+}
+
+pub(crate) fn remove_challenge(token: &str) {
+    if let Some(challenges) = CHALLENGES.lock().unwrap().as_mut() {
+        challenges.remove(token);
+    }
+}
+
+fn get_challenge(token: &str) -> Option<String> {
+    CHALLENGES.lock().unwrap().as_ref()?.get(token).cloned()
+}
+
+#[derive(Default, Debug)]
+struct ManagerHandle(Option<Arc<Manager>>);
+
+impl PartialEq for ManagerHandle {
+    fn eq(&self, other: &Self) -> bool {
+        match (&self.0, &other.0) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (a, b) => a.is_none() && b.is_none(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq, JequiConfig)]
+#[serde(default)]
+#[jequi(
+    content = handle_request,
+    validate = validate,
+    setup = setup,
+    lifecycle,
+    scopes(default, host)
+)]
+pub struct Config {
+    /// Directory url of the ACME server used to issue certificates (for example
+    /// `https://acme-v02.api.letsencrypt.org/directory` or a local
+    /// [Pebble](https://github.com/letsencrypt/pebble) server), when set the plugin answers the
+    /// http-01 challenges, stores the certificate and renews it before it expires, the renewed
+    /// certificate is used in new handshakes without restarting the server.
+    #[jequi(activate)]
+    pub acme_directory_url: Option<String>,
+    /// Email sent to the ACME server when creating the account.
+    pub acme_contact: Option<String>,
+    /// Domains included in the certificate, the host name of the scope when not set. It is
+    /// required in the default scope.
+    pub acme_domains: Option<Vec<String>>,
+    /// Directory where the account key, certificates and keys are stored.
+    #[jequi(default = PathBuf::from("acme/"))]
+    pub acme_storage_path: PathBuf,
+    /// How many days before expiring the certificate is renewed.
+    #[jequi(default = 30)]
+    pub acme_renew_before_days: u32,
+    /// Path of a PEM certificate used to trust the ACME server, useful for test servers like
+    /// Pebble.
+    pub acme_directory_ca_file: Option<PathBuf>,
+    /// Generates a self signed certificate for the domains instead of requesting one, useful for
+    /// development.
+    #[jequi(activate)]
+    pub acme_self_signed: bool,
+    config_host: Option<String>,
+    #[serde(skip)]
+    manager: ManagerHandle,
+}
+
+impl Config {
+    fn domains(&self) -> Option<Vec<String>> {
+        match (&self.acme_domains, &self.config_host) {
+            (Some(domains), _) if !domains.is_empty() => Some(domains.clone()),
+            (_, Some(host)) => Some(vec![host.clone()]),
+            _ => None,
+        }
+    }
+
+    async fn handle_request(
+        self: Arc<Self>,
+        req: &mut Request,
+        resp: &mut Response,
+    ) -> PostRequestHandler {
+        let token = match req.uri.path().strip_prefix(ACME_CHALLENGE_PATH) {
+            Some(token) => token,
+            None => return PostRequestHandler::Continue,
+        };
+
+        match get_challenge(token) {
+            Some(key_authorization) => {
+                resp.status = 200;
+                resp.set_header("Content-Type", "application/octet-stream");
+                resp.write_body(key_authorization.as_bytes()).unwrap();
+                PostRequestHandler::Exit
+            }
+            None => PostRequestHandler::Continue,
+        }
+    }
+
+    fn validate(&self) -> std::result::Result<(), ConfigError> {
+        if self.domains().is_none() {
+            return Err(
+                ConfigError::new("required when acme is configured outside of a host")
+                    .with_key("acme_domains"),
+            );
+        }
+        Ok(())
+    }
+
+    // Only links the manager, certificates are stored and issued once the configuration is used
+    // by the server, see `Lifecycle`
+    fn setup(&mut self, configs: &mut Vec<Option<Plugin>>) -> std::result::Result<(), ConfigError> {
+        let manager = Manager::get_or_new(self, self.domains().unwrap());
+
+        // Handshakes for this scope use the certificate managed by the plugin
+        let main_conf = get_plugin!(configs, jequi, mut Option)
+            .expect("main config should be loaded before plugins");
+        main_conf.ssl_identity = manager.identity.clone();
+
+        self.manager = ManagerHandle(Some(manager));
+        Ok(())
+    }
+}
+
+// Starts the managers of a configuration the server uses, the ones already running are kept
+fn start_managers(config_map: &ConfigMap) {
+    let configs = config_map
+        .config_lists()
+        .filter_map(|config| jequi::plugin::get::<Config>(config));
+    for manager in configs.filter_map(|conf| conf.manager.0.as_ref()) {
+        manager.start();
+    }
+}
+
+impl Lifecycle for Config {
+    fn init(config_map: &ConfigMap) {
+        start_managers(config_map);
+    }
+
+    fn commit(config_map: &ConfigMap) {
+        start_managers(config_map);
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+struct Settings {
+    directory_url: Option<String>,
+    contact: Option<String>,
+    domains: Vec<String>,
+    storage_path: PathBuf,
+    renew_before_days: u32,
+    directory_ca_file: Option<PathBuf>,
+    self_signed: bool,
+}
+
+#[derive(Debug)]
+struct Manager {
+    settings: Settings,
+    identity: SslIdentity,
+    started: AtomicBool,
+}
+
+impl Manager {
+    /// Returns the running manager for `domains` if its settings didn't change, otherwise a new
+    /// one that does nothing until `start` is called.
+    fn get_or_new(conf: &Config, domains: Vec<String>) -> Arc<Manager> {
+        let settings = Settings {
+            directory_url: conf.acme_directory_url.clone(),
+            contact: conf.acme_contact.clone(),
+            domains: domains.clone(),
+            storage_path: conf.acme_storage_path.clone(),
+            renew_before_days: conf.acme_renew_before_days,
+            directory_ca_file: conf.acme_directory_ca_file.clone(),
+            self_signed: conf.acme_self_signed,
+        };
+
+        let mut managers = MANAGERS.lock().unwrap();
+        let managers = managers.get_or_insert_with(HashMap::new);
+        if let Some(manager) = managers.get(&domains).and_then(|manager| manager.upgrade())
+            && manager.settings == settings
+        {
+            return manager;
+        }
+
+        let manager = Arc::new(Manager {
+            settings,
+            identity: SslIdentity::default(),
+            started: AtomicBool::new(false),
+        });
+        managers.insert(domains, Arc::downgrade(&manager));
+        manager
+    }
+
+    /// Installs the stored or a self signed certificate and starts issuing certificates, only
+    /// the first call does something.
+    fn start(self: &Arc<Self>) {
+        if self.started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        if let Err(err) = self.load_or_self_sign() {
+            println!(
+                "[ERROR] acme: failed to load certificate for {:?}: {}",
+                self.settings.domains, err
+            );
+        }
+
+        if self.settings.directory_url.is_some() {
+            match tokio::runtime::Handle::try_current() {
+                Ok(runtime) => {
+                    runtime.spawn(Manager::renew_loop(Arc::downgrade(self)));
+                }
+                Err(_) => println!("[ERROR] acme: no runtime to issue certificates"),
+            }
+        }
+    }
+
+    fn file_path(&self, extension: &str) -> PathBuf {
+        self.settings
+            .storage_path
+            .join(format!("{}.{}", self.settings.domains[0], extension))
+    }
+
+    fn load_stored(&self) -> Result<(SslKeyConfig, SslCertConfig)> {
+        let key = SslKeyConfig::from_pem(&std::fs::read(self.file_path("key"))?)
+            .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+        let cert = SslCertConfig::from_pem(&std::fs::read(self.file_path("crt"))?)
+            .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+        if !certificate::covers(&cert, &self.settings.domains) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "stored certificate doesn't cover the configured domains",
+            ));
+        }
+        Ok((key, cert))
+    }
+
+    fn store(&self, key: &[u8], cert: &[u8]) -> Result<(SslKeyConfig, SslCertConfig)> {
+        let key_config =
+            SslKeyConfig::from_pem(key).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+        let cert_config =
+            SslCertConfig::from_pem(cert).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+
+        std::fs::create_dir_all(&self.settings.storage_path)?;
+        write_file(&self.file_path("key"), key, PRIVATE_KEY_MODE)?;
+        write_file(&self.file_path("crt"), cert, CERTIFICATE_MODE)?;
+
+        Ok((key_config, cert_config))
+    }
+
+    /// Installs the stored certificate if there's one, otherwise installs a self signed
+    /// certificate so handshakes work until a certificate is issued.
+    fn load_or_self_sign(&self) -> Result<()> {
+        if let Ok((key, cert)) = self.load_stored()
+            && (!self.settings.self_signed
+                || !certificate::expires_within(&cert, self.settings.renew_before_days))
+        {
+            self.identity.set(key, cert);
+            return Ok(());
+        }
+
+        let to_io_error = |err| Error::new(ErrorKind::Other, err);
+        let key = PKey::from_ec_key(certificate::new_ec_key().map_err(to_io_error)?)
+            .map_err(to_io_error)?;
+        let cert = certificate::self_signed(&self.settings.domains, &key).map_err(to_io_error)?;
+        let key = key.private_key_to_pem_pkcs8().map_err(to_io_error)?;
+        let cert = cert.to_pem().map_err(to_io_error)?;
+
+        let (key, cert) = match self.settings.self_signed {
+            true => self.store(&key, &cert)?,
+            false => (
+                SslKeyConfig::from_pem(&key).map_err(to_io_error)?,
+                SslCertConfig::from_pem(&cert).map_err(to_io_error)?,
+            ),
+        };
+        self.identity.set(key, cert);
+        Ok(())
+    }
+
+    fn needs_renewal(&self) -> bool {
+        match self.load_stored() {
+            Ok((_, cert)) => certificate::expires_within(&cert, self.settings.renew_before_days),
+            Err(_) => true,
+        }
+    }
+
+    fn account_key(&self) -> Result<EcKey<openssl::pkey::Private>> {
+        let path = self.settings.storage_path.join("account.key");
+        if let Ok(pem) = std::fs::read(&path) {
+            return EcKey::private_key_from_pem(&pem)
+                .map_err(|err| Error::new(ErrorKind::InvalidData, err));
+        }
+
+        let to_io_error = |err| Error::new(ErrorKind::Other, err);
+        let key = certificate::new_ec_key().map_err(to_io_error)?;
+        std::fs::create_dir_all(&self.settings.storage_path)?;
+        let pem = key.private_key_to_pem().map_err(to_io_error)?;
+        write_file(&path, &pem, PRIVATE_KEY_MODE)?;
+        Ok(key)
+    }
+
+    async fn issue(&self) -> Result<()> {
+        let directory_url = self.settings.directory_url.as_deref().unwrap();
+        let mut client = Client::new(
+            directory_url,
+            self.settings.directory_ca_file.as_deref(),
+            self.account_key()?,
+        )
+        .await?;
+        client.register(self.settings.contact.as_deref()).await?;
+
+        let to_io_error = |err| Error::new(ErrorKind::Other, err);
+        let key = PKey::from_ec_key(certificate::new_ec_key().map_err(to_io_error)?)
+            .map_err(to_io_error)?;
+        let cert = client.issue(&self.settings.domains, &key).await?;
+
+        let (key, cert) =
+            self.store(&key.private_key_to_pem_pkcs8().map_err(to_io_error)?, &cert)?;
+        self.identity.set(key, cert);
+        Ok(())
+    }
+
+    async fn renew_loop(manager: Weak<Manager>) {
+        loop {
+            let manager = match manager.upgrade() {
+                Some(manager) => manager,
+                None => return,
+            };
+
+            let mut interval = RENEW_CHECK_INTERVAL;
+            if manager.needs_renewal() {
+                match manager.issue().await {
+                    Ok(_) => println!(
+                        "acme: issued certificate for {:?}",
+                        manager.settings.domains
+                    ),
+                    Err(err) => {
+                        println!(
+                            "[ERROR] acme: failed to issue certificate for {:?}: {}",
+                            manager.settings.domains, err
+                        );
+                        interval = RETRY_INTERVAL;
+                    }
+                }
+            }
+            drop(manager);
+
+            tokio::time::sleep(interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::Cursor,
+        os::unix::fs::PermissionsExt,
+        path::Path,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use jequi::{
+        http1::Http1Conn, ConfigError, ConfigMap, JequiConfig, Lifecycle, PhaseHandlers, Plugin,
+        RawStream, Uri,
+    };
+    use serde_yaml::{Mapping, Value};
+    use std::{path::PathBuf, sync::Arc};
+
+    use crate::{certificate, load_plugin, set_challenge, Config};
+
+    static TEST_PATH: &str = "test/";
+
+    #[tokio::test]
+    async fn handle_challenge_test() {
+        let mut http = Http1Conn::new(RawStream::Normal(Cursor::new(vec![])));
+        let conf = Arc::new(Config::default());
+
+        set_challenge("token", "token.thumbprint");
+
+        http.request.uri = Uri::from("/.well-known/acme-challenge/token".to_string());
+        conf.clone()
+            .handle_request(&mut http.request, &mut http.response)
+            .await;
+        assert_eq!(http.response.status, 200);
+        assert_eq!(&http.response.body_buffer[..], b"token.thumbprint");
+
+        let mut http = Http1Conn::new(RawStream::Normal(Cursor::new(vec![])));
+        http.request.uri = Uri::from("/.well-known/acme-challenge/other".to_string());
+        conf.handle_request(&mut http.request, &mut http.response)
+            .await;
+        assert_eq!(http.response.status, 0);
+    }
+
+    #[test]
+    fn load_config_test() {
+        let load = |yaml: &str| Config::load(&serde_yaml::from_str(yaml).unwrap(), &mut Vec::new());
+
+        let conf = Config::default();
+        assert_eq!(conf.acme_storage_path, PathBuf::from("acme/"));
+        assert_eq!(conf.acme_renew_before_days, 30);
+
+        // Only the directory url and acme_self_signed enable the plugin
+        assert!(load("acme_contact: mailto:admin@jequi.com")
+            .unwrap()
+            .is_none());
+
+        let err = load("acme_directory_url: https://acme.jequi.com").unwrap_err();
+        assert_eq!(err.key.as_deref(), Some("acme_domains"));
+
+        static LOADS: AtomicUsize = AtomicUsize::new(0);
+        let load_plugins = |config: &Value| -> Result<Vec<Plugin>, ConfigError> {
+            LOADS.fetch_add(1, Ordering::SeqCst);
+            let mut configs = vec![jequi::load_plugin(config, &mut Vec::new())?];
+            let acme = load_plugin(config, &mut configs)?;
+            Ok(configs.into_iter().chain([acme]).flatten().collect())
+        };
+        let err = ConfigMap::load(&format!("{}scopes.conf", TEST_PATH), load_plugins).unwrap_err();
+        assert_eq!(err.key.as_deref(), Some("acme_renew_before_days"));
+        assert_eq!(err.location.unwrap().line, 4);
+        assert_eq!(
+            err.message,
+            "can't be set in a path scope, only in: default, host"
+        );
+        // Found while checking the keys, before any plugin is loaded
+        assert_eq!(LOADS.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn self_signed_test() {
+        let storage_path = Path::new(TEST_PATH).join("self_signed");
+        let _ = fs::remove_dir_all(&storage_path);
+
+        let mut yaml_config = Mapping::new();
+        yaml_config.insert("acme_self_signed".into(), true.into());
+        yaml_config.insert(
+            "acme_storage_path".into(),
+            storage_path.to_string_lossy().to_string().into(),
+        );
+        yaml_config.insert("config_host".into(), "jequi.com".into());
+
+        let mut configs = vec![Some(Plugin {
+            name: "jequi",
+            config: Arc::new(jequi::Config::default()),
+            handlers: PhaseHandlers::default(),
+        })];
+        let acme = load_plugin(&Value::Mapping(yaml_config), &mut configs)
+            .unwrap()
+            .unwrap();
+        let acme_config = acme.config.clone();
+        let conf = acme_config.as_any().downcast_ref::<Config>().unwrap();
+
+        // Loading the config, like `--test-config` does, doesn't touch the storage
+        assert!(!storage_path.exists());
+
+        let config_map = ConfigMap {
+            config: configs.into_iter().flatten().chain([acme]).collect(),
+            ..Default::default()
+        };
+        Config::init(&config_map);
+
+        let main_conf = config_map.config[0]
+            .config
+            .as_any()
+            .downcast_ref::<jequi::Config>()
+            .unwrap();
+        let (_, cert) = main_conf.ssl_identity.get().unwrap();
+        assert!(certificate::covers(&cert, &["jequi.com".to_string()]));
+        let mode = |file| {
+            fs::metadata(storage_path.join(file))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777
+        };
+        assert_eq!(mode("jequi.com.key"), 0o600);
+        assert_eq!(mode("jequi.com.crt"), 0o644);
+        assert!(!storage_path.join("jequi.com.key.tmp").exists());
+
+        let manager = conf.manager.0.as_ref().unwrap();
+        manager.account_key().unwrap();
+        assert_eq!(mode("account.key"), 0o600);
+
+        assert_eq!(
+            conf.manager.0.as_ref().unwrap().identity,
+            main_conf.ssl_identity
+        );
+
+        fs::remove_dir_all(&storage_path).unwrap();
+    }
+}
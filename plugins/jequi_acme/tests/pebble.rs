@@ -0,0 +1,97 @@
+use std::{env, fs, path::Path, sync::Arc, time::Duration};
+
+use jequi::{http1::Http1Conn, ConfigMap, Lifecycle, RawStream};
+use jequi_acme::{load_plugin, Config};
+use openssl::x509::X509;
+use tokio::{net::TcpListener, time::sleep};
+
+// Port Pebble uses for the http-01 challenges, `httpPort` in its config
+const CHALLENGE_PORT: u16 = 5002;
+const ISSUE_ATTEMPTS: usize = 60;
+
+// Needs a running Pebble server (https://github.com/letsencrypt/pebble):
+//
+//     pebble -config test/config/pebble-config.json
+//     JEQUI_PEBBLE_CA_FILE=<pebble>/test/certs/pebble.minica.pem \
+//         cargo test -p jequi_acme --test pebble -- --ignored
+//
+// The challenges are served on port 5002 for `localhost`, `JEQUI_PEBBLE_DIRECTORY_URL` changes
+// the default `https://localhost:14000/dir`.
+#[tokio::test]
+#[ignore = "needs a Pebble server"]
+async fn pebble_test() {
+    let directory_url =
+        env::var("JEQUI_PEBBLE_DIRECTORY_URL").unwrap_or("https://localhost:14000/dir".to_string());
+    let ca_file = env::var("JEQUI_PEBBLE_CA_FILE").expect("JEQUI_PEBBLE_CA_FILE is not set");
+    let storage_path = Path::new("test/pebble");
+    let _ = fs::remove_dir_all(storage_path);
+
+    let config_yaml = serde_yaml::from_str(&format!(
+        "
+acme_directory_url: {}
+acme_directory_ca_file: {}
+acme_domains: [localhost]
+acme_storage_path: {}
+",
+        directory_url,
+        ca_file,
+        storage_path.display()
+    ))
+    .unwrap();
+    let mut configs = vec![jequi::load_plugin(&config_yaml, &mut Vec::new()).unwrap()];
+    let acme = load_plugin(&config_yaml, &mut configs).unwrap();
+    let config_map = Arc::new(ConfigMap {
+        config: configs.into_iter().chain([acme]).flatten().collect(),
+        ..Default::default()
+    });
+
+    let listener = TcpListener::bind(("127.0.0.1", CHALLENGE_PORT))
+        .await
+        .unwrap();
+    let challenges = {
+        let config_map = config_map.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let http = Http1Conn::new(RawStream::Normal(stream));
+                tokio::spawn(http.handle_connection(config_map.clone()));
+            }
+        })
+    };
+
+    Config::init(&config_map);
+
+    let cert_path = storage_path.join("localhost.crt");
+    for _ in 0..ISSUE_ATTEMPTS {
+        if cert_path.exists() {
+            break;
+        }
+        sleep(Duration::from_secs(1)).await;
+    }
+    challenges.abort();
+
+    let cert = X509::from_pem(&fs::read(&cert_path).unwrap()).unwrap();
+    let names: Vec<_> = cert
+        .subject_alt_names()
+        .unwrap()
+        .iter()
+        .filter_map(|name| name.dnsname().map(str::to_string))
+        .collect();
+    assert_eq!(names, ["localhost"]);
+    // Issued by Pebble, not the self signed certificate used until then
+    assert_ne!(
+        cert.issuer_name().to_der().unwrap(),
+        cert.subject_name().to_der().unwrap()
+    );
+
+    let main_conf = config_map.config[0]
+        .config
+        .as_any()
+        .downcast_ref::<jequi::Config>()
+        .unwrap();
+    // New handshakes use the issued certificate
+    let (_, served) = main_conf.ssl_identity.get().unwrap();
+    assert_eq!(served.chain()[0].to_der().unwrap(), cert.to_der().unwrap());
+
+    fs::remove_dir_all(storage_path).unwrap();
+}
@@ -7,6 +7,13 @@ package jequi_go
 //extern char* get_request_uri(void* req);
 //extern void set_request_uri(void* req, char* value);
 //extern char* get_request_method(void* req);
+//extern char* get_remote_addr(void* req);
+//extern char* get_local_addr(void* req);
+//extern char* get_scheme(void* req);
+//extern char* get_tls_version(void* req);
+//extern char* get_tls_cipher(void* req);
+//extern char* get_sni(void* req);
+//extern char* get_alpn(void* req);
 import "C"
 
 import (
@@ -52,3 +59,31 @@ func (r *Request) GetMethod() string {
 	method := C.get_request_method(r.pointer)
 	return cstring_to_string(method)
 }
+
+func (r *Request) GetRemoteAddr() string {
+	return cstring_to_string(C.get_remote_addr(r.pointer))
+}
+
+func (r *Request) GetLocalAddr() string {
+	return cstring_to_string(C.get_local_addr(r.pointer))
+}
+
+func (r *Request) GetScheme() string {
+	return cstring_to_string(C.get_scheme(r.pointer))
+}
+
+func (r *Request) GetTlsVersion() string {
+	return cstring_to_string(C.get_tls_version(r.pointer))
+}
+
+func (r *Request) GetTlsCipher() string {
+	return cstring_to_string(C.get_tls_cipher(r.pointer))
+}
+
+func (r *Request) GetSni() string {
+	return cstring_to_string(C.get_sni(r.pointer))
+}
+
+func (r *Request) GetAlpn() string {
+	return cstring_to_string(C.get_alpn(r.pointer))
+}
@@ -1,31 +1,15 @@
-#![feature(get_mut_unchecked)]
 use futures::future::FutureExt;
-use jequi::{JequiConfig, Plugin, PostRequestHandler, Request, RequestHandler, Response};
+use jequi::{ConfigError, Plugin, PostRequestHandler, Request, Response};
 use jequi_proxy::RequestProxyHandler;
 use libloading::Library;
-use plugins::get_plugin;
+use plugins::{get_plugin, JequiConfig};
 use serde::Deserialize;
-use serde_yaml::Value;
-use std::any::Any;
 use std::ffi::CStr;
 use std::os::raw::c_char;
 use std::sync::Arc;
 
-pub fn load_plugin(config_yaml: &Value, configs: &mut Vec<Option<Plugin>>) -> Option<Plugin> {
-    let config = Config::load(config_yaml, configs)?;
-    Some(Plugin {
-        config: config.clone(),
-        request_handler: RequestHandler(Some(Arc::new(
-            move |req: &mut Request, resp: &mut Response| {
-                let config = config.clone(); //TODO: figure out some way to avoid this clone
-                config.handle_request(req, resp).boxed()
-            },
-        ))),
-    })
-}
-
-#[derive(Default, Debug)]
-struct Lib(Option<Library>);
+#[derive(Default, Debug, Clone)]
+struct Lib(Option<Arc<Library>>);
 
 impl PartialEq for Lib {
     fn eq(&self, other: &Self) -> bool {
@@ -33,8 +17,28 @@ impl PartialEq for Lib {
     }
 }
 
-#[derive(Deserialize, Default, Debug, PartialEq)]
+impl Lib {
+    fn handle_request_proxy(&self, req: &mut Request, resp: &mut Response) -> Option<String> {
+        let lib = self.0.as_ref().unwrap();
+        unsafe {
+            let go_handle_proxy_request: libloading::Symbol<
+                unsafe extern "C" fn(req: *mut Request, resp: *mut Response) -> *const c_char,
+            > = lib.get(b"HandleProxyRequest\0").unwrap();
+            let address = go_handle_proxy_request(req, resp);
+            if address.is_null() {
+                return None;
+            }
+            return Some(CStr::from_ptr(address).to_str().unwrap().to_string());
+        }
+    }
+}
+
+#[derive(Deserialize, Default, Debug, PartialEq, JequiConfig)]
+#[jequi(content = handle_request, setup = setup, require(jequi_proxy))]
 pub struct Config {
+    /// Define the path of the compiled go shared lib that will be used to execute the go
+    /// functions.
+    #[jequi(activate)]
     pub go_library_path: Option<String>,
     #[serde(skip)]
     lib: Lib,
@@ -56,58 +60,25 @@ impl Config {
         PostRequestHandler::Continue
     }
 
-    fn handle_request_proxy(&self, req: &mut Request, resp: &mut Response) -> Option<String> {
-        let lib = self.lib.0.as_ref().unwrap();
-        unsafe {
-            let go_handle_proxy_request: libloading::Symbol<
-                unsafe extern "C" fn(req: *mut Request, resp: *mut Response) -> *const c_char,
-            > = lib.get(b"HandleProxyRequest\0").unwrap();
-            let address = go_handle_proxy_request(req, resp);
-            if address.is_null() {
-                return None;
-            }
-            return Some(CStr::from_ptr(address).to_str().unwrap().to_string());
-        }
-    }
-}
-
-impl JequiConfig for Config {
-    fn load(config_yaml: &Value, configs: &mut Vec<Option<Plugin>>) -> Option<Arc<Self>>
-    where
-        Self: Sized,
-    {
-        let mut conf: Config = Deserialize::deserialize(config_yaml).unwrap();
-        if conf == Config::default() {
-            return None;
-        }
-
-        unsafe {
-            let lib = Library::new(conf.go_library_path.as_ref().unwrap()).unwrap();
-            conf.lib = Lib(Some(lib));
-        }
-
-        let proxy_conf = get_plugin!(configs, jequi_proxy, mut Option);
-
-        let conf = Arc::new(conf);
-        if let Some(proxy_conf) = proxy_conf {
-            let conf2 = conf.clone();
-
+    fn setup(&mut self, configs: &mut Vec<Option<Plugin>>) -> Result<(), ConfigError> {
+        let go_library_path = self.go_library_path.as_ref().ok_or_else(|| {
+            ConfigError::new("go_library_path is not set").with_key("go_library_path")
+        })?;
+        let lib = unsafe { Library::new(go_library_path) }.map_err(|err| {
+            ConfigError::new(format!("can't load library: {}", err)).with_key("go_library_path")
+        })?;
+        self.lib = Lib(Some(Arc::new(lib)));
+
+        if let Some(proxy_conf) = get_plugin!(configs, jequi_proxy, mut Option) {
+            let lib = self.lib.clone();
             proxy_conf.add_proxy_handler(RequestProxyHandler(Some(Arc::new(
                 move |req: &mut Request, resp: &mut Response| {
-                    let config = conf2.clone(); //TODO: figure out some way to avoid this clone
-                    Some(async move { config.handle_request_proxy(req, resp) }.boxed())
+                    let lib = lib.clone(); //TODO: figure out some way to avoid this clone
+                    Some(async move { lib.handle_request_proxy(req, resp) }.boxed())
                 },
             ))));
         }
-
-        Some(conf)
-    }
-    fn as_any(&self) -> &dyn Any {
-        self
-    }
-
-    fn as_any_mut(&mut self) -> &mut dyn Any {
-        self
+        Ok(())
     }
 }
 
@@ -151,7 +122,9 @@ mod tests {
         let mut yaml_config = Mapping::new();
         yaml_config.insert("go_library_path".into(), go_library_path.clone().into());
 
-        let conf = Config::load(&Value::Mapping(yaml_config), &mut Vec::new()).unwrap();
+        let conf = Config::load(&Value::Mapping(yaml_config), &mut Vec::new())
+            .unwrap()
+            .unwrap();
 
         http.request.uri = Uri::from("/file".to_string());
 
@@ -165,4 +138,11 @@ mod tests {
             &http.request.uri.raw()
         );
     }
+
+    #[test]
+    fn setup_without_library_test() {
+        let err = Config::default().setup(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.message, "go_library_path is not set");
+        assert_eq!(err.key.as_deref(), Some("go_library_path"));
+    }
 }
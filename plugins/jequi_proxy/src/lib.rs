@@ -10,16 +10,15 @@ use http::uri::Scheme;
 use hyper::body::{self};
 use hyper::client::conn;
 use hyper_tls::HttpsConnector;
+use jequi::config_schema;
 use jequi::hijack::DynAsyncRWSend;
 use jequi::http1::Http1Conn;
-use jequi::{
-    JequiConfig, Plugin, PostRequestHandler, RawStream, Request, RequestHandler, Response, Uri,
-};
+use jequi::{PostRequestHandler, RawStream, Request, Response, Uri};
 use openssl::ssl::{Ssl, SslAcceptor, SslConnector, SslMethod};
+use plugins::JequiConfig;
 use rand::seq::SliceRandom;
 use serde::{de, Deserialize};
-use serde_yaml::Value;
-use std::any::Any;
+use serde_json::json;
 use std::ffi::CStr;
 use std::fmt;
 use std::ops::Deref;
@@ -45,17 +44,6 @@ pub unsafe extern "C" fn set_request_uri(req: *mut Request, value: *const c_char
     req.uri = Uri::from(uri);
 }
 
-pub fn load_plugin(config_yaml: &Value, configs: &mut Vec<Option<Plugin>>) -> Option<Plugin> {
-    let config = Config::load(config_yaml, configs)?;
-    Some(Plugin {
-        config: config.clone(),
-        request_handler: RequestHandler(Some(Arc::new(move |req, resp| {
-            let config = config.clone(); //TODO: figure out some way to avoid this clone
-            config.handle_request(req, resp).boxed()
-        }))),
-    })
-}
-
 impl PartialEq for Config {
     fn eq(&self, other: &Self) -> bool {
         self.proxy_address == other.proxy_address
@@ -84,8 +72,23 @@ pub enum ProxyAddress {
     Addresses(Vec<String>),
 }
 
-#[derive(Deserialize, Default, Debug)]
+jequi::config_type!(
+    ProxyAddress,
+    "string or list of strings",
+    (config_schema::string_or_list_schema()),
+    |address| match address {
+        ProxyAddress::Address(address) => json!(address),
+        ProxyAddress::Addresses(addresses) => json!(addresses),
+    }
+);
+
+#[derive(Deserialize, Default, Debug, JequiConfig)]
+#[jequi(content = handle_request)]
 pub struct Config {
+    /// Define the upstream address that the server will proxy, the address can be an ip or
+    /// domain and can have a port specified. With a list of addresses one of them is picked at
+    /// random for each request.
+    #[jequi(activate)]
     pub proxy_address: Option<ProxyAddress>,
     #[serde(skip)]
     proxy_handlers: Option<Vec<RequestProxyHandler>>,
@@ -192,24 +195,3 @@ impl Config {
         PostRequestHandler::Continue
     }
 }
-
-impl JequiConfig for Config {
-    fn load(config_yaml: &Value, _configs: &mut Vec<Option<Plugin>>) -> Option<Arc<Self>>
-    where
-        Self: Sized,
-    {
-        let conf: Config = Deserialize::deserialize(config_yaml).unwrap();
-        if conf == Config::default() {
-            return None;
-        }
-
-        Some(Arc::new(conf))
-    }
-    fn as_any(&self) -> &dyn Any {
-        self
-    }
-
-    fn as_any_mut(&mut self) -> &mut dyn Any {
-        self
-    }
-}
@@ -0,0 +1,5 @@
+// With `links`, the server gets the name of the crate and links it, see server/build.rs
+fn main() {
+    let name = std::env::var("CARGO_PKG_NAME").unwrap();
+    println!("cargo:jequi_plugin={}", name.replace('-', "_"));
+}
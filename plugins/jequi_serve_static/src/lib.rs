@@ -1,31 +1,14 @@
 mod content_type;
-use derivative::Derivative;
-use futures::future::FutureExt;
-use jequi::{JequiConfig, Plugin, PostRequestHandler, Request, RequestHandler, Response};
+use jequi::{PathPattern, PostRequestHandler, Request, Response};
+use plugins::JequiConfig;
 use serde::{de, Deserialize};
-use serde_yaml::Value;
-
 use std::{
-    any::Any,
     fmt,
     io::ErrorKind,
     path::{Path, PathBuf},
     sync::Arc,
 };
 
-pub fn load_plugin(config_yaml: &Value, configs: &mut Vec<Option<Plugin>>) -> Option<Plugin> {
-    let config = Config::load(config_yaml, configs)?;
-    Some(Plugin {
-        config: config.clone(),
-        request_handler: RequestHandler(Some(Arc::new(
-            move |req: &mut Request, resp: &mut Response| {
-                let config = config.clone(); //TODO: figure out some way to avoid this clone
-                config.handle_request(req, resp).boxed()
-            },
-        ))),
-    })
-}
-
 #[derive(PartialEq, Clone, Debug)]
 pub enum PathKind {
     Dir(PathBuf),
@@ -73,20 +56,33 @@ impl<'de> Deserialize<'de> for PathKind {
     }
 }
 
+jequi::config_type!(PathKind, "string", {"type": "string"}, |path| match path {
+    PathKind::Dir(path) | PathKind::File(path) => path,
+});
+
 impl Default for PathKind {
     fn default() -> Self {
         Self::Dir(PathBuf::new())
     }
 }
 
-#[derive(Deserialize, Clone, Debug, PartialEq, Derivative)]
-#[derivative(Default)]
+#[derive(Deserialize, Clone, Debug, PartialEq, JequiConfig)]
 #[serde(default)]
+#[jequi(content = handle_request, before(jequi_proxy))]
 pub struct Config {
+    /// Sets the path to serve static files, if the path is a directory it will serve the files
+    /// based on the request uri, if it is a file, it will serve the file always.
+    #[jequi(activate)]
     pub static_files_path: Option<PathKind>,
-    #[derivative(Default(value = "true"))]
+    /// Sets the `Content-Type` header of the response from the extension of the file served.
+    #[jequi(default = true)]
     pub infer_content_type: bool,
+    /// File sent as the body of the 404 response when the requested file doesn't exist.
     pub not_found_file_path: Option<PathBuf>,
+    /// Path of the file to serve inside `static_files_path` instead of the request uri, `$1` and
+    /// `$name` are replaced by the groups captured by the regex path of the request. For example
+    /// `static_files_rewrite: /images/$1.png` in the path `~ ^/img/(.+)$`.
+    pub static_files_rewrite: Option<String>,
     config_path: Option<String>,
 }
 
@@ -100,8 +96,16 @@ impl Config {
         match self.static_files_path.as_ref().unwrap() {
             PathKind::File(file_path) => final_path.push(file_path),
             PathKind::Dir(dir_path) => {
+                let rewritten;
                 let mut path = req.uri.path();
-                if let Some(path_config) = self.config_path.as_deref() {
+                if let Some(rewrite) = self.static_files_rewrite.as_deref() {
+                    rewritten = req.path_captures.expand(rewrite);
+                    path = &rewritten;
+                } else if let Some(path_config) = self
+                    .config_path
+                    .as_deref()
+                    .and_then(PathPattern::key_prefix)
+                {
                     path = path.strip_prefix(path_config).unwrap_or(path);
                 }
                 path = path.trim_start_matches('/');
@@ -152,26 +156,6 @@ impl Config {
     }
 }
 
-impl JequiConfig for Config {
-    fn load(config_yaml: &Value, _configs: &mut Vec<Option<Plugin>>) -> Option<Arc<Self>>
-    where
-        Self: Sized,
-    {
-        let conf: Config = Deserialize::deserialize(config_yaml).unwrap();
-        if conf == Config::default() || conf.static_files_path.is_none() {
-            return None;
-        }
-
-        Some(Arc::new(conf))
-    }
-    fn as_any(&self) -> &dyn Any {
-        self
-    }
-    fn as_any_mut(&mut self) -> &mut dyn Any {
-        self
-    }
-}
-
 #[cfg(test)]
 mod tests {
 
@@ -186,7 +170,7 @@ mod tests {
     };
 
     use http::HeaderValue;
-    use jequi::{http1::Http1Conn, AsyncRWSendBuf, RawStream, Uri};
+    use jequi::{http1::Http1Conn, AsyncRWSendBuf, PathMatcher, RawStream, Uri};
     use tokio::io::{AsyncRead, AsyncWrite};
 
     use crate::{Config, PathKind};
@@ -267,6 +251,27 @@ mod tests {
 
         test_handle_request(conf.clone(), &mut http, "/uri/file", 200, b"hello").await;
 
+        // Prefix with modifier test
+        Arc::get_mut(conf).unwrap().config_path = Some("^~ /uri".to_string());
+
+        test_handle_request(conf.clone(), &mut http, "/uri/file", 200, b"hello").await;
+
+        // Rewrite with regex captures test
+        let images_path = "~ ^/images/(?<name>.+)\\.txt$";
+        let mut path_matcher = PathMatcher::default();
+        path_matcher.push(images_path.into()).unwrap();
+        http.request.path_captures = path_matcher.find("/images/file.txt").unwrap().1;
+        Arc::get_mut(conf).unwrap().config_path = Some(images_path.to_string());
+        Arc::get_mut(conf).unwrap().static_files_rewrite = Some("/$name".to_string());
+
+        test_handle_request(conf.clone(), &mut http, "/images/file.txt", 200, b"hello").await;
+
+        // The rewritten path can't leave the static files directory
+        Arc::get_mut(conf).unwrap().static_files_rewrite = Some("/../../$name".to_string());
+
+        test_handle_request(conf.clone(), &mut http, "/images/file.txt", 200, b"hello").await;
+        Arc::get_mut(conf).unwrap().static_files_rewrite = None;
+
         // File as path test
         Arc::get_mut(conf).unwrap().static_files_path =
             Some(PathKind::File(format!("{}file", TEST_PATH).into()));
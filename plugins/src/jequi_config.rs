@@ -0,0 +1,385 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{
+    meta::ParseNestedMeta, Data, DeriveInput, Expr, Fields, Ident, Lit, Meta, Result, Token, Type,
+    Visibility,
+};
+
+// Attributes of the struct that set the handler of a phase, named like the fields of
+// `PhaseHandlers`
+const PHASES: &[&str] = &["post_read", "rewrite", "access", "content", "log"];
+
+// Attributes of the struct for the filter phases, the config implements the trait itself
+const FILTERS: &[&str] = &["header_filter", "body_filter"];
+
+#[derive(Default)]
+struct Options {
+    // Phase and the method handling it
+    handlers: Vec<(Ident, Ident)>,
+    filters: Vec<Ident>,
+    // Whether the struct implements `Lifecycle` itself
+    lifecycle: bool,
+    // Names of the plugins in `require(...)`, `after(...)` and `before(...)`
+    requires: Vec<String>,
+    after: Vec<String>,
+    before: Vec<String>,
+    validate: Option<Ident>,
+    setup: Option<Ident>,
+    scopes: Option<Vec<Ident>>,
+}
+
+fn parse_scopes(meta: &ParseNestedMeta) -> Result<Vec<Ident>> {
+    let mut scopes = Vec::new();
+    meta.parse_nested_meta(|scope| {
+        let kind = match scope.path.get_ident().map(ToString::to_string).as_deref() {
+            Some("default") => "Default",
+            Some("host") => "Host",
+            Some("path") => "Path",
+            _ => return Err(scope.error("expected default, host or path")),
+        };
+        scopes.push(Ident::new(kind, scope.path.get_ident().unwrap().span()));
+        Ok(())
+    })?;
+    Ok(scopes)
+}
+
+fn parse_plugin_names(meta: &ParseNestedMeta, names: &mut Vec<String>) -> Result<()> {
+    meta.parse_nested_meta(|plugin| match plugin.path.get_ident() {
+        Some(name) => {
+            names.push(name.to_string());
+            Ok(())
+        }
+        None => Err(plugin.error("expected a plugin name")),
+    })
+}
+
+fn parse_options(input: &DeriveInput) -> Result<Options> {
+    let mut options = Options::default();
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("jequi"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if let Some(phase) = meta.path.get_ident() {
+                if PHASES.contains(&phase.to_string().as_str()) {
+                    options
+                        .handlers
+                        .push((phase.clone(), meta.value()?.parse()?));
+                    return Ok(());
+                }
+                if FILTERS.contains(&phase.to_string().as_str()) {
+                    options.filters.push(phase.clone());
+                    return Ok(());
+                }
+            }
+            let hook = if meta.path.is_ident("validate") {
+                &mut options.validate
+            } else if meta.path.is_ident("setup") {
+                &mut options.setup
+            } else if meta.path.is_ident("scopes") {
+                options.scopes = Some(parse_scopes(&meta)?);
+                return Ok(());
+            } else if meta.path.is_ident("lifecycle") {
+                options.lifecycle = true;
+                return Ok(());
+            } else if meta.path.is_ident("require") {
+                return parse_plugin_names(&meta, &mut options.requires);
+            } else if meta.path.is_ident("after") {
+                return parse_plugin_names(&meta, &mut options.after);
+            } else if meta.path.is_ident("before") {
+                return parse_plugin_names(&meta, &mut options.before);
+            } else {
+                return Err(meta.error(
+                    "expected a phase, validate, setup, scopes, lifecycle, require, after or before",
+                ));
+            };
+            *hook = Some(meta.value()?.parse()?);
+            Ok(())
+        })?;
+    }
+    Ok(options)
+}
+
+struct Field {
+    name: Ident,
+    ty: Type,
+    activate: bool,
+    default: Option<Expr>,
+    scopes: Option<Vec<Ident>>,
+    // Only public fields that are deserialized are options
+    option: bool,
+    doc: String,
+}
+
+fn serde_skip(attr: &syn::Attribute) -> Result<bool> {
+    let mut skip = false;
+    attr.parse_nested_meta(|meta| {
+        skip |= meta.path.is_ident("skip") || meta.path.is_ident("skip_deserializing");
+        if meta.input.peek(Token![=]) {
+            meta.value()?.parse::<Expr>()?;
+        }
+        Ok(())
+    })?;
+    Ok(skip)
+}
+
+fn doc_line(attr: &syn::Attribute) -> Option<String> {
+    let Meta::NameValue(meta) = &attr.meta else {
+        return None;
+    };
+    match &meta.value {
+        Expr::Lit(expr) => match &expr.lit {
+            Lit::Str(doc) => {
+                let doc = doc.value();
+                Some(doc.strip_prefix(' ').unwrap_or(&doc).to_string())
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Lines wrapped in the doc comment are joined, blank lines, list items and code blocks are kept
+fn unwrap_doc(lines: &[String]) -> String {
+    let mut doc = String::new();
+    let mut code = false;
+    let mut previous = "";
+    for line in lines {
+        let fence = line.starts_with("```");
+        let separator = if doc.is_empty() {
+            ""
+        } else if code || fence || line.is_empty() || previous.is_empty() || line.starts_with("- ")
+        {
+            "\n"
+        } else {
+            " "
+        };
+        doc.push_str(separator);
+        doc.push_str(line);
+        code ^= fence;
+        previous = line;
+    }
+    doc
+}
+
+fn parse_fields(input: &DeriveInput) -> Result<Vec<Field>> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(syn::Error::new_spanned(input, "expected named fields")),
+        },
+        _ => return Err(syn::Error::new_spanned(input, "expected a struct")),
+    };
+
+    let mut parsed = Vec::new();
+    for field in fields {
+        let mut parsed_field = Field {
+            name: field.ident.clone().unwrap(),
+            ty: field.ty.clone(),
+            activate: false,
+            default: None,
+            scopes: None,
+            option: matches!(field.vis, Visibility::Public(_)),
+            doc: String::new(),
+        };
+        let mut doc = Vec::new();
+        for attr in &field.attrs {
+            if attr.path().is_ident("serde") && serde_skip(attr)? {
+                parsed_field.option = false;
+            } else if attr.path().is_ident("doc") {
+                doc.extend(doc_line(attr));
+            }
+        }
+        parsed_field.doc = unwrap_doc(&doc);
+        for attr in field
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("jequi"))
+        {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("activate") {
+                    parsed_field.activate = true;
+                } else if meta.path.is_ident("default") {
+                    parsed_field.default = Some(meta.value()?.parse()?);
+                } else if meta.path.is_ident("scopes") {
+                    parsed_field.scopes = Some(parse_scopes(&meta)?);
+                } else {
+                    return Err(meta.error("expected activate, default or scopes"));
+                }
+                Ok(())
+            })?;
+        }
+        parsed.push(parsed_field);
+    }
+    Ok(parsed)
+}
+
+fn options_impl(name: &Ident, scopes: &Option<Vec<Ident>>, fields: &[Field]) -> TokenStream {
+    let all_scopes = [
+        Ident::new("Default", name.span()),
+        Ident::new("Host", name.span()),
+        Ident::new("Path", name.span()),
+    ];
+    let options = fields.iter().filter(|field| field.option).map(|field| {
+        let Field { name, ty, doc, .. } = field;
+        let key = name.to_string();
+        let scopes = field.scopes.as_ref().or(scopes.as_ref());
+        let scopes = scopes.map_or(&all_scopes[..], Vec::as_slice);
+        quote! {
+            ::jequi::OptionInfo {
+                key: #key,
+                type_name: <#ty as ::jequi::config_schema::ConfigType>::type_name(),
+                schema: <#ty as ::jequi::config_schema::ConfigType>::schema(),
+                default: ::jequi::config_schema::ConfigType::to_json(&default.#name),
+                scopes: &[#(::jequi::ScopeKind::#scopes),*],
+                description: #doc,
+            }
+        }
+    });
+
+    quote! {
+        impl ::jequi::ConfigOptions for #name {
+            fn options() -> std::vec::Vec<::jequi::OptionInfo> {
+                let default = <Self as std::default::Default>::default();
+                std::vec![#(#options),*]
+            }
+        }
+    }
+}
+
+pub fn derive_options(input: DeriveInput) -> Result<TokenStream> {
+    let options = parse_options(&input)?;
+    let fields = parse_fields(&input)?;
+    Ok(options_impl(&input.ident, &options.scopes, &fields))
+}
+
+pub fn derive(input: DeriveInput) -> Result<TokenStream> {
+    let options = parse_options(&input)?;
+    let fields = parse_fields(&input)?;
+    let name = &input.ident;
+
+    let options_impl = options_impl(name, &options.scopes, &fields);
+
+    // Without activating fields the plugin is used when any option is set
+    let activate: Vec<_> = fields
+        .iter()
+        .filter(|field| field.activate)
+        .map(|field| &field.name)
+        .collect();
+    let inactive = match activate.is_empty() {
+        true => quote!(conf == <Self as std::default::Default>::default()),
+        false => quote! {{
+            let default = <Self as std::default::Default>::default();
+            #(conf.#activate == default.#activate)&&*
+        }},
+    };
+
+    let validate = options.validate.map(|validate| quote!(conf.#validate()?;));
+    let (configs, setup) = match options.setup {
+        Some(setup) => (quote!(configs), quote!(conf.#setup(configs)?;)),
+        None => (quote!(_configs), quote!()),
+    };
+
+    let handlers = options.handlers.iter().map(|(phase, handler)| {
+        quote! {
+            #phase: ::jequi::RequestHandler(std::option::Option::Some(std::sync::Arc::new({
+                let config = std::sync::Arc::clone(&config);
+                move |req: &mut ::jequi::Request, resp: &mut ::jequi::Response| {
+                    let config = std::sync::Arc::clone(&config);
+                    ::jequi::futures::future::FutureExt::boxed(config.#handler(req, resp))
+                }
+            }))),
+        }
+    });
+    let filters = options.filters.iter().map(|filter| {
+        quote! {
+            #filter: std::option::Option::Some(std::sync::Arc::<#name>::clone(&config)),
+        }
+    });
+
+    let (requires, after, before) = (&options.requires, &options.after, &options.before);
+    let lifecycle_impl = (!options.lifecycle).then(|| quote!(impl ::jequi::Lifecycle for #name {}));
+
+    let default_impl = fields.iter().any(|field| field.default.is_some()).then(|| {
+        let defaults = fields.iter().map(|field| {
+            let name = &field.name;
+            match &field.default {
+                Some(default) => quote!(#name: #default),
+                None => quote!(#name: std::default::Default::default()),
+            }
+        });
+        quote! {
+            impl std::default::Default for #name {
+                fn default() -> Self {
+                    Self { #(#defaults),* }
+                }
+            }
+        }
+    });
+
+    Ok(quote! {
+        impl ::jequi::JequiConfig for #name {
+            fn load(
+                config_yaml: &::jequi::serde_yaml::Value,
+                #configs: &mut std::vec::Vec<std::option::Option<::jequi::Plugin>>,
+            ) -> std::result::Result<std::option::Option<std::sync::Arc<Self>>, ::jequi::ConfigError>
+            {
+                #[allow(unused_mut)]
+                let mut conf: Self = ::jequi::config::deserialize(config_yaml)?;
+                if #inactive {
+                    return std::result::Result::Ok(std::option::Option::None);
+                }
+                #validate
+                #setup
+                std::result::Result::Ok(std::option::Option::Some(std::sync::Arc::new(conf)))
+            }
+
+            fn as_any(&self) -> &dyn std::any::Any {
+                self
+            }
+
+            fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+                self
+            }
+        }
+
+        #default_impl
+
+        #lifecycle_impl
+
+        #options_impl
+
+        pub fn load_plugin(
+            config_yaml: &::jequi::serde_yaml::Value,
+            configs: &mut std::vec::Vec<std::option::Option<::jequi::Plugin>>,
+        ) -> std::result::Result<std::option::Option<::jequi::Plugin>, ::jequi::ConfigError> {
+            let std::option::Option::Some(config) =
+                <#name as ::jequi::JequiConfig>::load(config_yaml, configs)?
+            else {
+                return std::result::Result::Ok(std::option::Option::None);
+            };
+            std::result::Result::Ok(std::option::Option::Some(::jequi::Plugin {
+                name: std::env!("CARGO_PKG_NAME"),
+                config: std::sync::Arc::<#name>::clone(&config),
+                handlers: ::jequi::PhaseHandlers {
+                    #(#handlers)*
+                    #(#filters)*
+                    ..std::default::Default::default()
+                },
+            }))
+        }
+
+        ::jequi::inventory::submit! {
+            ::jequi::PluginRegistration {
+                name: std::env!("CARGO_PKG_NAME"),
+                load: load_plugin,
+                options: <#name as ::jequi::ConfigOptions>::options,
+                requires: &[#(#requires),*],
+                after: &[#(#after),*],
+                before: &[#(#before),*],
+                lifecycle: ::jequi::LifecycleHooks::of::<#name>(),
+            }
+        }
+    })
+}
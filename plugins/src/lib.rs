@@ -1,7 +1,8 @@
+mod jequi_config;
+
 use proc_macro::TokenStream;
-use quote::{format_ident, quote, ToTokens};
-use std::{collections::HashMap, fs};
-use syn::{parse::Parse, parse_macro_input, token::Mut, Ident, PatIdent, Token};
+use quote::{quote, ToTokens};
+use syn::{parse::Parse, parse_macro_input, token::Mut, DeriveInput, Ident, PatIdent, Token};
 
 struct MacroInput {
     list: Ident,
@@ -25,119 +26,64 @@ impl Parse for MacroInput {
     }
 }
 
+/// Gets the config of a plugin from a `ConfigList`, or from the list of plugins being loaded with
+/// `get_plugin!(list, plugin, Option)` (`mut Option` to change it). Plugins are found by the
+/// type of their config, not by their position in the list.
 #[proc_macro]
 pub fn get_plugin(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as MacroInput);
 
-    let plugin_name = input.plugin.to_string();
-
-    let mut contents = match fs::read_to_string("plugins.txt") {
-        Ok(content) => content,
-        Err(err) => {
-            let err = format!("error opening plugins file: {}", err);
-            return quote! {compile_error!(#err)}.into();
-        }
-    };
-
-    contents.insert_str(0, "jequi\n");
-
-    let mut index = None;
-    for (i, line) in contents.lines().enumerate() {
-        let line = line.split('-').next().unwrap().trim();
-        if line == plugin_name {
-            index = Some(i)
-        }
-    }
-
-    let index = match index {
-        Some(i) => i,
-        None => return quote! {compile_error!("invalid plugin name")}.into(),
-    };
-
     let list = input.list;
     let plugin_name = input.plugin;
 
-    let get_plugin = match input.plugin_type {
-        Some(plugin_type) => {
-            if plugin_type == "Option" {
-                if input.mutability.is_some() {
-                    quote!(match #list.get_mut(#index) {
-                    Some(config) => config.as_mut().map(|plugin| {
-                        unsafe { Arc::get_mut_unchecked(&mut plugin.config) }
-                        .as_any_mut()
-                        .downcast_mut::<#plugin_name::Config>()
-                        .unwrap()}),
-                    None => None,
-                    })
-                } else {
-                    quote!(match #list.get(#index){
-                        Some(config) => config.as_mut().map(|plugin| {
-                        plugin.config
-                            .as_any()
-                            .downcast_ref::<#plugin_name::Config>()
-                            .unwrap()}),
-                        None => None,
-                    })
-                }
-            } else {
-                return quote! {compile_error!("invalid plugin name")}.into();
-            }
+    match input.plugin_type {
+        Some(plugin_type) if plugin_type != "Option" => {
+            quote! {compile_error!("invalid plugin type, expected Option")}
         }
-        None => {
-            quote!(match #list.get(#index) {
-                Some(config) => config.config.as_any().downcast_ref::<#plugin_name::Config>(),
-                None => None,
-            })
-        }
-    };
-
-    get_plugin.into()
-}
-
-#[proc_macro]
-pub fn load_plugins(_input: TokenStream) -> TokenStream {
-    let contents = match fs::read_to_string("plugins.txt") {
-        Ok(content) => content,
-        Err(err) => {
-            let err = format!("error opening plugins file: {}", err);
-            return quote! {compile_error!(#err)}.into();
-        }
-    };
-
-    let mut add_after = HashMap::new();
-
-    let mut plugins = Vec::new();
-    let mut indexes = Vec::new();
-    for (i, line) in contents.lines().enumerate() {
-        let mut info = line.split('-');
-        let plugin = info.next().unwrap().trim();
-        if let Some(info) = info.next() {
-            let requires = info.trim().strip_prefix("require ").unwrap().trim();
-            add_after.insert(requires, (plugin, i + 1));
-            continue;
-        }
-        plugins.push(format_ident!("{}", plugin));
-        indexes.push(i + 1);
-        if let Some((plugin, i)) = add_after.get(plugin) {
-            plugins.push(format_ident!("{}", plugin));
-            indexes.push(*i);
+        _ if input.mutability.is_some() => {
+            quote!(jequi::plugin::get_mut::<#plugin_name::Config>(&mut *#list))
         }
+        _ => quote!(jequi::plugin::get::<#plugin_name::Config>(&*#list)),
     }
+    .into()
+}
 
-    let size = plugins.len() + 1;
+/// Implements `JequiConfig` for a plugin config, generates its `load_plugin` function and
+/// registers the plugin in `jequi::registry`, so depending on the crate is enough to use it.
+///
+/// Options of the struct, in `#[jequi(...)]`:
+/// - `post_read`, `rewrite`, `access`, `content` or `log` `= method`: async method called for
+///   every request in that phase, see `jequi::Phase`
+/// - `header_filter`, `body_filter`: the struct implements `jequi::HeaderFilter` or
+///   `jequi::BodyFilter`
+/// - `validate = method`: `fn(&self) -> Result<(), ConfigError>` called once the plugin is used
+/// - `setup = method`: `fn(&mut self, &mut Vec<Option<Plugin>>) -> Result<(), ConfigError>`
+///   called after `validate`, with the plugins of the scope loaded before this one
+/// - `scopes(default, host, path)`: scopes the options can be set in, all of them by default
+/// - `lifecycle`: the struct implements `jequi::Lifecycle`, otherwise it gets the default hooks
+/// - `require(plugin, ...)`: plugins it uses, loaded before it, they must be linked too
+/// - `after(plugin, ...)`, `before(plugin, ...)`: plugins loaded before or after it, when they
+///   are linked
+///
+/// Fields marked `#[jequi(activate)]` enable the plugin when set, without them any option does.
+/// `#[jequi(default = expr)]` generates the `Default` implementation using `expr` for the field.
+///
+/// It also implements `ConfigOptions`, like `#[derive(ConfigOptions)]`.
+#[proc_macro_derive(JequiConfig, attributes(jequi))]
+pub fn derive_jequi_config(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    jequi_config::derive(input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
 
-    (quote! {
-    pub fn load_plugins(config: &serde_yaml::Value) -> jequi::ConfigList {
-        let mut plugins: Vec<Option<jequi::Plugin>> = Vec::with_capacity(#size);
-        plugins.resize_with(#size, Default::default);
-        let plugin = jequi::load_plugin(config, &mut plugins).expect("main config is required");
-        plugins.insert(0, Some(plugin));
-        #(
-        let plugin = #plugins::load_plugin(config, &mut plugins);
-        plugins.insert(#indexes,plugin);
-        )*
-        plugins.into_iter().flatten().collect::<Vec<_>>()
-        }
-    })
-    .into()
+/// Implements `ConfigOptions` with the public fields that aren't `#[serde(skip)]`, the doc
+/// comment of the field is the description of the option. The scopes are the ones of the
+/// struct, `#[jequi(scopes(...))]` in a field overrides them.
+#[proc_macro_derive(ConfigOptions, attributes(jequi))]
+pub fn derive_config_options(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    jequi_config::derive_options(input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
 }
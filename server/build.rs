@@ -0,0 +1,30 @@
+use std::{env, fs, path::Path};
+
+// Key of the metadata printed by the build script of every plugin, see `links` in their
+// Cargo.toml
+const PLUGIN_METADATA: &str = "JEQUI_PLUGIN";
+
+// Plugins register themselves when their crate is linked, but a dependency that is never used
+// isn't linked. Every plugin sets `links` and prints `cargo:jequi_plugin=<crate>` from its build
+// script, so Cargo passes `DEP_<LINKS>_JEQUI_PLUGIN` here for each plugin dependency that is
+// built, wherever it comes from: the dependencies that aren't optional and the optional ones
+// enabled with Cargo features. Generates `extern crate` for each of them in
+// `OUT_DIR/plugins.rs`, `include!`d by the server. Their `require`, `after` and `before` are
+// checked when the server sorts them, see `jequi::registry`.
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let suffix = format!("_{}", PLUGIN_METADATA);
+    let mut plugins: Vec<String> = env::vars()
+        .filter(|(key, _)| key.starts_with("DEP_") && key.ends_with(&suffix))
+        .map(|(_, name)| name)
+        .collect();
+    plugins.sort();
+
+    let mut generated = String::new();
+    for name in plugins {
+        generated.push_str(&format!("extern crate {};\n", name));
+    }
+    let out_dir = env::var("OUT_DIR").unwrap();
+    fs::write(Path::new(&out_dir).join("plugins.rs"), generated).unwrap();
+}
@@ -1,21 +1,86 @@
 #![feature(let_chains)]
-use jequi::{Config, ConfigMap, HttpConn};
-use plugins::load_plugins;
+use jequi::{
+    config_schema,
+    lifecycle::{reload, shutdown_when_closed},
+    registry::{config_options, load_plugins},
+    ssl::file_identities,
+    Config, ConfigMap, ConnInfo, HttpConn, Lifecycle, ListenConfig, OpenConnection,
+    PluginLifecycle,
+};
+use notify::{RecursiveMode, Watcher};
+use plugins::get_plugin;
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
 use std::pin::Pin;
 use std::process;
+use std::time::Duration;
 use std::{fs, sync::Arc};
 use tokio::{
     net::{TcpListener, TcpStream},
     signal::unix::{signal, SignalKind},
     spawn,
-    sync::RwLock,
+    sync::{mpsc::unbounded_channel, RwLock},
+    time::{sleep, timeout},
 };
 
-load_plugins!();
+const CONFIG_PATH: &str = "conf.yaml";
+
+// How often the watched certificate directories are updated after a config reload
+const CERTIFICATE_WATCH_REFRESH: Duration = Duration::from_secs(5);
+// Certificate and key are usually replaced together, wait a bit so both are read
+const CERTIFICATE_RELOAD_DELAY: Duration = Duration::from_millis(500);
+// With `watch_config`, the config is reloaded once its files stop changing for this long
+const CONFIG_RELOAD_DEBOUNCE: Duration = Duration::from_millis(500);
+// On SIGTERM or SIGINT, connections still open after this long are closed
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(30);
 
-async fn handle_connection(stream: TcpStream, config_map: Arc<ConfigMap>) {
-    let http = HttpConn::new(stream, config_map.clone()).await;
-    http.handle_connection(config_map).await;
+include!(concat!(env!("OUT_DIR"), "/plugins.rs"));
+
+async fn handle_connection(
+    stream: TcpStream,
+    listener: Arc<ListenConfig>,
+    config_map: Arc<ConfigMap>,
+) {
+    let conn_info = ConnInfo::new(stream.peer_addr().ok(), stream.local_addr().ok());
+    let peer = conn_info.remote_addr;
+    match HttpConn::new(stream, conn_info, &listener, config_map.clone()).await {
+        Ok(http) => {
+            let _connection =
+                OpenConnection::<PluginLifecycle>::new(http.conn_info(), config_map.clone());
+            http.handle_connection(config_map).await
+        }
+        Err(err) => match peer {
+            Some(peer) => println!("[ERROR] closing connection from {}: {}", peer, err),
+            None => println!("[ERROR] closing connection: {}", err),
+        },
+    }
+}
+
+// Every connection task holds a clone of `connections` until the connection is closed
+async fn accept_connections(
+    listener: TcpListener,
+    listen_config: ListenConfig,
+    config_map: Arc<RwLock<Arc<ConfigMap>>>,
+    connections: Arc<()>,
+) {
+    let listen_config = Arc::new(listen_config);
+    loop {
+        let (stream, _) = listener.accept().await.unwrap();
+        let config = config_map.read().await.clone();
+        let listen_config = listen_config.clone();
+        let connection = connections.clone();
+        tokio::spawn(async move {
+            handle_connection(stream, listen_config, config).await;
+            drop(connection);
+        });
+    }
+}
+
+async fn reload_config(config_map: &RwLock<Arc<ConfigMap>>) {
+    match reload::<PluginLifecycle>(config_map, CONFIG_PATH, load_plugins).await {
+        Ok(_) => println!("Reloaded configuration {}", CONFIG_PATH),
+        Err(err) => println!("[ERROR] keeping old configuration: {}", err),
+    }
 }
 
 async fn listen_reload(config_map: Arc<RwLock<Arc<ConfigMap>>>) {
@@ -25,36 +90,334 @@ async fn listen_reload(config_map: Arc<RwLock<Arc<ConfigMap>>>) {
     loop {
         stream.recv().await;
         println!("Reload");
-        let loaded = ConfigMap::load("conf.yaml", load_plugins);
-        *config_map.write().await = Arc::new(loaded);
+        reload_config(&config_map).await;
+    }
+}
+
+// Waits for SIGTERM or SIGINT
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    let mut interrupt = signal(SignalKind::interrupt()).unwrap();
+    tokio::select! {
+        _ = terminate.recv() => (),
+        _ = interrupt.recv() => (),
     }
 }
 
+fn watch_config_enabled(config_map: &ConfigMap) -> bool {
+    let plugin_list = &config_map.config;
+    get_plugin!(plugin_list, jequi).unwrap().watch_config
+}
+
+fn absolute_path(path: &Path) -> PathBuf {
+    std::env::current_dir().unwrap().join(path)
+}
+
+async fn listen_certificate_changes(config_map: Arc<RwLock<Arc<ConfigMap>>>) {
+    let (tx, mut rx) = unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event {
+            let _ = tx.send(event.paths);
+        }
+    })
+    .unwrap();
+
+    let mut watched_dirs = HashSet::new();
+    let mut watched_config: Option<Arc<ConfigMap>> = None;
+    loop {
+        let config = config_map.read().await.clone();
+        if !watched_config
+            .as_ref()
+            .is_some_and(|watched| Arc::ptr_eq(watched, &config))
+        {
+            for (key_path, cert_path, _) in file_identities(&config) {
+                for path in [key_path, cert_path] {
+                    // Watch the directory so files replaced by a rename are also noticed
+                    let dir = absolute_path(&path).parent().unwrap().to_path_buf();
+                    if watched_dirs.contains(&dir) {
+                        continue;
+                    }
+                    match watcher.watch(&dir, RecursiveMode::NonRecursive) {
+                        Ok(_) => {
+                            watched_dirs.insert(dir);
+                        }
+                        Err(err) => println!("[ERROR] can't watch {}: {}", dir.display(), err),
+                    }
+                }
+            }
+            watched_config = Some(config);
+        }
+
+        let mut changed: HashSet<PathBuf> =
+            match timeout(CERTIFICATE_WATCH_REFRESH, rx.recv()).await {
+                Ok(Some(paths)) => paths.into_iter().collect(),
+                Ok(None) => return,
+                Err(_) => continue,
+            };
+        sleep(CERTIFICATE_RELOAD_DELAY).await;
+        while let Ok(paths) = rx.try_recv() {
+            changed.extend(paths);
+        }
+
+        let config = config_map.read().await.clone();
+        let mut reloaded = HashSet::new();
+        for (key_path, cert_path, identity) in file_identities(&config) {
+            if !changed.contains(&absolute_path(&key_path))
+                && !changed.contains(&absolute_path(&cert_path))
+            {
+                continue;
+            }
+
+            let result = identity.reload(&key_path, &cert_path);
+            if !reloaded.insert((key_path, cert_path.clone())) {
+                continue;
+            }
+            match result {
+                Ok(_) => println!("Reloaded certificate {}", cert_path.display()),
+                Err(err) => println!(
+                    "[ERROR] keeping old certificate, failed to reload {}: {}",
+                    cert_path.display(),
+                    err
+                ),
+            }
+        }
+    }
+}
+
+// Directory an include glob searches in, made of the components before the first wildcard, and
+// whether the glob also matches files in its subdirectories
+fn glob_dir(glob: &Path) -> (PathBuf, bool) {
+    let components: Vec<Component> = glob.components().collect();
+    let wildcard = |component: &Component| {
+        let component = component.as_os_str().to_string_lossy();
+        component.contains(['*', '?', '['])
+    };
+    let first = components
+        .iter()
+        .position(wildcard)
+        .unwrap_or(components.len());
+    let rest = &components[first..];
+    let recursive = rest.len() > 1 || rest.iter().any(|part| part.as_os_str() == "**");
+    (components[..first].iter().collect(), recursive)
+}
+
+async fn listen_config_changes(config_map: Arc<RwLock<Arc<ConfigMap>>>) {
+    let (tx, mut rx) = unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event
+            && !event.kind.is_access()
+        {
+            let _ = tx.send(event.paths);
+        }
+    })
+    .unwrap();
+
+    let mut watched_dirs = HashSet::new();
+    let mut config_files = HashSet::new();
+    let mut include_globs = HashSet::new();
+    let mut watched_config: Option<Arc<ConfigMap>> = None;
+    loop {
+        let config = config_map.read().await.clone();
+        if !watched_config
+            .as_ref()
+            .is_some_and(|watched| Arc::ptr_eq(watched, &config))
+        {
+            // The files of a failed reload aren't known, keep watching the ones already found
+            config_files.extend(config.files.iter().map(|path| absolute_path(path)));
+            include_globs.extend(config.include_globs.iter().map(|glob| absolute_path(glob)));
+            if watch_config_enabled(&config) {
+                // Watch the directory so files replaced by a rename are also noticed, and the
+                // directories of the include globs so new files matching them are too
+                let files = config_files
+                    .iter()
+                    .map(|path| (path.parent().unwrap().to_path_buf(), false));
+                for (dir, recursive) in files.chain(include_globs.iter().map(|glob| glob_dir(glob)))
+                {
+                    if watched_dirs.contains(&(dir.clone(), recursive)) {
+                        continue;
+                    }
+                    let mode = match recursive {
+                        true => RecursiveMode::Recursive,
+                        false => RecursiveMode::NonRecursive,
+                    };
+                    match watcher.watch(&dir, mode) {
+                        Ok(_) => {
+                            watched_dirs.insert((dir, recursive));
+                        }
+                        Err(err) => println!("[ERROR] can't watch {}: {}", dir.display(), err),
+                    }
+                }
+            }
+            watched_config = Some(config.clone());
+        }
+
+        let paths = match timeout(CERTIFICATE_WATCH_REFRESH, rx.recv()).await {
+            Ok(Some(paths)) => paths,
+            Ok(None) => return,
+            Err(_) => continue,
+        };
+        let included = |path: &PathBuf| {
+            include_globs.iter().any(|glob| {
+                glob::Pattern::new(&glob.to_string_lossy())
+                    .is_ok_and(|pattern| pattern.matches_path(path))
+            })
+        };
+        let changed = paths
+            .iter()
+            .any(|path| config_files.contains(path) || included(path));
+        if !watch_config_enabled(&config) || !changed {
+            continue;
+        }
+
+        // Wait until the files stop changing, editors usually write a file in several steps
+        while let Ok(Some(_)) = timeout(CONFIG_RELOAD_DEBOUNCE, rx.recv()).await {}
+
+        println!("Configuration changed, reloading");
+        reload_config(&config_map).await;
+    }
+}
+
+// Prints the merged options used for `--host` and `--path`, the config file defaults to `conf.yaml`
+fn print_config(args: &[String]) -> Result<(), jequi::ConfigError> {
+    let (mut file, mut host, mut path) = (CONFIG_PATH, None, None);
+    let mut args = args.iter().map(String::as_str);
+    while let Some(arg) = args.next() {
+        match arg {
+            "--host" => host = args.next(),
+            "--path" => path = args.next(),
+            arg => file = arg,
+        }
+    }
+
+    let (scope, config) = ConfigMap::effective_config(file, host, path)?;
+    println!("# {}", scope);
+    print!("{}", serde_yaml::to_string(&config).unwrap());
+    Ok(())
+}
+
+// Prints which config and plugins are used for requests to a host and path, like the admin
+// endpoint does, the config file defaults to `conf.yaml`
+fn explain_route(args: &[String]) -> Result<(), jequi::ConfigError> {
+    let [host, path, file @ ..] = args else {
+        return Err(jequi::ConfigError::new(
+            "usage: --explain-route <host> <path> [config file]",
+        ));
+    };
+    let file = file.first().map_or(CONFIG_PATH, String::as_str);
+    let config_map = ConfigMap::load(file, load_plugins)?;
+    print!(
+        "{}",
+        config_map.explain_route(None, Some(host), Some(path))?
+    );
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some(command @ ("--dump-schema" | "--dump-reference")) => {
+            let options = match config_options() {
+                Ok(options) => options,
+                Err(err) => {
+                    println!("[ERROR] {}", err);
+                    process::exit(1);
+                }
+            };
+            if command == "--dump-schema" {
+                let schema = config_schema::json_schema(&options);
+                println!("{}", serde_json::to_string_pretty(&schema).unwrap());
+            } else {
+                print!("{}", config_schema::reference(&options));
+            }
+            process::exit(0);
+        }
+        Some("--print-config") => {
+            if let Err(err) = print_config(&args[2..]) {
+                println!("[ERROR] {}", err);
+                process::exit(1);
+            }
+            process::exit(0);
+        }
+        Some("--explain-route") => {
+            if let Err(err) = explain_route(&args[2..]) {
+                println!("[ERROR] {}", err);
+                process::exit(1);
+            }
+            process::exit(0);
+        }
+        _ => {}
+    }
+    if args.get(1).map(String::as_str) == Some("--test-config") {
+        let path = args.get(2).map(String::as_str).unwrap_or(CONFIG_PATH);
+        match ConfigMap::load(path, load_plugins) {
+            Ok(_) => {
+                println!("Configuration file {} is valid", path);
+                process::exit(0);
+            }
+            Err(err) => {
+                println!("[ERROR] {}", err);
+                process::exit(1);
+            }
+        }
+    }
+
+    let config_map = match ConfigMap::load(CONFIG_PATH, load_plugins) {
+        Ok(config_map) => config_map,
+        Err(err) => {
+            println!("[ERROR] {}", err);
+            process::exit(1);
+        }
+    };
+
     fs::write("./jequi.pid", process::id().to_string()).unwrap();
 
-    let config = Arc::new(RwLock::new(Arc::new(ConfigMap::load(
-        "conf.yaml",
-        load_plugins,
-    ))));
+    let config = Arc::new(RwLock::new(Arc::new(config_map)));
 
     let conf = config.read().await.config.get(0).unwrap().config.clone();
     let conf = conf.as_any().downcast_ref::<Config>().unwrap();
 
-    let address = (conf.ip.clone(), conf.port);
+    // Listeners are only bound on startup, changes to `listen` need a restart
+    let mut listeners = Vec::new();
+    for listen_config in conf.listen_configs() {
+        let address = (listen_config.ip.clone(), listen_config.port);
+        let listener = TcpListener::bind(address).await.unwrap();
+        listeners.push((listener, listen_config));
+    }
 
-    spawn(listen_reload(config.clone()));
+    PluginLifecycle::init(&config.read().await.clone());
 
-    let listener = TcpListener::bind(address).await.unwrap();
+    spawn(listen_reload(config.clone()));
+    spawn(listen_certificate_changes(config.clone()));
+    spawn(listen_config_changes(config.clone()));
 
     println!("Jequi server started!");
 
-    loop {
-        let (stream, _) = listener.accept().await.unwrap();
-        let config = config.read().await.clone();
-        tokio::spawn(async move {
-            handle_connection(stream, config).await;
-        });
+    let connections = Arc::new(());
+    let mut accepting = Vec::new();
+    for (listener, listen_config) in listeners {
+        accepting.push(spawn(accept_connections(
+            listener,
+            listen_config,
+            config.clone(),
+            connections.clone(),
+        )));
+    }
+    let accept_all = async {
+        for accepting in &mut accepting {
+            accepting.await.unwrap();
+        }
+    };
+    tokio::select! {
+        _ = accept_all => return,
+        _ = shutdown_signal() => (),
+    }
+
+    // Stop accepting, then let the connections still open finish before the plugins shut down
+    println!("Shutting down");
+    for accepting in accepting {
+        accepting.abort();
     }
+    let config_map = config.read().await.clone();
+    shutdown_when_closed::<PluginLifecycle>(&config_map, connections, SHUTDOWN_GRACE_PERIOD).await;
 }
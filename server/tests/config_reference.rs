@@ -0,0 +1,38 @@
+use jequi::{config_schema, registry::config_options};
+
+include!(concat!(env!("OUT_DIR"), "/plugins.rs"));
+
+#[test]
+fn readme_reference_test() {
+    let readme = std::fs::read_to_string("../README.md").unwrap();
+    let reference = config_schema::reference(&config_options().unwrap());
+    assert!(
+        readme.contains(&format!("\n\n{}# Api", reference)),
+        "README configuration options are outdated, regenerate them with `server --dump-reference`"
+    );
+}
+
+#[test]
+fn json_schema_test() {
+    let schema = config_schema::json_schema(&config_options().unwrap());
+    let properties = &schema["properties"];
+
+    // Every plugin adds its options
+    for key in [
+        "port",
+        "static_files_path",
+        "proxy_address",
+        "go_library_path",
+        "acme_directory_url",
+    ] {
+        assert!(properties[key]["description"].is_string(), "{}", key);
+    }
+    assert_eq!(properties["infer_content_type"]["default"], true);
+    assert!(properties["proxy_address"]["oneOf"].is_array());
+
+    let host = &properties["host"]["additionalProperties"]["properties"];
+    assert!(host["acme_directory_url"].is_object());
+    let path = &host["path"]["additionalProperties"]["properties"];
+    assert!(path["acme_directory_url"].is_null());
+    assert!(path["static_files_path"].is_object());
+}
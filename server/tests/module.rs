@@ -0,0 +1,109 @@
+use std::{
+    env::consts::{DLL_PREFIX, DLL_SUFFIX},
+    fs,
+    path::PathBuf,
+    process::Command,
+    sync::Arc,
+};
+
+use jequi::{registry::load_plugins, ConfigError, ConfigMap, Request, Response, Uri};
+
+include!(concat!(env!("OUT_DIR"), "/plugins.rs"));
+
+// Builds `example/hello_module` apart from the tests being run
+fn build_module() -> PathBuf {
+    let target_dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("modules");
+    let output = Command::new(env!("CARGO"))
+        .args(["build", "-p", "hello_module", "--target-dir"])
+        .arg(&target_dir)
+        .output()
+        .expect("failed to build module");
+    assert!(
+        output.status.success(),
+        "stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    target_dir.join(format!("debug/{}hello_module{}", DLL_PREFIX, DLL_SUFFIX))
+}
+
+fn load(name: &str, options: &str) -> Result<ConfigMap, ConfigError> {
+    let config = format!(
+        "strict_config: true\nload_module:\n  - {}\n{}",
+        build_module().display(),
+        options
+    );
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(format!("module-{}.yaml", name));
+    fs::write(&path, config).unwrap();
+    ConfigMap::load(path.to_str().unwrap(), load_plugins)
+}
+
+async fn request(config_map: &ConfigMap, path: &str) -> Response {
+    let mut request = Request::new();
+    request.uri = Uri::from(path.to_string());
+    let (config, _) = config_map.match_request(&request);
+    let plugin = config
+        .iter()
+        .find(|plugin| plugin.name == "jequi_module")
+        .unwrap();
+    let handler = plugin.handlers.content.0.clone().unwrap();
+    let mut response = Response::new();
+    handler(&mut request, &mut response).await;
+    response
+}
+
+#[tokio::test]
+async fn load_module_test() {
+    let config_map = load(
+        "hello",
+        "hello_message: hello from a module\npath:\n  /teapot:\n    hello_status: 418\n",
+    )
+    .unwrap();
+
+    let response = request(&config_map, "/hello").await;
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body_buffer, b"hello from a module");
+    assert_eq!(response.headers["x-hello-uri"], "/hello");
+
+    let response = request(&config_map, "/teapot").await;
+    assert_eq!(response.status, 418);
+
+    // The options of the module are known while it is loaded
+    let err = load("typo", "hello_mesage: hello\n").unwrap_err();
+    assert_eq!(err.message, "unknown option, did you mean `hello_message`?");
+
+    let err = load("invalid", "hello_message: hello\nhello_status: 1000\n").unwrap_err();
+    assert_eq!(err.message, "module hello_module: invalid hello_status");
+    assert_eq!(err.plugin, Some("jequi_module"));
+}
+
+#[tokio::test]
+async fn module_order_test() {
+    let file = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("module-static.txt");
+    fs::write(&file, "static file, ").unwrap();
+    let config_map = load(
+        "static",
+        &format!(
+            "hello_message: hello from a module\nstatic_files_path: {}\n",
+            file.display()
+        ),
+    )
+    .unwrap();
+
+    // The module runs after the file is served instead of replacing it
+    let mut request = Request::new();
+    request.uri = Uri::from("/".to_string());
+    let mut response = Response::new();
+    request
+        .handle_request(&mut response, Arc::new(config_map))
+        .await;
+    assert_eq!(response.body_buffer, b"static file, hello from a module");
+}
+
+#[test]
+fn load_module_error_test() {
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("module-missing.yaml");
+    fs::write(&path, "load_module:\n  - missing.so\n").unwrap();
+    let err = ConfigMap::load(path.to_str().unwrap(), load_plugins).unwrap_err();
+    assert!(err.message.starts_with("can't load module missing.so: "));
+    assert_eq!(err.key.as_deref(), Some("load_module"));
+}
@@ -0,0 +1,147 @@
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use jequi::{lifecycle::reload, registry::load_plugins, ConfigList, ConfigMap, PluginLifecycle};
+use plugins::get_plugin;
+use tokio::{
+    sync::RwLock,
+    time::{sleep, timeout},
+};
+
+include!(concat!(env!("OUT_DIR"), "/plugins.rs"));
+
+#[derive(Debug, Clone, Copy)]
+enum Plugins {
+    None,
+    ServeStatic,
+    Proxy,
+    Both,
+}
+
+impl Plugins {
+    fn options(self) -> &'static str {
+        match self {
+            Plugins::None => "",
+            Plugins::ServeStatic => "static_files_path: ../jequi/test\n",
+            Plugins::Proxy => "proxy_address: 127.0.0.1:8080\n",
+            Plugins::Both => "static_files_path: ../jequi/test\nproxy_address: 127.0.0.1:8080\n",
+        }
+    }
+
+    fn check(self, config: &ConfigList) {
+        let serve_static = get_plugin!(config, jequi_serve_static);
+        let proxy = get_plugin!(config, jequi_proxy);
+        let (has_static, has_proxy) = match self {
+            Plugins::None => (false, false),
+            Plugins::ServeStatic => (true, false),
+            Plugins::Proxy => (false, true),
+            Plugins::Both => (true, true),
+        };
+
+        assert_eq!(serve_static.is_some(), has_static, "{:?}", self);
+        assert_eq!(proxy.is_some(), has_proxy, "{:?}", self);
+        if let Some(proxy) = proxy {
+            assert!(proxy.proxy_address.is_some());
+        }
+        assert_eq!(get_plugin!(config, jequi).unwrap().port, 7070);
+
+        let handlers = config
+            .iter()
+            .filter(|plugin| !plugin.handlers.phases().is_empty())
+            .count();
+        assert_eq!(handlers, has_static as usize + has_proxy as usize);
+    }
+}
+
+fn config(plugins: Plugins) -> String {
+    let indented = |options: &str| {
+        options.lines().fold(String::new(), |indented, line| {
+            indented + "    " + line + "\n"
+        })
+    };
+    format!(
+        "port: 7070\n{}path:\n  /api:\n    chunk_size: 4096\n{}",
+        plugins.options(),
+        indented(plugins.options())
+    )
+}
+
+fn check(config_map: &ConfigMap, plugins: Plugins) {
+    plugins.check(&config_map.config);
+    let path = config_map.path.as_ref().unwrap();
+    plugins.check(&path[&PathBuf::from("/api")]);
+}
+
+#[tokio::test]
+async fn reload_plugins_test() {
+    let all = [
+        Plugins::None,
+        Plugins::ServeStatic,
+        Plugins::Proxy,
+        Plugins::Both,
+    ];
+    let path = std::env::temp_dir().join("jequi-reload-plugins.yaml");
+    let path_str = path.to_str().unwrap();
+    fs::write(&path, config(Plugins::None)).unwrap();
+    let config_map = RwLock::new(Arc::new(ConfigMap::load(path_str, load_plugins).unwrap()));
+
+    // Every transition between two sets of plugins, through the same reload as a HUP signal
+    for (i, from) in all.into_iter().enumerate() {
+        for (j, to) in all.into_iter().enumerate() {
+            if i == j {
+                continue;
+            }
+            for plugins in [from, to] {
+                fs::write(&path, config(plugins)).unwrap();
+                let previous = config_map.read().await.clone();
+                let loaded = reload::<PluginLifecycle>(&config_map, path_str, load_plugins)
+                    .await
+                    .unwrap();
+                // The old configuration isn't reused
+                assert!(!Arc::ptr_eq(&previous, &loaded));
+                assert!(Arc::ptr_eq(&loaded, &*config_map.read().await));
+                check(&loaded, plugins);
+            }
+        }
+    }
+    fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn reload_config_test() {
+    let path = std::env::temp_dir().join("jequi-reload-signal.yaml");
+    let path_str = path.to_str().unwrap();
+    fs::write(&path, config(Plugins::ServeStatic)).unwrap();
+    let config_map = RwLock::new(Arc::new(ConfigMap::load(path_str, load_plugins).unwrap()));
+    let first = Arc::downgrade(&*config_map.read().await);
+
+    // Same as a HUP signal, the file is loaded again and replaces the current configuration
+    fs::write(&path, config(Plugins::Proxy)).unwrap();
+    let loaded = reload::<PluginLifecycle>(&config_map, path_str, load_plugins)
+        .await
+        .unwrap();
+    assert!(Arc::ptr_eq(&loaded, &*config_map.read().await));
+    check(&loaded, Plugins::Proxy);
+
+    // An invalid file keeps the current configuration
+    fs::write(&path, "port: [").unwrap();
+    assert!(
+        reload::<PluginLifecycle>(&config_map, path_str, load_plugins)
+            .await
+            .is_err()
+    );
+    assert!(Arc::ptr_eq(&loaded, &*config_map.read().await));
+    fs::remove_file(path).unwrap();
+
+    // The replaced configuration is torn down and dropped once nothing uses it
+    let dropped = async {
+        while Weak::strong_count(&first) > 0 {
+            sleep(Duration::from_millis(100)).await;
+        }
+    };
+    timeout(Duration::from_secs(5), dropped).await.unwrap();
+}